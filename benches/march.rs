@@ -0,0 +1,56 @@
+// Marching steps and time for the camera rays of the default scene with each of the ways to cut down on steps.
+// Run with `cargo bench --bench march`.
+
+use std::time::Instant;
+use rvk::render::ConePrepass;
+use rvk::rays::MarchSettings;
+use rvk::scene;
+
+const SIZE: u32 = 128;
+
+struct Variant {
+    name: &'static str,
+    relaxation: f32,
+    footprint: bool,
+    cone_prepass: bool
+}
+
+const VARIANTS: [Variant; 4] = [
+    Variant { name: "plain sphere tracing", relaxation: 1., footprint: false, cone_prepass: false },
+    Variant { name: "over-relaxed", relaxation: 1.6, footprint: false, cone_prepass: false },
+    Variant { name: "over-relaxed, pixel footprint", relaxation: 1.6, footprint: true, cone_prepass: false },
+    Variant { name: "over-relaxed, pixel footprint, cones", relaxation: 1.6, footprint: true, cone_prepass: true }
+];
+
+fn main() {
+    let path = concat!( env!( "CARGO_MANIFEST_DIR" ), "/scenes/default.toml" );
+    let scene = scene::load( path ).expect( "the default scene loads" );
+    let camera = scene.camera.build( 1. );
+    let world = &scene.world;
+
+    println!( "{} x {} camera rays of {}", SIZE, SIZE, path );
+    println!( "{:<40} {:>12} {:>10} {:>10}", "variant", "steps", "relative", "seconds" );
+    let mut plain_steps = None;
+    for variant in &VARIANTS {
+        let settings = MarchSettings {
+            relaxation: variant.relaxation,
+            pixel_angle: if variant.footprint { camera.pixel_angle( SIZE ) } else { 0. },
+            ..MarchSettings::default()
+        };
+
+        let time = Instant::now();
+        let prepass = variant.cone_prepass.then( || ConePrepass::new( &camera, world, SIZE, SIZE, 16, &settings ) );
+        let mut steps = prepass.as_ref().map_or( 0, | prepass | prepass.steps );
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                let start = prepass.as_ref().map_or( 0., | prepass | prepass.start( x, y ) );
+                let ray = camera.get_ray( ( x as f32 + 0.5 ) / SIZE as f32, ( y as f32 + 0.5 ) / SIZE as f32 ).advanced( start );
+                steps += world.trace( &ray, &settings ).steps() as u64;
+            }
+        }
+        let seconds = time.elapsed().as_secs_f32();
+
+        let plain = *plain_steps.get_or_insert( steps );
+        println!( "{:<40} {:>12} {:>9.1}% {:>10.2}", variant.name, steps, steps as f32 / plain as f32 * 100., seconds );
+    }
+}
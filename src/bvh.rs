@@ -0,0 +1,221 @@
+use glam::Vec3;
+
+#[derive( Clone, Copy, Debug, PartialEq )]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3
+}
+
+impl Aabb {
+    // Bounds of shapes that extend to infinity, these are never pruned
+    pub const INFINITE: Aabb = Aabb { min: Vec3::splat( f32::NEG_INFINITY ), max: Vec3::splat( f32::INFINITY ) };
+
+    pub const EMPTY: Aabb = Aabb { min: Vec3::splat( f32::INFINITY ), max: Vec3::splat( f32::NEG_INFINITY ) };
+
+    pub fn new( min: Vec3, max: Vec3 ) -> Aabb {
+        Aabb { min: min.min( max ), max: min.max( max ) }
+    }
+
+    pub fn from_points( points: &[Vec3] ) -> Aabb {
+        points.iter().fold( Aabb::EMPTY, | aabb, &p | Aabb { min: aabb.min.min( p ), max: aabb.max.max( p ) } )
+    }
+
+    pub fn is_finite( &self ) -> bool {
+        self.min.is_finite() && self.max.is_finite()
+    }
+
+    pub fn union( &self, other: &Aabb ) -> Aabb {
+        Aabb { min: self.min.min( other.min ), max: self.max.max( other.max ) }
+    }
+
+    pub fn center( &self ) -> Vec3 {
+        ( self.min + self.max ) * 0.5
+    }
+
+    pub fn size( &self ) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn corners( &self ) -> [Vec3; 8] {
+        let ( a, b ) = ( self.min, self.max );
+        [
+            Vec3::new( a.x, a.y, a.z ), Vec3::new( b.x, a.y, a.z ), Vec3::new( a.x, b.y, a.z ), Vec3::new( b.x, b.y, a.z ),
+            Vec3::new( a.x, a.y, b.z ), Vec3::new( b.x, a.y, b.z ), Vec3::new( a.x, b.y, b.z ), Vec3::new( b.x, b.y, b.z )
+        ]
+    }
+
+    // Distance from pos to the box, 0 when pos is inside
+    pub fn distance( &self, pos: Vec3 ) -> f32 {
+        ( self.min - pos ).max( pos - self.max ).max( Vec3::ZERO ).length()
+    }
+}
+
+// Items per leaf node
+const LEAF_SIZE: usize = 4;
+
+// Below this many items a box test costs about as much as evaluating the item, so no tree is built
+const MIN_TREE_SIZE: usize = 32;
+
+enum Node {
+    Leaf { bounds: Aabb, items: Vec<usize> },
+    Branch { bounds: Aabb, left: usize, right: usize }
+}
+
+impl Node {
+    fn bounds( &self ) -> &Aabb {
+        match self {
+            Node::Leaf { bounds, .. } => bounds,
+            Node::Branch { bounds, .. } => bounds
+        }
+    }
+}
+
+// Bounding volume hierarchy over a list of items with known bounds, used to find the nearest item to a point
+// without evaluating every item. The distance of an item must never be smaller than the distance to its bounds.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    // Items with infinite bounds, or all items in small sets, are checked for every query
+    unbounded: Vec<usize>
+}
+
+impl Bvh {
+    pub fn new( bounds: &[Aabb] ) -> Bvh {
+        let mut bvh = Bvh { nodes: vec![], unbounded: vec![] };
+        let mut items = vec![];
+        for ( i, b ) in bounds.iter().enumerate() {
+            if b.is_finite() {
+                items.push( i );
+            } else {
+                bvh.unbounded.push( i );
+            }
+        }
+        if items.len() < MIN_TREE_SIZE {
+            bvh.unbounded = ( 0..bounds.len() ).collect();
+        } else {
+            bvh.build( bounds, items );
+        }
+        bvh
+    }
+
+    // Split along the longest axis of the item centers until the leaves are small enough
+    fn build( &mut self, bounds: &[Aabb], mut items: Vec<usize> ) -> usize {
+        let node_bounds = items.iter().fold( Aabb::EMPTY, | aabb, &i | aabb.union( &bounds[ i ] ) );
+        if items.len() <= LEAF_SIZE {
+            self.nodes.push( Node::Leaf { bounds: node_bounds, items } );
+            return self.nodes.len() - 1;
+        }
+
+        let centers = Aabb::from_points( &items.iter().map( | &i | bounds[ i ].center() ).collect::<Vec<_>>() );
+        let size = centers.size();
+        let axis = if size.x >= size.y && size.x >= size.z { 0 } else if size.y >= size.z { 1 } else { 2 };
+        items.sort_by( | &a, &b | bounds[ a ].center()[ axis ].total_cmp( &bounds[ b ].center()[ axis ] ) );
+        let right_items = items.split_off( items.len() / 2 );
+
+        // Reserve the slot for this node so the root ends up at index 0
+        let index = self.nodes.len();
+        self.nodes.push( Node::Leaf { bounds: node_bounds, items: vec![] } );
+        let left = self.build( bounds, items );
+        let right = self.build( bounds, right_items );
+        self.nodes[ index ] = Node::Branch { bounds: node_bounds, left, right };
+        index
+    }
+
+    // Find the item with the smallest `distance` to pos, skipping nodes whose bounds are further away than the
+    // best distance so far. Returns the item index and its distance.
+    pub fn nearest<F: Fn( usize ) -> f32>( &self, pos: Vec3, distance: F ) -> Option<( usize, f32 )> {
+        let mut best: Option<( usize, f32 )> = None;
+        let consider = | i: usize, best: &mut Option<( usize, f32 )> | {
+            let d = distance( i );
+            if best.is_none_or( | ( _, b ) | d < b ) {
+                *best = Some( ( i, d ) );
+            }
+        };
+
+        for &i in &self.unbounded {
+            consider( i, &mut best );
+        }
+
+        if self.nodes.is_empty() {
+            return best;
+        }
+
+        // The tree is balanced, so a fixed size stack is plenty and avoids allocating for every query
+        let mut stack = [ ( 0, 0. ); 64 ];
+        let mut len = 1;
+        stack[ 0 ] = ( 0, self.nodes[ 0 ].bounds().distance( pos ) );
+        while len > 0 {
+            len -= 1;
+            let ( node, node_distance ) = stack[ len ];
+            // Nodes containing pos are never skipped, overlapping items inside them may be further below zero
+            if node_distance > 0. && best.is_some_and( | ( _, b ) | node_distance >= b ) {
+                continue;
+            }
+            match &self.nodes[ node ] {
+                Node::Leaf { items, .. } => {
+                    for &i in items {
+                        consider( i, &mut best );
+                    }
+                },
+                Node::Branch { left, right, .. } => {
+                    let left_distance = self.nodes[ *left ].bounds().distance( pos );
+                    let right_distance = self.nodes[ *right ].bounds().distance( pos );
+                    // Push the closest child last so it is visited first
+                    if left_distance < right_distance {
+                        stack[ len ] = ( *right, right_distance );
+                        stack[ len + 1 ] = ( *left, left_distance );
+                    } else {
+                        stack[ len ] = ( *left, left_distance );
+                        stack[ len + 1 ] = ( *right, right_distance );
+                    }
+                    len += 2;
+                }
+            }
+        }
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+    use super::{Aabb, Bvh};
+
+    #[test]
+    fn aabb_distance() {
+        let aabb = Aabb::new( Vec3::new( -1., -1., -1. ), Vec3::new( 1., 1., 1. ) );
+        assert_eq!( aabb.distance( Vec3::ZERO ), 0. );
+        assert_eq!( aabb.distance( Vec3::new( 3., 0., 0. ) ), 2. );
+        assert_eq!( aabb.distance( Vec3::new( 4., 5., 1. ) ), 5. );
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        // A grid of points, each item is the point itself with a small box around it
+        let mut points = vec![];
+        for x in 0..10 {
+            for y in 0..7 {
+                for z in 0..5 {
+                    points.push( Vec3::new( x as f32 * 1.5, y as f32 * 2.1 - 4., z as f32 * 0.7 ) );
+                }
+            }
+        }
+        let bounds: Vec<Aabb> = points.iter().map( | &p | Aabb::new( p - 0.1, p + 0.1 ) ).collect();
+        let bvh = Bvh::new( &bounds );
+
+        for i in 0..200 {
+            let pos = Vec3::new( ( i as f32 * 0.37 ).sin() * 12., ( i as f32 * 0.71 ).cos() * 9., ( i as f32 * 0.13 ).sin() * 6. );
+            let distance = | i: usize | ( points[ i ] - pos ).length();
+            let expected = ( 0..points.len() ).map( distance ).fold( f32::MAX, f32::min );
+            let ( _, found ) = bvh.nearest( pos, distance ).unwrap();
+            assert!( ( found - expected ).abs() < 1e-6 );
+        }
+    }
+
+    #[test]
+    fn unbounded_items_are_always_checked() {
+        let bvh = Bvh::new( &[ Aabb::new( Vec3::ZERO, Vec3::ONE ), Aabb::INFINITE ] );
+        let ( item, _ ) = bvh.nearest( Vec3::new( 100., 0., 0. ), | i | if i == 1 { 0.5 } else { 99. } ).unwrap();
+        assert_eq!( item, 1 );
+    }
+}
@@ -8,6 +8,13 @@ pub struct Ray {
     pub weigth: f32
 }
 
+impl Ray {
+    // The same ray starting distance further along, with the skipped part counted in its length
+    pub fn advanced( self, distance: f32 ) -> Ray {
+        Ray { origin: self.origin + self.direction * distance, cum_length: self.cum_length + distance, ..self }
+    }
+}
+
 pub struct Camera {
     pub position: Vec3,
     pub direction: Vec3,
@@ -38,4 +45,9 @@ impl Camera {
         let direction = (pix_pos - self.position).normalize();
         Ray { origin: self.position, direction, reflect_count: 0, cum_length: 0., weigth: 0. }
     }
+
+    // Angle covered by one pixel of an image with this many rows, near the center of the image
+    pub fn pixel_angle( &self, height: u32 ) -> f32 {
+        f32::tan( self.fov / 2. ) / height as f32
+    }
 }
@@ -0,0 +1,233 @@
+use std::path::PathBuf;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+
+#[derive( Parser )]
+#[command( name = "rvk", version, about = "Ray marcher for signed distance field scenes" )]
+pub struct Cli {
+    #[command( subcommand )]
+    pub command: Command
+}
+
+#[derive( Subcommand )]
+pub enum Command {
+    /// Render a scene to an image
+    Render( RenderArgs ),
+    /// Apply post processing to an existing image
+    Postprocess( PostprocessArgs ),
+    /// Print a summary of a scene file
+    Info( InfoArgs ),
+    /// Sample the shapes of a scene or a mesh into a signed distance grid file
+    Bake( BakeArgs )
+}
+
+#[derive( Args )]
+pub struct RenderArgs {
+    /// Scene description file
+    #[arg( short, long, default_value = "scenes/default.toml" )]
+    pub scene: PathBuf,
+
+    /// Output image path, .exr and .hdr files keep the linear colors and anything else is written as PNG
+    #[arg( short, long, default_value = "Output/out.png" )]
+    pub output: PathBuf,
+
+    #[command( flatten )]
+    pub export: ExportArgs,
+
+    /// Image width in pixels
+    #[arg( long, default_value_t = 512, value_parser = clap::value_parser!( u32 ).range( 1.. ) )]
+    pub width: u32,
+
+    /// Image height in pixels
+    #[arg( long, default_value_t = 512, value_parser = clap::value_parser!( u32 ).range( 1.. ) )]
+    pub height: u32,
+
+    /// Samples per pixel, spread over a regular grid inside the pixel
+    #[arg( long, default_value_t = 4, value_parser = clap::value_parser!( u32 ).range( 1.. ) )]
+    pub samples: u32,
+
+    /// Number of render threads [default: one per core]
+    #[arg( short, long, value_parser = clap::value_parser!( u32 ).range( 1.. ) )]
+    pub threads: Option<u32>,
+
+    /// Edge length in pixels of the square tiles handed out to the render threads
+    #[arg( long, default_value_t = 16, value_parser = clap::value_parser!( u32 ).range( 1.. ) )]
+    pub tile_size: u32,
+
+    /// Total distance a ray may travel, including reflections
+    #[arg( long, default_value_t = 500. )]
+    pub max_distance: f32,
+
+    /// Reflections a ray may make before it is dropped
+    #[arg( long, default_value_t = 500 )]
+    pub max_bounces: u32,
+
+    /// Marching steps between two reflections
+    #[arg( long, default_value_t = 500, value_parser = clap::value_parser!( u32 ).range( 1.. ) )]
+    pub max_steps: u32,
+
+    /// Marching steps for a ray including all of its reflections
+    #[arg( long, default_value_t = 100_000, value_parser = clap::value_parser!( u32 ).range( 1.. ) )]
+    pub max_path_steps: u32,
+
+    /// Factor on the marching step lengths, 1 for plain sphere tracing and up to 2 for larger steps in open space
+    #[arg( long, default_value_t = 1.6 )]
+    pub relaxation: f32,
+
+    /// Let rays hit surfaces once they are closer than a pixel is wide instead of at a fixed distance. Faster, but
+    /// thin features and shapes with inexact distances may grow a little
+    #[arg( long )]
+    pub footprint_epsilon: bool,
+
+    /// March cones over blocks of pixels first, so camera rays skip the empty space in front of the scene
+    #[arg( long )]
+    pub cone_prepass: bool,
+
+    /// How pixels are colored
+    #[arg( long, value_enum, default_value_t = ShadingArg::Palette )]
+    pub shading: ShadingArg,
+
+    /// Surface interactions per path when path tracing, or mirror reflections with direct shading
+    #[arg( long, default_value_t = 16 )]
+    pub max_depth: u32,
+
+    /// Penumbra factor for direct shading, larger values give harder shadows
+    #[arg( long, default_value_t = 16. )]
+    pub shadow_hardness: f32,
+
+    /// Distance samples along the normal for ambient occlusion
+    #[arg( long, default_value_t = 5 )]
+    pub ao_steps: u32,
+
+    /// Spacing of the ambient occlusion samples
+    #[arg( long, default_value_t = 0.15 )]
+    pub ao_step_size: f32
+}
+
+// How the linear image is written
+#[derive( Args )]
+pub struct ExportArgs {
+    /// Brightness change in stops before tone mapping
+    #[arg( long, default_value_t = 0., allow_negative_numbers = true )]
+    pub exposure: f32,
+
+    /// Curve that compresses bright colors into the displayable range
+    #[arg( long, value_enum, default_value_t = ToneMapArg::None )]
+    pub tone_map: ToneMapArg,
+
+    /// Color space of PNG output, OpenEXR and HDR files always hold linear sRGB primaries
+    #[arg( long, value_enum, default_value_t = ColorSpaceArg::Srgb )]
+    pub color_space: ColorSpaceArg,
+
+    /// Precision of the channels of OpenEXR output
+    #[arg( long, value_enum, default_value_t = PrecisionArg::Half )]
+    pub exr_precision: PrecisionArg,
+
+    /// Write 16 bits per channel to PNG files instead of 8
+    #[arg( long )]
+    pub png_16_bit: bool,
+
+    /// Write the alpha channel to PNG files
+    #[arg( long )]
+    pub alpha: bool
+}
+
+#[derive( Clone, Copy, ValueEnum )]
+pub enum ToneMapArg {
+    /// Clip values above 1
+    None,
+    /// Luminance based Reinhard curve
+    Reinhard,
+    /// ACES filmic curve
+    Aces,
+    /// AgX with the default look
+    Agx
+}
+
+#[derive( Clone, Copy, ValueEnum )]
+pub enum ColorSpaceArg {
+    /// sRGB with its transfer curve
+    Srgb,
+    /// Rec.709 primaries with the BT.709 curve
+    Rec709,
+    /// Display P3 with the sRGB curve
+    DisplayP3,
+    /// Linear values with sRGB primaries, without a curve
+    Linear
+}
+
+#[derive( Clone, Copy, ValueEnum )]
+pub enum PrecisionArg {
+    /// 16-bit floats
+    Half,
+    /// 32-bit floats
+    Float
+}
+
+#[derive( Clone, Copy, ValueEnum )]
+pub enum ShadingArg {
+    /// Color by the number of reflections
+    Palette,
+    /// Ambient occlusion as gray values
+    Occlusion,
+    /// Direct light with soft shadows and ambient occlusion
+    Direct,
+    /// Physically based path tracing
+    Path
+}
+
+#[derive( Args )]
+pub struct PostprocessArgs {
+    /// Input image, an OpenEXR (.exr) or Radiance (.hdr) file or a PNG of any format, which is taken to be sRGB
+    #[arg( short, long, default_value = "Output/out.png" )]
+    pub input: PathBuf,
+
+    /// Output image path, the format is picked by the extension like for rendering
+    #[arg( short, long, default_value = "Output/out2.png" )]
+    pub output: PathBuf,
+
+    #[command( flatten )]
+    pub export: ExportArgs,
+
+    /// Factor applied to every color channel
+    #[arg( long, default_value_t = 1. )]
+    pub gain: f32
+}
+
+#[derive( Args )]
+pub struct InfoArgs {
+    /// Scene description file
+    #[arg( short, long, default_value = "scenes/default.toml" )]
+    pub scene: PathBuf
+}
+
+#[derive( Args )]
+#[command( group = ArgGroup::new( "input" ).required( true ) )]
+pub struct BakeArgs {
+    /// Scene file whose shapes are baked
+    #[arg( short, long, group = "input" )]
+    pub scene: Option<PathBuf>,
+
+    /// OBJ or PLY mesh to bake
+    #[arg( short, long, group = "input" )]
+    pub mesh: Option<PathBuf>,
+
+    /// Bake only the shape at this index in the scene's list instead of all of them
+    #[arg( long, requires = "scene" )]
+    pub shape: Option<usize>,
+
+    /// Output grid path
+    #[arg( short, long )]
+    pub output: PathBuf,
+
+    /// Samples along the longest side of the grid
+    #[arg( long, default_value_t = 64, value_parser = clap::value_parser!( u32 ).range( 2.. ) )]
+    pub resolution: u32,
+
+    /// Margin around the shape's bounds, as a fraction of their largest size
+    #[arg( long, default_value_t = 0.1 )]
+    pub padding: f32,
+
+    /// Number of threads [default: one per core]
+    #[arg( short, long, value_parser = clap::value_parser!( u32 ).range( 1.. ) )]
+    pub threads: Option<u32>
+}
@@ -0,0 +1,185 @@
+// Turning the linear radiance of renders into display colors: exposure, a tone mapping curve that compresses bright
+// values into the displayable range, and the primaries and transfer function of the output color space.
+//
+// Colors in the framebuffer are linear with the sRGB / Rec.709 primaries.
+
+use glam::{Mat3, Vec3};
+
+#[derive( Clone, Copy, Debug, PartialEq, Eq )]
+pub enum ToneMap {
+    // Values above 1 are clipped on 8-bit export
+    None,
+    // Luminance based x / (1 + x), keeps hues but never reaches white
+    Reinhard,
+    // Fit of the ACES reference rendering and sRGB output transforms by Stephen Hill
+    Aces,
+    // Troy Sobotka's AgX with the default look, which desaturates bright colors towards white
+    Agx
+}
+
+#[derive( Clone, Copy, Debug, PartialEq, Eq )]
+pub enum ColorSpace {
+    // sRGB primaries with the piecewise sRGB curve
+    Srgb,
+    // The same primaries with the BT.709 camera curve, for video
+    Rec709,
+    // P3 primaries with a D65 white point and the sRGB curve
+    DisplayP3,
+    // sRGB primaries without any curve, the values are stored as they are
+    Linear
+}
+
+// Rec.709 to Display P3, both relative to D65
+const REC709_TO_P3: [f32; 9] = [
+    0.822_462, 0.177_538, 0.,
+    0.033_194, 0.966_806, 0.,
+    0.017_083, 0.072_397, 0.910_520
+];
+
+impl ColorSpace {
+    // Encode a linear Rec.709 color in [0, 1] for this color space
+    pub fn encode( self, color: Vec3 ) -> Vec3 {
+        match self {
+            ColorSpace::Srgb => color.to_array().map( srgb_encode ).into(),
+            ColorSpace::Rec709 => color.to_array().map( | c | if c < 0.018 { 4.5 * c } else { 1.099 * c.powf( 0.45 ) - 0.099 } ).into(),
+            ColorSpace::DisplayP3 => ( row_major( &REC709_TO_P3 ) * color ).clamp( Vec3::ZERO, Vec3::ONE ).to_array().map( srgb_encode ).into(),
+            ColorSpace::Linear => color
+        }
+    }
+}
+
+fn row_major( values: &[f32; 9] ) -> Mat3 {
+    Mat3::from_cols_array( values ).transpose()
+}
+
+pub fn srgb_encode( c: f32 ) -> f32 {
+    if c <= 0.003_130_8 { 12.92 * c } else { 1.055 * c.powf( 1. / 2.4 ) - 0.055 }
+}
+
+pub fn srgb_decode( c: f32 ) -> f32 {
+    if c <= 0.040_45 { c / 12.92 } else { ( ( c + 0.055 ) / 1.055 ).powf( 2.4 ) }
+}
+
+fn luminance( color: Vec3 ) -> f32 {
+    color.dot( Vec3::new( 0.2126, 0.7152, 0.0722 ) )
+}
+
+fn aces( color: Vec3 ) -> Vec3 {
+    let input = row_major( &[
+        0.597_19, 0.354_58, 0.048_23,
+        0.076_00, 0.908_34, 0.015_66,
+        0.028_40, 0.133_83, 0.837_77
+    ] );
+    let output = row_major( &[
+        1.604_75, -0.531_08, -0.073_67,
+        -0.102_08, 1.108_13, -0.006_05,
+        -0.003_27, -0.072_76, 1.076_02
+    ] );
+    let v = input * color;
+    let v = ( v * ( v + 0.024_578_6 ) - 0.000_090_537 ) / ( v * ( 0.983_729 * v + 0.432_951 ) + 0.238_081 );
+    ( output * v ).clamp( Vec3::ZERO, Vec3::ONE )
+}
+
+// https://iolite-engine.com/blog_posts/minimal_agx_implementation
+fn agx( color: Vec3 ) -> Vec3 {
+    const MIN_EV: f32 = -12.473_931;
+    const MAX_EV: f32 = 4.026_069;
+    let inset = Mat3::from_cols_array( &[
+        0.842_479, 0.042_328, 0.042_376,
+        0.078_434, 0.878_469, 0.078_434,
+        0.079_224, 0.079_166, 0.879_143
+    ] );
+    let outset = Mat3::from_cols_array( &[
+        1.196_879, -0.052_897, -0.052_972,
+        -0.098_021, 1.151_903, -0.098_043,
+        -0.099_030, -0.098_961, 1.151_074
+    ] );
+
+    // Log encoding of the inset color, then a sigmoid fitted to the default contrast
+    let v = ( inset * color.max( Vec3::splat( 1e-10 ) ) ).to_array().map( | c | ( c.log2().clamp( MIN_EV, MAX_EV ) - MIN_EV ) / ( MAX_EV - MIN_EV ) );
+    let v = v.map( | x | {
+        let ( x2, x4 ) = ( x * x, x * x * x * x );
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.002_32
+    } );
+    // The curve gives display values, undo the 2.2 gamma it assumes to get back to linear
+    ( outset * Vec3::from( v ) ).clamp( Vec3::ZERO, Vec3::ONE ).powf( 2.2 )
+}
+
+#[derive( Clone, Copy, Debug )]
+pub struct ColorTransform {
+    // Brightness change in stops applied before tone mapping
+    pub exposure: f32,
+    pub tone_map: ToneMap,
+    pub color_space: ColorSpace
+}
+
+impl Default for ColorTransform {
+    fn default() -> Self {
+        ColorTransform { exposure: 0., tone_map: ToneMap::None, color_space: ColorSpace::Srgb }
+    }
+}
+
+impl ColorTransform {
+    // Exposure and tone mapping of a linear color, the result is still linear
+    pub fn tone_map( &self, color: Vec3 ) -> Vec3 {
+        let color = color * f32::exp2( self.exposure );
+        match self.tone_map {
+            ToneMap::None => color,
+            ToneMap::Reinhard => {
+                let l = luminance( color );
+                if l > 0. { color / ( 1. + l ) } else { color }
+            },
+            ToneMap::Aces => aces( color ),
+            ToneMap::Agx => agx( color )
+        }
+    }
+
+    // The display values in [0, 1] of a linear color in the output color space
+    pub fn apply( &self, color: Vec3 ) -> Vec3 {
+        let color = self.tone_map( color ).clamp( Vec3::ZERO, Vec3::ONE );
+        self.color_space.encode( color ).clamp( Vec3::ZERO, Vec3::ONE )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+    use super::{srgb_decode, srgb_encode, ColorSpace, ColorTransform, ToneMap};
+
+    #[test]
+    fn transfer_functions() {
+        for c in [ 0., 0.002, 0.1, 0.5, 1. ] {
+            assert!( ( srgb_decode( srgb_encode( c ) ) - c ).abs() < 1e-6, "{}", c );
+        }
+        assert!( ( srgb_encode( 0.214_041 ) - 0.5 ).abs() < 1e-5 );
+        assert_eq!( ColorSpace::Rec709.encode( Vec3::ONE ), Vec3::ONE );
+        assert!( ( ColorSpace::Rec709.encode( Vec3::splat( 0.01 ) ).x - 0.045 ).abs() < 1e-6 );
+
+        // White stays white in P3, pure sRGB red is less saturated there
+        assert!( ( ColorSpace::DisplayP3.encode( Vec3::ONE ) - Vec3::ONE ).abs().max_element() < 1e-4 );
+        let red = ColorSpace::DisplayP3.encode( Vec3::X );
+        assert!( red.x < 1. && red.y > 0. && red.z > 0. );
+        assert_eq!( ColorSpace::Linear.encode( Vec3::splat( 0.3 ) ), Vec3::splat( 0.3 ) );
+    }
+
+    #[test]
+    fn tone_map_curves() {
+        for tone_map in [ ToneMap::Reinhard, ToneMap::Aces, ToneMap::Agx ] {
+            let transform = ColorTransform { tone_map, color_space: ColorSpace::Linear, ..ColorTransform::default() };
+            // Black stays black, brighter input never gets darker and very bright gray stays in range
+            assert!( transform.apply( Vec3::ZERO ).max_element() < 1e-3, "{:?}", tone_map );
+            let mut previous = 0.;
+            for stop in -8..12 {
+                let value = transform.apply( Vec3::splat( f32::exp2( stop as f32 ) ) );
+                assert!( value.x >= previous - 1e-6 && value.x <= 1., "{:?} at {}: {}", tone_map, stop, value );
+                assert!( ( value.x - value.y ).abs() < 1e-3 && ( value.x - value.z ).abs() < 1e-3, "{:?} tints gray: {}", tone_map, value );
+                previous = value.x;
+            }
+        }
+
+        // Exposure is in stops
+        let transform = ColorTransform { exposure: 2., color_space: ColorSpace::Linear, ..ColorTransform::default() };
+        assert_eq!( transform.apply( Vec3::splat( 0.1 ) ), Vec3::splat( 0.4 ) );
+        assert_eq!( transform.apply( Vec3::splat( 1. ) ), Vec3::ONE );
+    }
+}
@@ -0,0 +1,239 @@
+// Constructive solid geometry: shapes combined from two others by their distances.
+// The blended variants follow https://iquilezles.org/articles/smin/ and the hg_sdf library,
+// https://mercury.sexy/hg_sdf/
+
+use std::borrow::Cow;
+use glam::Vec3;
+use crate::bvh::Aabb;
+use crate::material::Material;
+use crate::rays::Hittable;
+
+#[derive( Clone, Copy, Debug, PartialEq, Eq )]
+pub enum Operation {
+    // Inside of either shape
+    Union,
+    // Inside of both shapes
+    Intersection,
+    // Inside of the first shape but not the second
+    Subtraction
+}
+
+// How the surfaces meet where the shapes cross. The radius is the size of the blended region.
+#[derive( Clone, Copy, Debug, PartialEq )]
+pub enum Blend {
+    Sharp,
+    // Polynomial smooth minimum
+    Smooth( f32 ),
+    // A 45 degree bevel
+    Chamfer( f32 ),
+    // A quarter circle
+    Round( f32 )
+}
+
+impl Blend {
+    fn radius( &self ) -> f32 {
+        match *self {
+            Blend::Sharp => 0.,
+            Blend::Smooth( r ) | Blend::Chamfer( r ) | Blend::Round( r ) => r
+        }
+    }
+
+    // The blended minimum of two distances
+    pub fn union( &self, a: f32, b: f32 ) -> f32 {
+        match *self {
+            Blend::Sharp => a.min( b ),
+            Blend::Smooth( k ) => {
+                let h = f32::max( k - ( a - b ).abs(), 0. ) / k;
+                a.min( b ) - h * h * k * 0.25
+            },
+            Blend::Chamfer( r ) => a.min( b ).min( ( a + b - r ) * std::f32::consts::FRAC_1_SQRT_2 ),
+            Blend::Round( r ) => {
+                let u = Vec3::new( r - a, r - b, 0. ).max( Vec3::ZERO );
+                r.max( a.min( b ) ) - u.length()
+            }
+        }
+    }
+
+    // The blended maximum of two distances
+    pub fn intersection( &self, a: f32, b: f32 ) -> f32 {
+        -self.union( -a, -b )
+    }
+
+    // Weight of the second shape's material at a point with the given distances, for a union
+    fn weight( &self, a: f32, b: f32 ) -> f32 {
+        match *self {
+            Blend::Smooth( k ) => ( 0.5 + 0.5 * ( a - b ) / k ).clamp( 0., 1. ),
+            _ => if b < a { 1. } else { 0. }
+        }
+    }
+}
+
+pub struct Csg<A: Hittable, B: Hittable> {
+    pub a: A,
+    pub b: B,
+    pub operation: Operation,
+    pub blend: Blend
+}
+
+impl<A: Hittable, B: Hittable> Csg<A, B> {
+    // Weight of the second shape's surface at pos, a subtraction only has surfaces of the first
+    fn weight( &self, pos: Vec3 ) -> f32 {
+        let ( a, b ) = ( self.a.distance( pos ), self.b.distance( pos ) );
+        match self.operation {
+            Operation::Union => self.blend.weight( a, b ),
+            Operation::Intersection => self.blend.weight( -a, -b ),
+            Operation::Subtraction => 0.
+        }
+    }
+}
+
+impl<A: Hittable, B: Hittable> Hittable for Csg<A, B> {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let ( a, b ) = ( self.a.distance( pos ), self.b.distance( pos ) );
+        match self.operation {
+            Operation::Union => self.blend.union( a, b ),
+            Operation::Intersection => self.blend.intersection( a, b ),
+            Operation::Subtraction => self.blend.intersection( a, -b )
+        }
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let a = self.a.bounds();
+        match self.operation {
+            // Blending only adds material within the blend radius of both shapes
+            Operation::Union => {
+                let union = a.union( &self.b.bounds() );
+                let radius = self.blend.radius();
+                Aabb::new( union.min - radius, union.max + radius )
+            },
+            Operation::Intersection => {
+                let b = self.b.bounds();
+                let both = Aabb { min: a.min.max( b.min ), max: a.max.min( b.max ) };
+                if both.min.cmple( both.max ).all() { both } else { a }
+            },
+            Operation::Subtraction => a
+        }
+    }
+
+    // The material of the first shape, which is what is left over after a subtraction
+    fn material( &self ) -> & Material {
+        self.a.material()
+    }
+
+    // Surfaces get the material of the shape they belong to, smooth blends mix both materials
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        let weight = self.weight( pos );
+        if weight <= 0. {
+            self.a.material_at( pos )
+        } else if weight >= 1. {
+            self.b.material_at( pos )
+        } else {
+            Cow::Owned( self.a.material_at( pos ).blend( &self.b.material_at( pos ), weight ) )
+        }
+    }
+
+    // Mixed like the materials, a shape without a trap leaves the other one's
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        let weight = self.weight( pos );
+        match ( self.a.orbit_trap( pos ), self.b.orbit_trap( pos ) ) {
+            ( a, _ ) if weight <= 0. => a,
+            ( _, b ) if weight >= 1. => b,
+            ( Some( a ), Some( b ) ) => Some( a + ( b - a ) * weight ),
+            ( a, b ) => a.or( b )
+        }
+    }
+
+    // Emitters of both shapes, except for the cut away one of a subtraction. Blends spread them by the blend radius.
+    fn emitters( &self ) -> Vec<Aabb> {
+        let ( bounds, radius ) = ( self.bounds(), self.blend.radius() );
+        let b = if self.operation == Operation::Subtraction { vec![] } else { self.b.emitters() };
+        self.a.emitters().into_iter().chain( b )
+            .map( | e | Aabb { min: ( e.min - radius ).max( bounds.min ), max: ( e.max + radius ).min( bounds.max ) } )
+            .filter( | e | e.min.cmple( e.max ).all() )
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use glam::Vec3;
+    use super::{Blend, Csg, Operation};
+    use crate::fractal::MengerSponge;
+    use crate::material::{Bsdf, Material};
+    use crate::rays::{Hittable, Sphere};
+
+    fn sphere( x: f32, color: Vec3 ) -> Sphere {
+        Sphere { position: Vec3::new( x, 0., 0. ), radius: 1., material: Arc::new( Material::lambertian( color ) ) }
+    }
+
+    fn csg( operation: Operation, blend: Blend ) -> Csg<Sphere, Sphere> {
+        Csg { a: sphere( -0.5, Vec3::X ), b: sphere( 0.5, Vec3::Y ), operation, blend }
+    }
+
+    #[test]
+    fn sharp_operations() {
+        let union = csg( Operation::Union, Blend::Sharp );
+        assert_eq!( union.distance( Vec3::new( 3., 0., 0. ) ), 1.5 );
+        assert_eq!( union.distance( Vec3::new( -3., 0., 0. ) ), 1.5 );
+
+        let intersection = csg( Operation::Intersection, Blend::Sharp );
+        assert_eq!( intersection.distance( Vec3::new( 3., 0., 0. ) ), 2.5 );
+        assert_eq!( intersection.distance( Vec3::ZERO ), -0.5 );
+
+        let subtraction = csg( Operation::Subtraction, Blend::Sharp );
+        assert_eq!( subtraction.distance( Vec3::new( -1., 0., 0. ) ), -0.5 );
+        assert_eq!( subtraction.distance( Vec3::ZERO ), 0.5 );
+    }
+
+    #[test]
+    fn blends_only_change_the_crossing() {
+        for blend in [ Blend::Smooth( 0.5 ), Blend::Chamfer( 0.5 ), Blend::Round( 0.5 ) ] {
+            // Far from where the surfaces cross the distance is the same as a sharp union
+            let union = csg( Operation::Union, blend );
+            assert!( ( union.distance( Vec3::new( 3., 0., 0. ) ) - 1.5 ).abs() < 1e-6 );
+            // In the crease between the spheres the blend fills in material
+            let crease = Vec3::new( 0., 1., 0. );
+            assert!( union.distance( crease ) < csg( Operation::Union, Blend::Sharp ).distance( crease ) );
+            // Intersections are cut away instead
+            let intersection = csg( Operation::Intersection, blend );
+            let sharp = csg( Operation::Intersection, Blend::Sharp );
+            assert!( intersection.distance( Vec3::new( 0., 0.8, 0. ) ) > sharp.distance( Vec3::new( 0., 0.8, 0. ) ) );
+            assert!( intersection.distance( Vec3::new( 3., 0., 0. ) ) >= 2.5 );
+            // The blended surface stays inside the bounds
+            assert!( union.bounds().distance( crease ) <= union.distance( crease ).max( 0. ) );
+        }
+    }
+
+    #[test]
+    fn smooth_union_blends_materials() {
+        let union = csg( Operation::Union, Blend::Smooth( 1. ) );
+        let albedo = | pos: Vec3 | match union.material_at( pos ).bsdf {
+            Bsdf::Lambertian { albedo } => albedo,
+            _ => panic!( "unexpected material" )
+        };
+        assert_eq!( albedo( Vec3::new( -2., 0., 0. ) ), Vec3::X );
+        assert_eq!( albedo( Vec3::new( 2., 0., 0. ) ), Vec3::Y );
+        assert!( ( albedo( Vec3::new( 0., 1., 0. ) ) - Vec3::new( 0.5, 0.5, 0. ) ).length() < 1e-6 );
+
+        let sharp = csg( Operation::Union, Blend::Sharp );
+        assert_eq!( sharp.material_at( Vec3::new( 0.1, 1., 0. ) ).bsdf, Bsdf::Lambertian { albedo: Vec3::Y } );
+    }
+
+    #[test]
+    fn emitters_of_either_shape() {
+        let glowing = Sphere { material: Arc::new( Material { emission: Vec3::ONE, ..Material::lambertian( Vec3::ZERO ) } ), ..sphere( 0.5, Vec3::ZERO ) };
+        let union = Csg { a: sphere( -0.5, Vec3::X ), b: glowing, operation: Operation::Union, blend: Blend::Sharp };
+        assert_eq!( union.emitters(), vec![ union.b.bounds() ] );
+        let subtraction = Csg { operation: Operation::Subtraction, ..union };
+        assert!( subtraction.emitters().is_empty() );
+    }
+
+    #[test]
+    fn orbit_trap_of_either_shape() {
+        let sponge = MengerSponge { position: Vec3::new( 3., 0., 0. ), size: 1., iterations: 3, material: Arc::new( Material::lambertian( Vec3::ONE ) ) };
+        let union = Csg { a: sphere( -3., Vec3::X ), b: sponge, operation: Operation::Union, blend: Blend::Smooth( 0.5 ) };
+        assert_eq!( union.orbit_trap( Vec3::new( 3., 0., 0. ) ), Some( 1. / 3. ) );
+        assert_eq!( union.orbit_trap( Vec3::new( -3., 0., 0. ) ), None );
+    }
+}
@@ -0,0 +1,553 @@
+// Domain operators: shapes that evaluate another shape at a changed position to repeat, mirror or deform it.
+// https://iquilezles.org/articles/distfunctions/
+//
+// Marching relies on the distance never being larger than the true distance to the surface. Folding space with a
+// continuous map that doesn't stretch distances keeps that property, the other operators below make up for the
+// parts where it doesn't hold, see each of them.
+
+use std::borrow::Cow;
+use std::f32::consts::{FRAC_PI_2, TAU};
+use glam::{BVec3, Vec2, Vec3};
+use crate::bvh::Aabb;
+use crate::material::Material;
+use crate::rays::Hittable;
+
+// Copies of a shape on a grid with `spacing` between them, centered around the origin. `limit` is the number of
+// copies on either side of the center copy per axis, infinite for an endless grid. Axes with a spacing of 0 are
+// not repeated. Only bounded shapes can be repeated.
+pub struct Repeat<H: Hittable> {
+    shape: H,
+    spacing: Vec3,
+    limit: Vec3
+}
+
+impl<H: Hittable> Repeat<H> {
+    pub fn infinite( shape: H, spacing: Vec3 ) -> Option<Repeat<H>> {
+        Repeat::limited( shape, spacing, Vec3::splat( f32::INFINITY ) )
+    }
+
+    pub fn limited( shape: H, spacing: Vec3, limit: Vec3 ) -> Option<Repeat<H>> {
+        shape.bounds().is_finite().then_some( Repeat { shape, spacing, limit } )
+    }
+
+    // The nearest copy as the position relative to it and its distance, together with a lower bound on the
+    // distance to every other copy.
+    //
+    // Only the cell of pos and the neighbouring cells towards pos are evaluated. Any other copy lies at least as far
+    // away as the far sides of those cells, less however far the shape sticks out of its own cell.
+    fn nearest( &self, pos: Vec3 ) -> ( Vec3, f32, f32 ) {
+        let bounds = self.shape.bounds();
+        let overhang = ( bounds.min.abs().max( bounds.max.abs() ) - self.spacing * 0.5 ).max( Vec3::ZERO );
+        let mut cells = [ Vec2::ZERO; 3 ];
+        let mut others = f32::INFINITY;
+        for axis in 0..3 {
+            let spacing = self.spacing[ axis ];
+            if spacing <= 0. {
+                continue;
+            }
+            let limit = self.limit[ axis ];
+            let cell = ( pos[ axis ] / spacing ).round().clamp( -limit, limit );
+            let offset = pos[ axis ] - cell * spacing;
+            let neighbour = ( cell + offset.signum() ).clamp( -limit, limit );
+            cells[ axis ] = Vec2::new( cell, neighbour ) * spacing;
+            others = others.min( spacing * 0.5 + offset.abs() - overhang[ axis ] );
+        }
+
+        let mut nearest = ( pos, f32::INFINITY );
+        for corner in 0..8 {
+            let cell = Vec3::new( cells[ 0 ][ corner & 1 ], cells[ 1 ][ ( corner >> 1 ) & 1 ], cells[ 2 ][ corner >> 2 ] );
+            let local = pos - cell;
+            let dist = self.shape.distance( local );
+            if dist < nearest.1 {
+                nearest = ( local, dist );
+            }
+        }
+        // All copies are inside the bounds as well, which matters along the axes that are not repeated
+        ( nearest.0, nearest.1, others.max( self.bounds().distance( pos ) ) )
+    }
+}
+
+impl<H: Hittable> Hittable for Repeat<H> {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let ( _, dist, others ) = self.nearest( pos );
+        dist.min( others )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let bounds = self.shape.bounds();
+        let extent = self.spacing * self.limit;
+        // 0 * infinity for axes that are not repeated
+        let extent = Vec3::select( self.spacing.cmpgt( Vec3::ZERO ), extent, Vec3::ZERO );
+        Aabb::new( bounds.min - extent, bounds.max + extent )
+    }
+
+    fn material( &self ) -> & Material {
+        self.shape.material()
+    }
+
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        self.shape.material_at( self.nearest( pos ).0 )
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        self.shape.orbit_trap( self.nearest( pos ).0 )
+    }
+
+    // Every copy of an emitter emits, so the light covers all of them
+    fn emitters( &self ) -> Vec<Aabb> {
+        if self.shape.emitters().is_empty() { vec![] } else { vec![ self.bounds() ] }
+    }
+}
+
+// `count` copies of a shape spread evenly around the y axis, the original being the one on the positive x axis
+pub struct PolarRepeat<H: Hittable> {
+    pub shape: H,
+    pub count: u32
+}
+
+impl<H: Hittable> PolarRepeat<H> {
+    fn sector( &self ) -> f32 {
+        TAU / self.count.max( 1 ) as f32
+    }
+
+    // Like `Repeat::nearest`, with the sector of pos and the neighbouring one towards pos. The other copies lie
+    // beyond the far edges of the two sectors, as long as the shape stays inside its own sector.
+    fn nearest( &self, pos: Vec3 ) -> ( Vec3, f32, f32 ) {
+        let sector = self.sector();
+        let angle = pos.z.atan2( pos.x );
+        let cell = ( angle / sector ).round();
+        let offset = angle - cell * sector;
+        let rotate = | cell: f32 | {
+            let ( sin, cos ) = ( -cell * sector ).sin_cos();
+            Vec3::new( cos * pos.x - sin * pos.z, pos.y, sin * pos.x + cos * pos.z )
+        };
+
+        let ( own, neighbour ) = ( rotate( cell ), rotate( cell + offset.signum() ) );
+        let ( own_dist, neighbour_dist ) = ( self.shape.distance( own ), self.shape.distance( neighbour ) );
+        let nearest = if neighbour_dist < own_dist { ( neighbour, neighbour_dist ) } else { ( own, own_dist ) };
+
+        // With at most two sectors both copies are evaluated. Otherwise the edges of the sectors meet at the axis,
+        // which copies keep away from by as much as the shape's bounds do.
+        let others = if self.count <= 2 {
+            f32::INFINITY
+        } else {
+            let bounds = self.shape.bounds();
+            let to_axis = Vec2::ZERO.clamp( Vec2::new( bounds.min.x, bounds.min.z ), Vec2::new( bounds.max.x, bounds.max.z ) ).length();
+            let radius = Vec2::new( pos.x, pos.z ).length();
+            f32::max( radius * f32::min( sector * 0.5 + offset.abs(), FRAC_PI_2 ).sin(), to_axis - radius )
+        };
+        ( nearest.0, nearest.1, others.max( self.bounds().distance( pos ) ) )
+    }
+}
+
+impl<H: Hittable> Hittable for PolarRepeat<H> {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let ( _, dist, others ) = self.nearest( pos );
+        dist.min( others )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let bounds = self.shape.bounds();
+        let radius = Vec2::new( bounds.min.x.abs().max( bounds.max.x.abs() ), bounds.min.z.abs().max( bounds.max.z.abs() ) ).length();
+        Aabb::new( Vec3::new( -radius, bounds.min.y, -radius ), Vec3::new( radius, bounds.max.y, radius ) )
+    }
+
+    fn material( &self ) -> & Material {
+        self.shape.material()
+    }
+
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        self.shape.material_at( self.nearest( pos ).0 )
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        self.shape.orbit_trap( self.nearest( pos ).0 )
+    }
+
+    fn emitters( &self ) -> Vec<Aabb> {
+        if self.shape.emitters().is_empty() { vec![] } else { vec![ self.bounds() ] }
+    }
+}
+
+// Mirrors the positive side of the shape across the planes through the origin perpendicular to `axes`
+pub struct Mirror<H: Hittable> {
+    pub shape: H,
+    pub axes: BVec3
+}
+
+impl<H: Hittable> Mirror<H> {
+    fn fold( &self, pos: Vec3 ) -> Vec3 {
+        Vec3::select( self.axes, pos.abs(), pos )
+    }
+}
+
+impl<H: Hittable> Hittable for Mirror<H> {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        self.shape.distance( self.fold( pos ) )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let bounds = self.shape.bounds();
+        let extent = bounds.min.abs().max( bounds.max.abs() );
+        Aabb::new( Vec3::select( self.axes, -extent, bounds.min ), Vec3::select( self.axes, extent, bounds.max ) )
+    }
+
+    fn material( &self ) -> & Material {
+        self.shape.material()
+    }
+
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        self.shape.material_at( self.fold( pos ) )
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        self.shape.orbit_trap( self.fold( pos ) )
+    }
+
+    fn emitters( &self ) -> Vec<Aabb> {
+        if self.shape.emitters().is_empty() { vec![] } else { vec![ self.bounds() ] }
+    }
+}
+
+// Twists a shape around the y axis by `rate` radians per unit of height. Only bounded shapes can be twisted.
+pub struct Twist<H: Hittable> {
+    shape: H,
+    rate: f32,
+    region: Aabb,
+    lipschitz: f32
+}
+
+impl<H: Hittable> Twist<H> {
+    pub fn new( shape: H, rate: f32 ) -> Option<Twist<H>> {
+        let bounds = shape.bounds();
+        if !bounds.is_finite() {
+            return None;
+        }
+        // Twisting keeps the height and the distance from the axis
+        let radius = bounds.corners().iter().map( | c | Vec2::new( c.x, c.z ).length() ).fold( 0., f32::max );
+        let region = Aabb::new( Vec3::new( -radius, bounds.min.y, -radius ), Vec3::new( radius, bounds.max.y, radius ) );
+        Some( Twist { shape, rate, region, lipschitz: 1. + rate.abs() * radius } )
+    }
+
+    fn untwist( &self, pos: Vec3 ) -> Vec3 {
+        let ( sin, cos ) = ( self.rate * pos.y ).sin_cos();
+        Vec3::new( cos * pos.x - sin * pos.z, pos.y, sin * pos.x + cos * pos.z )
+    }
+}
+
+impl<H: Hittable> Hittable for Twist<H> {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        bounded_distance( &self.region, pos, | p | self.shape.distance( self.untwist( p ) ) / self.lipschitz )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        self.region
+    }
+
+    fn material( &self ) -> & Material {
+        self.shape.material()
+    }
+
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        self.shape.material_at( self.untwist( pos ) )
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        self.shape.orbit_trap( self.untwist( pos ) )
+    }
+
+    fn emitters( &self ) -> Vec<Aabb> {
+        if self.shape.emitters().is_empty() { vec![] } else { vec![ self.bounds() ] }
+    }
+}
+
+// Bends a shape in the xy plane, turning it around the z axis by `rate` radians per unit along x. Only bounded
+// shapes can be bent.
+pub struct Bend<H: Hittable> {
+    shape: H,
+    rate: f32,
+    region: Aabb,
+    lipschitz: f32
+}
+
+impl<H: Hittable> Bend<H> {
+    pub fn new( shape: H, rate: f32 ) -> Option<Bend<H>> {
+        let bounds = shape.bounds();
+        if !bounds.is_finite() {
+            return None;
+        }
+        // Bending keeps z and the distance from the z axis
+        let radius = bounds.corners().iter().map( | c | Vec2::new( c.x, c.y ).length() ).fold( 0., f32::max );
+        let region = Aabb::new( Vec3::new( -radius, -radius, bounds.min.z ), Vec3::new( radius, radius, bounds.max.z ) );
+        Some( Bend { shape, rate, region, lipschitz: 1. + rate.abs() * radius } )
+    }
+
+    fn unbend( &self, pos: Vec3 ) -> Vec3 {
+        let ( sin, cos ) = ( self.rate * pos.x ).sin_cos();
+        Vec3::new( cos * pos.x - sin * pos.y, sin * pos.x + cos * pos.y, pos.z )
+    }
+}
+
+impl<H: Hittable> Hittable for Bend<H> {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        bounded_distance( &self.region, pos, | p | self.shape.distance( self.unbend( p ) ) / self.lipschitz )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        self.region
+    }
+
+    fn material( &self ) -> & Material {
+        self.shape.material()
+    }
+
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        self.shape.material_at( self.unbend( pos ) )
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        self.shape.orbit_trap( self.unbend( pos ) )
+    }
+
+    fn emitters( &self ) -> Vec<Aabb> {
+        if self.shape.emitters().is_empty() { vec![] } else { vec![ self.bounds() ] }
+    }
+}
+
+// Stretches a shape by inserting `size` (half lengths) of straight extrusion through the center of its bounds
+pub struct Elongate<H: Hittable> {
+    pub shape: H,
+    pub size: Vec3
+}
+
+impl<H: Hittable> Elongate<H> {
+    fn center( &self ) -> Vec3 {
+        let bounds = self.shape.bounds();
+        if bounds.is_finite() { bounds.center() } else { Vec3::ZERO }
+    }
+
+    fn shrink( &self, pos: Vec3 ) -> Vec3 {
+        let q = pos - self.center();
+        q - q.clamp( -self.size, self.size ) + self.center()
+    }
+}
+
+impl<H: Hittable> Hittable for Elongate<H> {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        self.shape.distance( self.shrink( pos ) )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let bounds = self.shape.bounds();
+        Aabb::new( bounds.min - self.size, bounds.max + self.size )
+    }
+
+    fn material( &self ) -> & Material {
+        self.shape.material()
+    }
+
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        self.shape.material_at( self.shrink( pos ) )
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        self.shape.orbit_trap( self.shrink( pos ) )
+    }
+
+    fn emitters( &self ) -> Vec<Aabb> {
+        self.shape.emitters().into_iter().map( | bounds | Aabb::new( bounds.min - self.size, bounds.max + self.size ) ).collect()
+    }
+}
+
+// Grows the surface outwards by `radius`, rounding off its edges
+pub struct Round<H: Hittable> {
+    pub shape: H,
+    pub radius: f32
+}
+
+impl<H: Hittable> Hittable for Round<H> {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        self.shape.distance( pos ) - self.radius
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let bounds = self.shape.bounds();
+        Aabb::new( bounds.min - self.radius, bounds.max + self.radius )
+    }
+
+    fn material( &self ) -> & Material {
+        self.shape.material()
+    }
+
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        self.shape.material_at( pos )
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        self.shape.orbit_trap( pos )
+    }
+
+    fn emitters( &self ) -> Vec<Aabb> {
+        self.shape.emitters().into_iter().map( | bounds | Aabb::new( bounds.min - self.radius, bounds.max + self.radius ) ).collect()
+    }
+}
+
+// A shell of `thickness` on either side of the surface, hollowing out the shape
+pub struct Onion<H: Hittable> {
+    pub shape: H,
+    pub thickness: f32
+}
+
+impl<H: Hittable> Hittable for Onion<H> {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        self.shape.distance( pos ).abs() - self.thickness
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let bounds = self.shape.bounds();
+        Aabb::new( bounds.min - self.thickness, bounds.max + self.thickness )
+    }
+
+    fn material( &self ) -> & Material {
+        self.shape.material()
+    }
+
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        self.shape.material_at( pos )
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        self.shape.orbit_trap( pos )
+    }
+
+    fn emitters( &self ) -> Vec<Aabb> {
+        self.shape.emitters().into_iter().map( | bounds | Aabb::new( bounds.min - self.thickness, bounds.max + self.thickness ) ).collect()
+    }
+}
+
+// Distance to a surface inside a convex region, given a lower bound that only holds inside of it. From outside,
+// the projection q onto the region is closer to every point of the surface s, as |p - s|^2 >= |p - q|^2 + |q - s|^2.
+pub( crate ) fn bounded_distance<F: Fn( Vec3 ) -> f32>( region: &Aabb, pos: Vec3, inside: F ) -> f32 {
+    let outside = region.distance( pos );
+    if outside <= 0. {
+        return inside( pos );
+    }
+    let projected = pos.clamp( region.min, region.max );
+    Vec2::new( outside, inside( projected ).max( 0. ) ).length()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use glam::{BVec3, Vec3};
+    use super::{Bend, Elongate, Mirror, Onion, PolarRepeat, Repeat, Round, Twist};
+    use crate::fractal::MengerSponge;
+    use crate::material::Material;
+    use crate::rays::{Hittable, Sphere, Wall};
+
+    fn sphere( position: Vec3, radius: f32 ) -> Sphere {
+        Sphere { position, radius, material: Arc::new( Material::lambertian( Vec3::ONE ) ) }
+    }
+
+    fn bar() -> Wall {
+        Wall { position: Vec3::ZERO, rotation: glam::Mat4::IDENTITY, size: Vec3::new( 0.3, 2., 0.1 ), material: Arc::new( Material::lambertian( Vec3::ONE ) ) }
+    }
+
+    // Marching along rays never moves past a surface: stepping by the distance can't cross a sign change of the
+    // exact distance, which is approximated by sampling the line finely
+    fn assert_safe( shape: &dyn Hittable, points: &[Vec3] ) {
+        let directions = [ Vec3::X, Vec3::NEG_X, Vec3::Y, Vec3::NEG_Y, Vec3::Z, Vec3::NEG_Z, Vec3::new( 1., 1., 1. ).normalize(), Vec3::new( -1., 0.5, 0.2 ).normalize() ];
+        for &start in points {
+            for &direction in &directions {
+                let d = shape.distance( start );
+                if d <= 0. {
+                    continue;
+                }
+                for i in 0..=100 {
+                    let t = d * i as f32 / 100.;
+                    assert!( shape.distance( start + direction * t ) > -1e-4, "stepped through the surface from {} towards {}", start, direction );
+                }
+            }
+        }
+    }
+
+    fn grid() -> Vec<Vec3> {
+        let mut points = vec![];
+        for x in -6..=6 {
+            for y in -3..=3 {
+                for z in -2..=2 {
+                    points.push( Vec3::new( x as f32 * 0.77, y as f32 * 0.61, z as f32 * 0.93 ) );
+                }
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn repetition() {
+        let infinite = Repeat::infinite( sphere( Vec3::ZERO, 0.5 ), Vec3::new( 2., 0., 2. ) ).unwrap();
+        assert_eq!( infinite.distance( Vec3::new( 100., 0., -40. ) ), -0.5 );
+        assert_eq!( infinite.distance( Vec3::new( 101., 0., 0. ) ), 0.5 );
+        assert!( !infinite.bounds().is_finite() );
+
+        let limited = Repeat::limited( sphere( Vec3::ZERO, 0.5 ), Vec3::new( 2., 0., 0. ), Vec3::new( 1., 0., 0. ) ).unwrap();
+        assert_eq!( limited.distance( Vec3::new( 2., 0., 0. ) ), -0.5 );
+        assert_eq!( limited.distance( Vec3::new( 4., 0., 0. ) ), 1.5 );
+        assert_eq!( limited.bounds().max.x, 2.5 );
+
+        // Copies larger than their cell overlap and stay safe to march
+        let overlapping = Repeat::limited( sphere( Vec3::new( 0.4, 0., 0. ), 1.4 ), Vec3::new( 2., 2., 0. ), Vec3::new( 2., 1., 0. ) ).unwrap();
+        assert_safe( &infinite, &grid() );
+        assert_safe( &overlapping, &grid() );
+    }
+
+    #[test]
+    fn polar_repetition() {
+        let ring = PolarRepeat { shape: sphere( Vec3::new( 3., 0., 0. ), 0.5 ), count: 8 };
+        for i in 0..8 {
+            let angle = i as f32 * std::f32::consts::TAU / 8.;
+            assert!( ( ring.distance( Vec3::new( angle.cos(), 0., angle.sin() ) * 3. ) + 0.5 ).abs() < 1e-5 );
+        }
+        assert!( ring.distance( Vec3::new( 0., 0., 0. ) ) > 2. );
+        assert_safe( &ring, &grid() );
+    }
+
+    #[test]
+    fn mirroring() {
+        let corners = Mirror { shape: sphere( Vec3::splat( 2. ), 1. ), axes: BVec3::new( true, true, false ) };
+        assert_eq!( corners.distance( Vec3::new( -2., -2., 2. ) ), -1. );
+        assert_eq!( corners.distance( Vec3::new( -2., -2., -2. ) ), 3. );
+        assert_eq!( corners.bounds().min, Vec3::new( -3., -3., 1. ) );
+        assert_safe( &corners, &grid() );
+
+        // Fractals keep their orbit traps in the mirrored copies
+        let sponge = MengerSponge { position: Vec3::new( 5., 0., 0. ), size: 3., iterations: 3, material: Arc::new( Material::lambertian( Vec3::ONE ) ) };
+        let mirrored = Mirror { shape: sponge, axes: BVec3::new( true, false, false ) };
+        assert_eq!( mirrored.orbit_trap( Vec3::new( -5., 0., 0. ) ), Some( 1. / 3. ) );
+        assert_eq!( Round { shape: mirrored, radius: 0.1 }.orbit_trap( Vec3::new( -5., 0., 0. ) ), Some( 1. / 3. ) );
+    }
+
+    #[test]
+    fn deformations() {
+        let twisted = Twist::new( bar(), 1.2 ).unwrap();
+        // The top of the bar is turned by 2.4 radians
+        let ( sin, cos ) = ( -2.4f32 ).sin_cos();
+        assert!( twisted.distance( Vec3::new( cos * 0.25, 1.99, sin * 0.25 ) ) < 0. );
+        assert_safe( &twisted, &grid() );
+
+        let bent = Bend::new( Wall { size: Vec3::new( 3., 0.2, 0.5 ), ..bar() }, 0.4 ).unwrap();
+        assert_safe( &bent, &grid() );
+        assert!( Twist::new( crate::shapes::Plane { normal: Vec3::Y, offset: 0., material: Arc::new( Material::lambertian( Vec3::ONE ) ) }, 1. ).is_none() );
+
+        let capsule = Elongate { shape: sphere( Vec3::new( 1., 0., 0. ), 0.5 ), size: Vec3::new( 0., 1., 0. ) };
+        assert!( ( capsule.distance( Vec3::new( 1., 1.4, 0. ) ) + 0.1 ).abs() < 1e-6 );
+        assert_eq!( capsule.distance( Vec3::new( 2., 0.5, 0. ) ), 0.5 );
+        assert_safe( &capsule, &grid() );
+
+        let rounded = Round { shape: bar(), radius: 0.2 };
+        assert!( ( rounded.distance( Vec3::new( 0.5, 0., 0. ) ) ).abs() < 1e-6 );
+        let shell = Onion { shape: sphere( Vec3::ZERO, 1. ), thickness: 0.1 };
+        assert_eq!( shell.distance( Vec3::ZERO ), 0.9 );
+        assert!( shell.distance( Vec3::new( 0.95, 0., 0. ) ) < 0. );
+    }
+}
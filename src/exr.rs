@@ -0,0 +1,482 @@
+// OpenEXR files, for linear images that are composited or graded by other tools.
+//
+// Only single part scanline images are supported. Files are written with R, G, B and optionally A channels of half or
+// full floats, uncompressed or with ZIP compression. Reading accepts the same compressions, any of the HALF, FLOAT and
+// UINT pixel types and ignores channels other than R, G, B, A and Y.
+// https://openexr.com/en/latest/OpenEXRFileLayout.html
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use glam::Vec4;
+use crate::image::{Framebuffer, ImageError};
+
+const MAGIC: [u8; 4] = [ 0x76, 0x2f, 0x31, 0x01 ];
+const VERSION: u8 = 2;
+const TILED: u32 = 0x200;
+// Deep data and multi part files
+const UNSUPPORTED: u32 = 0x800 | 0x1000;
+
+#[derive( Clone, Copy, Debug, PartialEq, Eq )]
+pub enum Precision {
+    // 16-bit floats, enough for color and half the size
+    Half,
+    Float
+}
+
+#[derive( Clone, Copy, Debug, PartialEq, Eq )]
+pub enum Compression {
+    None,
+    // Deflate over blocks of 16 scanlines
+    Zip
+}
+
+#[derive( Clone, Copy, Debug )]
+pub struct ExrSettings {
+    pub precision: Precision,
+    pub compression: Compression,
+    // Write the alpha channel next to the colors
+    pub alpha: bool
+}
+
+impl Default for ExrSettings {
+    fn default() -> Self {
+        ExrSettings { precision: Precision::Half, compression: Compression::Zip, alpha: true }
+    }
+}
+
+// Values of the compression attribute and the number of scanlines per chunk
+fn compression_code( compression: Compression ) -> ( u8, u32 ) {
+    match compression {
+        Compression::None => ( 0, 1 ),
+        Compression::Zip => ( 3, 16 )
+    }
+}
+
+// Round to the nearest 16-bit float, values too large become infinity
+pub fn f32_to_f16( value: f32 ) -> u16 {
+    let bits = value.to_bits();
+    let sign = ( ( bits >> 16 ) & 0x8000 ) as u16;
+    let exponent = ( ( bits >> 23 ) & 0xff ) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exponent < -10 {
+        return sign;
+    }
+
+    // Shift the mantissa, with the implicit bit for numbers that become subnormal, rounding half to even. A carry out
+    // of the mantissa correctly moves on to the exponent.
+    let ( base, mantissa, shift ) = if exponent > 0 { ( ( exponent as u32 ) << 10, mantissa, 13 ) } else { ( 0, mantissa | 0x80_0000, ( 14 - exponent ) as u32 ) };
+    let mut half = base + ( mantissa >> shift );
+    let rest = mantissa & ( ( 1 << shift ) - 1 );
+    let halfway = 1 << ( shift - 1 );
+    if rest > halfway || ( rest == halfway && half & 1 == 1 ) {
+        half += 1;
+    }
+    sign | half as u16
+}
+
+pub fn f16_to_f32( half: u16 ) -> f32 {
+    let sign = ( ( half & 0x8000 ) as u32 ) << 16;
+    let exponent = ( ( half >> 10 ) & 0x1f ) as u32;
+    let mantissa = ( half & 0x3ff ) as u32;
+    match exponent {
+        0 => f32::copysign( mantissa as f32 / ( 1 << 24 ) as f32, f32::from_bits( sign | 0x3f80_0000 ) ),
+        0x1f => f32::from_bits( sign | 0x7f80_0000 | ( mantissa << 13 ) ),
+        _ => f32::from_bits( sign | ( ( exponent + 112 ) << 23 ) | ( mantissa << 13 ) )
+    }
+}
+
+// Split the bytes into the even and odd ones and store the differences between them, which deflate compresses
+// better for smooth images
+fn zip_filter( data: &[u8] ) -> Vec<u8> {
+    let mut filtered: Vec<u8> = data.iter().step_by( 2 ).chain( data.iter().skip( 1 ).step_by( 2 ) ).copied().collect();
+    for i in ( 1..filtered.len() ).rev() {
+        filtered[ i ] = filtered[ i ].wrapping_sub( filtered[ i - 1 ] ).wrapping_add( 128 );
+    }
+    filtered
+}
+
+fn zip_unfilter( filtered: &mut [u8] ) -> Vec<u8> {
+    for i in 1..filtered.len() {
+        filtered[ i ] = filtered[ i - 1 ].wrapping_add( filtered[ i ] ).wrapping_sub( 128 );
+    }
+    let ( even, odd ) = filtered.split_at( filtered.len().div_ceil( 2 ) );
+    let mut data = Vec::with_capacity( filtered.len() );
+    for ( i, &byte ) in even.iter().enumerate() {
+        data.push( byte );
+        if let Some( &byte ) = odd.get( i ) {
+            data.push( byte );
+        }
+    }
+    data
+}
+
+fn attribute( header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8] ) {
+    for text in [ name, kind ] {
+        header.extend_from_slice( text.as_bytes() );
+        header.push( 0 );
+    }
+    header.extend_from_slice( &( value.len() as i32 ).to_le_bytes() );
+    header.extend_from_slice( value );
+}
+
+pub fn write<W: Write>( image: &Framebuffer, writer: &mut W, settings: &ExrSettings ) -> Result<(), ImageError> {
+    let ( width, height ) = ( image.get_width(), image.get_height() );
+    let ( compression, lines ) = compression_code( settings.compression );
+    let ( pixel_type, size ) = match settings.precision {
+        Precision::Half => ( 1i32, 2 ),
+        Precision::Float => ( 2i32, 4 )
+    };
+    // Channels are stored in alphabetical order, with the index of their component
+    let channels: &[( &str, usize )] = if settings.alpha { &[ ( "A", 3 ), ( "B", 2 ), ( "G", 1 ), ( "R", 0 ) ] } else { &[ ( "B", 2 ), ( "G", 1 ), ( "R", 0 ) ] };
+
+    let mut header = vec![];
+    header.extend_from_slice( &MAGIC );
+    header.extend_from_slice( &[ VERSION, 0, 0, 0 ] );
+    let mut list = vec![];
+    for ( name, _ ) in channels {
+        list.extend_from_slice( name.as_bytes() );
+        list.push( 0 );
+        list.extend_from_slice( &pixel_type.to_le_bytes() );
+        // Perceptually linear flag, reserved bytes and x and y sampling
+        list.extend_from_slice( &[ 0, 0, 0, 0 ] );
+        list.extend_from_slice( &1i32.to_le_bytes() );
+        list.extend_from_slice( &1i32.to_le_bytes() );
+    }
+    list.push( 0 );
+    let window: Vec<u8> = [ 0, 0, width as i32 - 1, height as i32 - 1 ].iter().flat_map( | v | v.to_le_bytes() ).collect();
+    attribute( &mut header, "channels", "chlist", &list );
+    attribute( &mut header, "compression", "compression", &[ compression ] );
+    attribute( &mut header, "dataWindow", "box2i", &window );
+    attribute( &mut header, "displayWindow", "box2i", &window );
+    attribute( &mut header, "lineOrder", "lineOrder", &[ 0 ] );
+    attribute( &mut header, "pixelAspectRatio", "float", &1f32.to_le_bytes() );
+    attribute( &mut header, "screenWindowCenter", "v2f", &[ 0; 8 ] );
+    attribute( &mut header, "screenWindowWidth", "float", &1f32.to_le_bytes() );
+    header.push( 0 );
+    writer.write_all( &header )?;
+
+    // Chunks of scanlines, each line holding all values of one channel after the other
+    let mut chunks = vec![];
+    for y in ( 0..height ).step_by( lines as usize ) {
+        let mut data = Vec::with_capacity( ( width * channels.len() as u32 * size * lines ) as usize );
+        for y in y..u32::min( y + lines, height ) {
+            for &( _, component ) in channels {
+                for x in 0..width {
+                    let value = image.get_pixel( x, y )[ component ];
+                    match settings.precision {
+                        Precision::Half => data.extend_from_slice( &f32_to_f16( value ).to_le_bytes() ),
+                        Precision::Float => data.extend_from_slice( &value.to_le_bytes() )
+                    }
+                }
+            }
+        }
+        if settings.compression == Compression::Zip {
+            // Chunks that do not get smaller are stored as they are
+            let compressed = miniz_oxide::deflate::compress_to_vec_zlib( &zip_filter( &data ), 6 );
+            if compressed.len() < data.len() {
+                data = compressed;
+            }
+        }
+        chunks.push( ( y, data ) );
+    }
+
+    // The offset table points at every chunk from the start of the file
+    let mut offset = ( header.len() + chunks.len() * 8 ) as u64;
+    for ( _, data ) in &chunks {
+        writer.write_all( &offset.to_le_bytes() )?;
+        offset += 8 + data.len() as u64;
+    }
+    for ( y, data ) in &chunks {
+        writer.write_all( &( *y as i32 ).to_le_bytes() )?;
+        writer.write_all( &( data.len() as i32 ).to_le_bytes() )?;
+        writer.write_all( data )?;
+    }
+    Ok( () )
+}
+
+fn format_error<T>( message: &str ) -> Result<T, ImageError> {
+    Err( ImageError::Format( message.to_string() ) )
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes( &mut self, count: usize ) -> Result<&'a [u8], ImageError> {
+        if count > self.data.len() - self.position {
+            return format_error( "unexpected end of file" );
+        }
+        self.position += count;
+        Ok( &self.data[ self.position - count..self.position ] )
+    }
+
+    fn word( &mut self ) -> Result<[u8; 4], ImageError> {
+        let bytes = self.bytes( 4 )?;
+        Ok( [ bytes[ 0 ], bytes[ 1 ], bytes[ 2 ], bytes[ 3 ] ] )
+    }
+
+    fn i32( &mut self ) -> Result<i32, ImageError> {
+        Ok( i32::from_le_bytes( self.word()? ) )
+    }
+
+    fn string( &mut self ) -> Result<&'a str, ImageError> {
+        let length = self.data[ self.position.. ].iter().position( | &b | b == 0 ).ok_or( ImageError::Format( "unterminated name".to_string() ) )?;
+        let text = std::str::from_utf8( self.bytes( length )? ).map_err( | _ | ImageError::Format( "name is not text".to_string() ) )?;
+        self.bytes( 1 )?;
+        Ok( text )
+    }
+}
+
+struct Channel {
+    // Index of the component it is read into, `None` for channels that are skipped and 4 for luminance
+    component: Option<usize>,
+    // 0 for UINT, 1 for HALF and 2 for FLOAT
+    pixel_type: i32
+}
+
+impl Channel {
+    fn size( &self ) -> usize {
+        if self.pixel_type == 1 { 2 } else { 4 }
+    }
+}
+
+pub fn read<R: Read>( reader: &mut R ) -> Result<Framebuffer, ImageError> {
+    let mut data = vec![];
+    reader.read_to_end( &mut data )?;
+    let mut cursor = Cursor { data: &data, position: 0 };
+    if cursor.word()? != MAGIC {
+        return format_error( "not an OpenEXR file" );
+    }
+    let version = u32::from_le_bytes( cursor.word()? );
+    if version & 0xff != VERSION as u32 {
+        return Err( ImageError::Format( format!( "unsupported OpenEXR version {}", version & 0xff ) ) );
+    }
+    if version & TILED != 0 {
+        return format_error( "tiled OpenEXR files are not supported" );
+    }
+    if version & UNSUPPORTED != 0 {
+        return format_error( "deep and multi part OpenEXR files are not supported" );
+    }
+
+    let ( mut channels, mut compression, mut window ) = ( None, None, None );
+    loop {
+        let name = cursor.string()?;
+        if name.is_empty() {
+            break;
+        }
+        let kind = cursor.string()?;
+        let size = cursor.i32()?;
+        if size < 0 {
+            return format_error( "negative attribute size" );
+        }
+        let mut value = Cursor { data: cursor.bytes( size as usize )?, position: 0 };
+        match ( name, kind ) {
+            ( "channels", "chlist" ) => {
+                let mut list = vec![];
+                loop {
+                    let name = value.string()?;
+                    if name.is_empty() {
+                        break;
+                    }
+                    let pixel_type = value.i32()?;
+                    value.word()?;
+                    if !( 0..=2 ).contains( &pixel_type ) {
+                        return Err( ImageError::Format( format!( "unknown pixel type {} of channel {}", pixel_type, name ) ) );
+                    }
+                    if value.i32()? != 1 || value.i32()? != 1 {
+                        return format_error( "subsampled channels are not supported" );
+                    }
+                    let component = [ "R", "G", "B", "A", "Y" ].iter().position( | &c | c == name );
+                    list.push( Channel { component, pixel_type } );
+                }
+                if list.iter().all( | channel | channel.component.is_none() ) {
+                    return format_error( "no R, G, B, A or Y channel" );
+                }
+                channels = Some( list );
+            },
+            ( "compression", "compression" ) => compression = Some( value.bytes( 1 )?[ 0 ] ),
+            ( "dataWindow", "box2i" ) => window = Some( [ value.i32()?, value.i32()?, value.i32()?, value.i32()? ] ),
+            _ => {}
+        }
+    }
+    let ( Some( channels ), Some( compression ), Some( [ x_min, y_min, x_max, y_max ] ) ) = ( channels, compression, window ) else {
+        return format_error( "missing channels, compression or dataWindow attribute" );
+    };
+    let lines = match compression {
+        0 | 2 => 1,
+        3 => 16,
+        _ => return Err( ImageError::Format( format!( "unsupported compression {}, only none and ZIP can be read", compression ) ) )
+    };
+    if x_max < x_min || y_max < y_min {
+        return format_error( "empty data window" );
+    }
+    // Check the size of the data window against the file before allocating the image
+    let ( width, height ) = ( u32::try_from( x_max as i64 - x_min as i64 + 1 ), u32::try_from( y_max as i64 - y_min as i64 + 1 ) );
+    let ( Ok( width ), Ok( height ) ) = ( width, height ) else { return format_error( "data window is too large" ) };
+    let count = width as u64 * height as u64;
+    let line_size = width as usize * channels.iter().map( Channel::size ).sum::<usize>();
+    let chunks = height.div_ceil( lines );
+    if chunks as usize * 8 > data.len() - cursor.position {
+        return format_error( "offset table is larger than the file" );
+    }
+    // Every pixel takes at least a byte, and ZIP packs data by a factor of 1032 at most
+    let limit = data.len() as u64 * if compression == 3 { 1032 } else { 1 };
+    if count > limit || line_size as u64 * height as u64 > limit {
+        return format_error( "data window is larger than the pixel data of the file" );
+    }
+
+    let Some( mut image ) = Framebuffer::try_new( width, height ) else { return format_error( "image is too large" ) };
+    let mut pixels = vec![ Vec4::W; count as usize ];
+    for chunk in 0..chunks {
+        let offset = u64::from_le_bytes( cursor.bytes( 8 )?.try_into().unwrap() );
+        let mut block = Cursor { data: &data, position: usize::try_from( offset ).unwrap_or( usize::MAX ).min( data.len() ) };
+        let first = ( block.i32()? as i64 - y_min as i64 ) as u32;
+        if first != chunk * lines {
+            return format_error( "scanline chunks out of order" );
+        }
+        let size = block.i32()?;
+        let count = u32::min( lines, height - first );
+        let packed = block.bytes( usize::try_from( size ).map_err( | _ | ImageError::Format( "negative chunk size".to_string() ) )? )?;
+        let unpacked;
+        let raw = if compression == 0 || packed.len() == line_size * count as usize {
+            packed
+        } else {
+            let mut filtered = miniz_oxide::inflate::decompress_to_vec_zlib( packed ).map_err( | _ | ImageError::Format( "corrupt ZIP data".to_string() ) )?;
+            unpacked = zip_unfilter( &mut filtered );
+            &unpacked
+        };
+        if raw.len() != line_size * count as usize {
+            return format_error( "chunk has the wrong size" );
+        }
+
+        let mut values = Cursor { data: raw, position: 0 };
+        for y in first..first + count {
+            for channel in &channels {
+                for x in 0..width {
+                    let value = match channel.pixel_type {
+                        0 => u32::from_le_bytes( values.word()? ) as f32,
+                        1 => f16_to_f32( u16::from_le_bytes( values.bytes( 2 )?.try_into().unwrap() ) ),
+                        _ => f32::from_le_bytes( values.word()? )
+                    };
+                    let pixel = &mut pixels[ ( y * width + x ) as usize ];
+                    match channel.component {
+                        Some( 4 ) => { pixel.x = value; pixel.y = value; pixel.z = value },
+                        Some( component ) => pixel[ component ] = value,
+                        None => {}
+                    }
+                }
+            }
+        }
+    }
+    image.set_tile( 0, 0, width, height, &pixels );
+    Ok( image )
+}
+
+pub fn load<P: AsRef<Path>>( path: P ) -> Result<Framebuffer, ImageError> {
+    read( &mut BufReader::new( File::open( path )? ) )
+}
+
+pub fn save<P: AsRef<Path>>( image: &Framebuffer, path: P, settings: &ExrSettings ) -> Result<(), ImageError> {
+    let mut writer = BufWriter::new( File::create( path )? );
+    write( image, &mut writer, settings )?;
+    writer.flush()?;
+    Ok( () )
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec4;
+    use super::{f16_to_f32, f32_to_f16, read, write, Compression, ExrSettings, Precision};
+    use crate::image::{Framebuffer, ImageError};
+
+    #[test]
+    fn half_floats() {
+        // Zero, normal numbers, the largest half, the smallest normal and subnormal ones
+        for ( value, half ) in [ ( 0., 0 ), ( 1., 0x3c00 ), ( -2., 0xc000 ), ( 65504., 0x7bff ), ( 6.1035156e-5, 0x0400 ), ( 5.9604645e-8, 0x0001 ) ] {
+            assert_eq!( f32_to_f16( value ), half, "{}", value );
+            assert_eq!( f16_to_f32( half ), value );
+        }
+        assert_eq!( f32_to_f16( 1e6 ), 0x7c00 );
+        assert_eq!( f16_to_f32( 0x7c00 ), f32::INFINITY );
+        // Rounds to nearest, ties to even
+        assert_eq!( f32_to_f16( 1. + 1. / 2048. ), 0x3c00 );
+        assert_eq!( f32_to_f16( 1. + 3. / 2048. ), 0x3c02 );
+        assert_eq!( f32_to_f16( 2e-8 ), 0 );
+        assert!( f16_to_f32( f32_to_f16( f32::NAN ) ).is_nan() );
+        for value in [ 0.1, 3.3, 1000.5, 1e-4 ] {
+            assert!( ( f16_to_f32( f32_to_f16( value ) ) - value ).abs() <= value * 1e-3, "{}", value );
+        }
+    }
+
+    fn gradient() -> Framebuffer {
+        let mut image = Framebuffer::new( 37, 21 );
+        for y in 0..21 {
+            for x in 0..37 {
+                image.set_pixel( x, y, Vec4::new( x as f32 / 4., y as f32 * 100., 0.01 * ( x * y ) as f32, 0.5 ) );
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn round_trip() {
+        let image = gradient();
+        for compression in [ Compression::None, Compression::Zip ] {
+            let settings = ExrSettings { precision: Precision::Float, compression, alpha: true };
+            let mut data = vec![];
+            write( &image, &mut data, &settings ).unwrap();
+            assert_eq!( read( &mut data.as_slice() ).unwrap(), image, "{:?}", compression );
+
+            // Half precision keeps three decimal digits, without alpha the image is opaque
+            let settings = ExrSettings { precision: Precision::Half, alpha: false, ..settings };
+            let mut data = vec![];
+            write( &image, &mut data, &settings ).unwrap();
+            let half = read( &mut data.as_slice() ).unwrap();
+            for ( x, y ) in [ ( 0, 0 ), ( 36, 20 ), ( 13, 7 ) ] {
+                let ( expected, value ) = ( image.get_pixel( x, y ).truncate().extend( 1. ), half.get_pixel( x, y ) );
+                assert!( ( value - expected ).abs().max_element() <= expected.abs().max_element() * 1e-3, "{} {}", value, expected );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        let mut data = vec![];
+        write( &gradient(), &mut data, &ExrSettings::default() ).unwrap();
+        assert!( matches!( read( &mut &data[ ..data.len() - 10 ] ), Err( ImageError::Format( _ ) ) ) );
+        data[ 0 ] = 0;
+        assert!( matches!( read( &mut data.as_slice() ), Err( ImageError::Format( _ ) ) ) );
+
+        // Huge data windows are rejected before anything is allocated for them
+        for window in [ [ i32::MIN, 0, i32::MAX, 0 ], [ 0, 0, 99_999, 99_999 ], [ 0, 0, 0, i32::MAX ] ] {
+            let mut data = vec![];
+            write( &gradient(), &mut data, &ExrSettings::default() ).unwrap();
+            let at = data.windows( 17 ).position( | w | w == b"dataWindow\0box2i\0" ).unwrap() + 17 + 4;
+            for ( i, value ) in window.iter().enumerate() {
+                data[ at + i * 4..at + i * 4 + 4 ].copy_from_slice( &value.to_le_bytes() );
+            }
+            assert!( matches!( read( &mut data.as_slice() ), Err( ImageError::Format( _ ) ) ), "{:?}", window );
+        }
+
+        // Channels that are all skipped leave nothing to read
+        let mut data = vec![];
+        write( &gradient(), &mut data, &ExrSettings::default() ).unwrap();
+        let mut at = data.windows( 16 ).position( | w | w == b"channels\0chlist\0" ).unwrap() + 16 + 4;
+        while data[ at ] != 0 {
+            data[ at ] = b'Z';
+            at += 2 + 16;
+        }
+        assert!( matches!( read( &mut data.as_slice() ), Err( ImageError::Format( _ ) ) ) );
+    }
+}
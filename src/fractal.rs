@@ -0,0 +1,395 @@
+// Fractals given by distance estimators, which get less precise close to the surface. Some of them overestimate far
+// away, so outside of their bounds they are only evaluated on the bounds. Every fractal also reports an orbit trap,
+// a value taken from the orbit of a point under the fractal's iteration that is commonly used to color them.
+// http://blog.hvidtfeldts.net/index.php/2011/06/distance-estimated-3d-fractals-part-i/
+
+use std::sync::Arc;
+use glam::{Vec3, Vec4};
+use crate::bvh::Aabb;
+use crate::domain::bounded_distance;
+use crate::material::Material;
+use crate::rays::Hittable;
+
+// Orbits that leave this radius are known to escape
+const BAILOUT: f32 = 2.;
+
+// The 3D Mandelbrot set of z^power + c, with the power taken in spherical coordinates. Power 8 gives the well known
+// bulb. Its orbit trap is the smallest distance of the orbit from the origin.
+pub struct Mandelbulb {
+    pub position: Vec3,
+    pub power: f32,
+    pub iterations: u32,
+    pub material: Arc<Material>
+}
+
+impl Mandelbulb {
+    fn estimate( &self, pos: Vec3 ) -> ( f32, f32 ) {
+        let c = pos - self.position;
+        let mut z = c;
+        let mut dr = 1.;
+        let mut r = z.length();
+        let mut trap = r;
+        for _ in 0..self.iterations {
+            if r > BAILOUT {
+                break;
+            }
+            // Raise to the power in spherical coordinates around the y axis
+            if r > 0. {
+                let theta = ( z.y / r ).clamp( -1., 1. ).acos() * self.power;
+                let phi = z.z.atan2( z.x ) * self.power;
+                dr = r.powf( self.power - 1. ) * self.power * dr + 1.;
+                z = r.powf( self.power ) * Vec3::new( theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin() );
+            }
+            z += c;
+            r = z.length();
+            trap = trap.min( r );
+        }
+        if r == 0. {
+            return ( 0., trap );
+        }
+        ( 0.5 * r.ln() * r / dr, trap )
+    }
+}
+
+impl Hittable for Mandelbulb {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        bounded_distance( &self.bounds(), pos, | p | self.estimate( p ).0 )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        Aabb::new( self.position - BAILOUT, self.position + BAILOUT )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        Some( self.estimate( pos ).1 )
+    }
+}
+
+// Folds space into a box and a sphere and scales it up again every iteration. Scales below -1 give a cube of size 4,
+// scales above 1 grow with 4 (scale + 1) / (scale - 1). The orbit trap is the smallest distance of the orbit from the
+// origin.
+// https://sites.google.com/site/mandelbox/what-is-a-mandelbox
+pub struct Mandelbox {
+    pub position: Vec3,
+    pub scale: f32,
+    pub iterations: u32,
+    pub material: Arc<Material>
+}
+
+impl Mandelbox {
+    // Radii of the sphere fold, points inside the inner one are scaled up the most
+    const MIN_RADIUS2: f32 = 0.25;
+    const FIXED_RADIUS2: f32 = 1.;
+
+    fn estimate( &self, pos: Vec3 ) -> ( f32, f32 ) {
+        let c = pos - self.position;
+        let mut z = c;
+        let mut dr = 1.;
+        let mut trap = z.length();
+        for _ in 0..self.iterations {
+            z = z.clamp( -Vec3::ONE, Vec3::ONE ) * 2. - z;
+            let r2 = z.length_squared();
+            let factor = if r2 < Self::MIN_RADIUS2 {
+                Self::FIXED_RADIUS2 / Self::MIN_RADIUS2
+            } else if r2 < Self::FIXED_RADIUS2 {
+                Self::FIXED_RADIUS2 / r2
+            } else {
+                1.
+            };
+            z = z * factor * self.scale + c;
+            dr = dr * factor * self.scale.abs() + 1.;
+            trap = trap.min( z.length() );
+            if z.length_squared() > 1e8 {
+                break;
+            }
+        }
+        ( z.length() / dr, trap )
+    }
+}
+
+impl Hittable for Mandelbox {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        bounded_distance( &self.bounds(), pos, | p | self.estimate( p ).0 )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let half_size = if self.scale > 1. {
+            2. * ( self.scale + 1. ) / ( self.scale - 1. )
+        } else if self.scale < -1. {
+            2.
+        } else {
+            return Aabb::INFINITE;
+        };
+        Aabb::new( self.position - half_size, self.position + half_size )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        Some( self.estimate( pos ).1 )
+    }
+}
+
+// A cube with the middle ninth of every face removed through to the other side, over and over. The distance is exact
+// for the removed crosses. The orbit trap is the level of detail of the nearest surface, from 0 for the faces of the
+// cube up to 1 for the smallest holes.
+// https://iquilezles.org/articles/menger/
+pub struct MengerSponge {
+    pub position: Vec3,
+    // Half the edge length of the cube
+    pub size: f32,
+    pub iterations: u32,
+    pub material: Arc<Material>
+}
+
+impl MengerSponge {
+    fn estimate( &self, pos: Vec3 ) -> ( f32, f32 ) {
+        let p = ( pos - self.position ) / self.size;
+        let q = p.abs() - 1.;
+        let mut d = q.max( Vec3::ZERO ).length() + q.max_element().min( 0. );
+        let mut trap = 0.;
+        let mut scale: f32 = 1.;
+        for i in 0..self.iterations {
+            // Position inside the cell at this level, in [-1, 1]
+            let a = ( p * scale ).to_array().map( | x: f32 | x.rem_euclid( 2. ) - 1. );
+            scale *= 3.;
+            let r = ( 1. - 3. * Vec3::from_array( a ).abs() ).abs();
+            let cross = ( r.x.max( r.y ).min( r.y.max( r.z ) ).min( r.z.max( r.x ) ) - 1. ) / scale;
+            if cross > d {
+                d = cross;
+                trap = ( i + 1 ) as f32 / self.iterations as f32;
+            }
+        }
+        ( d * self.size, trap )
+    }
+}
+
+impl Hittable for MengerSponge {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        self.estimate( pos ).0
+    }
+
+    fn bounds( &self ) -> Aabb {
+        Aabb::new( self.position - self.size, self.position + self.size )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        Some( self.estimate( pos ).1 )
+    }
+}
+
+// A tetrahedron split into four half size copies at its corners, over and over. Built by folding space along the
+// tetrahedron's planes of symmetry. The orbit trap is the smallest distance of the orbit from the origin, in units of
+// the size.
+pub struct SierpinskiTetrahedron {
+    pub position: Vec3,
+    // Half the edge length of the cube the tetrahedron's corners sit on
+    pub size: f32,
+    pub iterations: u32,
+    pub material: Arc<Material>
+}
+
+impl SierpinskiTetrahedron {
+    fn estimate( &self, pos: Vec3 ) -> ( f32, f32 ) {
+        let mut z = ( pos - self.position ) / self.size;
+        let mut trap = z.length();
+        let mut scale = 1.;
+        for _ in 0..self.iterations {
+            // Mirror into the part of space nearest to the corner at [ 1, 1, 1 ] and grow from that corner
+            if z.x + z.y < 0. {
+                z = Vec3::new( -z.y, -z.x, z.z );
+            }
+            if z.x + z.z < 0. {
+                z = Vec3::new( -z.z, z.y, -z.x );
+            }
+            if z.y + z.z < 0. {
+                z = Vec3::new( z.x, -z.z, -z.y );
+            }
+            z = z * 2. - Vec3::ONE;
+            scale *= 0.5;
+            trap = trap.min( z.length() );
+        }
+        // Distance to the tetrahedron with corners [ 1, 1, 1 ], [ 1, -1, -1 ], [ -1, 1, -1 ] and [ -1, -1, 1 ]
+        let tetrahedron = f32::max(
+            f32::max( -z.x - z.y - z.z, z.x + z.y - z.z ),
+            f32::max( -z.x + z.y + z.z, z.x - z.y + z.z )
+        ) - 1.;
+        ( tetrahedron / 3f32.sqrt() * scale * self.size, trap )
+    }
+}
+
+impl Hittable for SierpinskiTetrahedron {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        self.estimate( pos ).0
+    }
+
+    fn bounds( &self ) -> Aabb {
+        Aabb::new( self.position - self.size, self.position + self.size )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        Some( self.estimate( pos ).1 )
+    }
+}
+
+// The 3D slice w = 0 of the Julia set of z^2 + c over the quaternions. The orbit trap is the smallest squared length
+// of the orbit.
+// https://iquilezles.org/articles/juliasets3d/
+pub struct QuaternionJulia {
+    pub position: Vec3,
+    pub c: Vec4,
+    pub iterations: u32,
+    pub material: Arc<Material>
+}
+
+impl QuaternionJulia {
+    fn estimate( &self, pos: Vec3 ) -> ( f32, f32 ) {
+        let p = pos - self.position;
+        let mut z = Vec4::new( p.x, p.y, p.z, 0. );
+        // Squared lengths of z and of its derivative
+        let mut length2 = z.length_squared();
+        let mut derivative2 = 1.;
+        let mut trap = length2;
+        for _ in 0..self.iterations {
+            derivative2 *= 4. * length2;
+            z = square( z ) + self.c;
+            length2 = z.length_squared();
+            trap = trap.min( length2 );
+            if length2 > BAILOUT * BAILOUT * 4. {
+                break;
+            }
+        }
+        if length2 == 0. {
+            return ( 0., trap );
+        }
+        ( 0.25 * length2.ln() * ( length2 / derivative2 ).sqrt(), trap )
+    }
+}
+
+// Square of the quaternion x + yi + zj + wk
+fn square( q: Vec4 ) -> Vec4 {
+    Vec4::new( q.x * q.x - q.y * q.y - q.z * q.z - q.w * q.w, 2. * q.x * q.y, 2. * q.x * q.z, 2. * q.x * q.w )
+}
+
+impl Hittable for QuaternionJulia {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        bounded_distance( &self.bounds(), pos, | p | self.estimate( p ).0 )
+    }
+
+    // The set lies within the larger of 2 and |c| around its center
+    fn bounds( &self ) -> Aabb {
+        let radius = f32::max( BAILOUT, self.c.length() );
+        Aabb::new( self.position - radius, self.position + radius )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        Some( self.estimate( pos ).1 )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use glam::{Vec3, Vec4};
+    use super::{Mandelbox, Mandelbulb, MengerSponge, QuaternionJulia, SierpinskiTetrahedron};
+    use crate::material::Material;
+    use crate::rays::Hittable;
+
+    fn material() -> Arc<Material> {
+        Arc::new( Material::lambertian( Vec3::ONE ) )
+    }
+
+    // Marching along a ray from outside with the estimated distances must not pass the first point found to be inside
+    // by fine stepping
+    fn assert_safe( shape: &dyn Hittable, origin: Vec3, direction: Vec3 ) {
+        let first_inside = ( 0..4000 ).map( | i | i as f32 * 0.002 ).find( | &t | shape.distance( origin + direction * t ) < 0. );
+        let mut t = 0.;
+        for _ in 0..1000 {
+            let d = shape.distance( origin + direction * t );
+            if d < 1e-4 {
+                break;
+            }
+            t += d;
+        }
+        if let Some( inside ) = first_inside {
+            assert!( t <= inside + 0.01, "marched to {} past the surface at {}", t, inside );
+        }
+        assert!( shape.bounds().distance( origin + direction * t ) <= 1e-3 );
+    }
+
+    #[test]
+    fn mandelbulb() {
+        let bulb = Mandelbulb { position: Vec3::new( 1., 0., 0. ), power: 8., iterations: 10, material: material() };
+        assert_eq!( bulb.distance( Vec3::new( 1., 0., 0. ) ), 0. );
+        assert!( bulb.distance( Vec3::new( 1.1, 0.05, 0. ) ) < 0. );
+        assert!( bulb.distance( Vec3::new( 1., 3., 0. ) ) > 1. );
+        assert_safe( &bulb, Vec3::new( 1., 0.3, -3. ), Vec3::Z );
+        assert_safe( &bulb, Vec3::new( 4., 0.5, 0.2 ), Vec3::NEG_X );
+        let ( inner, outer ) = ( bulb.orbit_trap( Vec3::new( 1.1, 0., 0. ) ).unwrap(), bulb.orbit_trap( Vec3::new( 1., 2., 0. ) ).unwrap() );
+        assert!( inner < outer, "{} {}", inner, outer );
+    }
+
+    #[test]
+    fn mandelbox() {
+        let mandelbox = Mandelbox { position: Vec3::ZERO, scale: -1.5, iterations: 12, material: material() };
+        assert!( mandelbox.distance( Vec3::new( 0., 0., 5. ) ) > 1. );
+        assert_safe( &mandelbox, Vec3::new( 0.1, 0.2, -5. ), Vec3::Z );
+        assert_safe( &mandelbox, Vec3::new( -5., 1.3, 0.7 ), Vec3::X );
+        assert_eq!( Mandelbox { scale: 2., ..mandelbox }.bounds().max, Vec3::splat( 6. ) );
+    }
+
+    #[test]
+    fn menger_sponge() {
+        let sponge = MengerSponge { position: Vec3::ZERO, size: 3., iterations: 3, material: material() };
+        // The middle is carved away, and so is the middle of each face
+        assert!( sponge.distance( Vec3::ZERO ) > 0. );
+        assert!( ( sponge.distance( Vec3::new( 0., 0., -4. ) ) - 1. ).abs() < 1e-5 );
+        assert!( sponge.distance( Vec3::new( 2.5, 2.5, 2.5 ) ) < 0. );
+        assert!( ( sponge.distance( Vec3::new( 2., 2., -4. ) ) - 1. ).abs() < 1e-5 );
+        assert_safe( &sponge, Vec3::new( 0.4, 1.3, -5. ), Vec3::Z );
+        assert_eq!( sponge.orbit_trap( Vec3::ZERO ), Some( 1. / 3. ) );
+        assert_eq!( sponge.orbit_trap( Vec3::new( 2.5, 2.5, -3.5 ) ), Some( 0. ) );
+    }
+
+    #[test]
+    fn sierpinski_tetrahedron() {
+        let tetrahedron = SierpinskiTetrahedron { position: Vec3::ZERO, size: 1., iterations: 6, material: material() };
+        // The corners are solid, the center is empty
+        assert!( tetrahedron.distance( Vec3::splat( 0.999 ) ) < 0. );
+        assert!( tetrahedron.distance( Vec3::ZERO ) > 0. );
+        assert!( tetrahedron.distance( Vec3::new( -1., -1., -1. ) ) > 1. );
+        assert_safe( &tetrahedron, Vec3::new( 0.3, 0.2, -3. ), Vec3::Z );
+        assert_safe( &tetrahedron, Vec3::new( 3., 3., 3. ), Vec3::NEG_ONE.normalize() );
+    }
+
+    #[test]
+    fn quaternion_julia() {
+        let julia = QuaternionJulia { position: Vec3::ZERO, c: Vec4::new( -0.2, 0.6, 0.2, 0.2 ), iterations: 11, material: material() };
+        assert!( julia.distance( Vec3::new( 0., 0., 4. ) ) > 1. );
+        assert_safe( &julia, Vec3::new( 0.1, 0.1, -3. ), Vec3::Z );
+        assert_safe( &julia, Vec3::new( 3., 0.4, 0. ), Vec3::NEG_X );
+        // Both points start at the same distance from the center, only the orbit of the inside one comes closer
+        let ( inside, outside ) = ( Vec3::new( 0., 0.8, 0. ), Vec3::new( 0.8, 0., 0. ) );
+        assert!( julia.distance( inside ) < 0. && julia.distance( outside ) > 0. );
+        let ( inner, outer ) = ( julia.orbit_trap( inside ).unwrap(), julia.orbit_trap( outside ).unwrap() );
+        assert!( inner < outer, "{} {}", inner, outer );
+    }
+}
@@ -0,0 +1,301 @@
+// Shapes stored as a dense grid of signed distances, to cache shapes that are expensive to evaluate and to load
+// distance fields made by other tools.
+//
+// Grid files are little endian binary:
+//   8 bytes    magic "RVKSDF" followed by the version, 0 and 1
+//   3 x u32    number of samples along x, y and z, at least 2 each
+//   3 x f32    position of the first sample, the minimum corner of the grid
+//   f32        spacing between samples
+//   f32 * n    the distances, x varying fastest and z slowest
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use glam::{UVec3, Vec3};
+use crate::bvh::Aabb;
+use crate::domain::bounded_distance;
+use crate::material::Material;
+use crate::rays::Hittable;
+
+const MAGIC: &[u8; 8] = b"RVKSDF\x00\x01";
+
+#[derive( Debug )]
+pub enum GridError {
+    Io( std::io::Error ),
+    Format( String )
+}
+
+impl fmt::Display for GridError {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            GridError::Io( e ) => write!( f, "could not access grid file: {}", e ),
+            GridError::Format( message ) => write!( f, "invalid grid file: {}", message )
+        }
+    }
+}
+
+impl std::error::Error for GridError {
+    fn source( &self ) -> Option<&( dyn std::error::Error + 'static )> {
+        match self {
+            GridError::Io( e ) => Some( e ),
+            GridError::Format( _ ) => None
+        }
+    }
+}
+
+impl From<std::io::Error> for GridError {
+    fn from( e: std::io::Error ) -> Self {
+        GridError::Io( e )
+    }
+}
+
+#[derive( Clone, Copy, Debug, PartialEq, Eq )]
+pub enum Interpolation {
+    Trilinear,
+    // Catmull-Rom splines through 4 x 4 x 4 samples, smoother but it can overshoot between samples
+    Tricubic
+}
+
+#[derive( Clone, Debug, PartialEq )]
+pub struct DistanceGrid {
+    pub origin: Vec3,
+    pub spacing: f32,
+    pub size: UVec3,
+    pub values: Vec<f32>
+}
+
+impl DistanceGrid {
+    // Sample distance at every point of a grid with the given spacing covering bounds, using up to `threads`
+    // threads. None if the grid would have more samples than fit in memory.
+    pub fn bake<F: Fn( Vec3 ) -> f32 + Sync>( distance: F, bounds: Aabb, spacing: f32, threads: usize ) -> Option<DistanceGrid> {
+        let size = ( ( bounds.size() / spacing ).ceil() + 1. ).as_uvec3();
+        let size = size.max( UVec3::splat( 2 ) );
+        let slice_length = ( size.x as usize ).checked_mul( size.y as usize )?;
+        let count = slice_length.checked_mul( size.z as usize ).filter( | &count | count <= isize::MAX as usize / 4 )?;
+        let mut grid = DistanceGrid { origin: bounds.min, spacing, size, values: vec![ 0.; count ] };
+
+        // Slices of constant z are handed out to the workers
+        let slices = Mutex::new( grid.values.chunks_mut( slice_length ).enumerate().collect::<Vec<_>>() );
+        let origin = grid.origin;
+        thread::scope( | scope | {
+            for _ in 0..threads.clamp( 1, size.z as usize ) {
+                scope.spawn( || {
+                    loop {
+                        // Pop in its own statement, so the lock is released before the slice is sampled
+                        let next = slices.lock().unwrap().pop();
+                        let Some( ( z, slice ) ) = next else { break };
+                        for ( i, value ) in slice.iter_mut().enumerate() {
+                            let cell = Vec3::new( ( i % size.x as usize ) as f32, ( i / size.x as usize ) as f32, z as f32 );
+                            *value = distance( origin + cell * spacing );
+                        }
+                    }
+                } );
+            }
+        } );
+        Some( grid )
+    }
+
+    pub fn bounds( &self ) -> Aabb {
+        Aabb::new( self.origin, self.origin + ( self.size - 1 ).as_vec3() * self.spacing )
+    }
+
+    fn value( &self, x: i32, y: i32, z: i32 ) -> f32 {
+        let max = self.size.as_ivec3() - 1;
+        let ( x, y, z ) = ( x.clamp( 0, max.x ) as u32, y.clamp( 0, max.y ) as u32, z.clamp( 0, max.z ) as u32 );
+        self.values[ x as usize + self.size.x as usize * ( y as usize + self.size.y as usize * z as usize ) ]
+    }
+
+    // The interpolated distance at pos, which is clamped to the grid
+    pub fn sample( &self, pos: Vec3, interpolation: Interpolation ) -> f32 {
+        let max = ( self.size - 1 ).as_vec3();
+        let cell = ( ( pos - self.origin ) / self.spacing ).clamp( Vec3::ZERO, max );
+        let base = cell.floor().min( max - 1. );
+        let t = cell - base;
+        let ( x, y, z ) = ( base.x as i32, base.y as i32, base.z as i32 );
+        match interpolation {
+            Interpolation::Trilinear => {
+                let lerp = | a: f32, b: f32, t: f32 | a + ( b - a ) * t;
+                let row = | dy: i32, dz: i32 | lerp( self.value( x, y + dy, z + dz ), self.value( x + 1, y + dy, z + dz ), t.x );
+                lerp( lerp( row( 0, 0 ), row( 1, 0 ), t.y ), lerp( row( 0, 1 ), row( 1, 1 ), t.y ), t.z )
+            },
+            Interpolation::Tricubic => {
+                let ( wx, wy, wz ) = ( catmull_rom( t.x ), catmull_rom( t.y ), catmull_rom( t.z ) );
+                let mut sum = 0.;
+                for ( k, wz ) in wz.iter().enumerate() {
+                    for ( j, wy ) in wy.iter().enumerate() {
+                        let row: f32 = wx.iter().enumerate()
+                            .map( | ( i, wx ) | wx * self.value( x + i as i32 - 1, y + j as i32 - 1, z + k as i32 - 1 ) )
+                            .sum();
+                        sum += wz * wy * row;
+                    }
+                }
+                sum
+            }
+        }
+    }
+
+    pub fn read<R: Read>( reader: &mut R ) -> Result<DistanceGrid, GridError> {
+        let mut magic = [ 0u8; 8 ];
+        reader.read_exact( &mut magic )?;
+        if &magic[ ..6 ] != b"RVKSDF" {
+            return Err( GridError::Format( "not a grid file".to_string() ) );
+        }
+        if &magic != MAGIC {
+            return Err( GridError::Format( format!( "unsupported version {}.{}", magic[ 6 ], magic[ 7 ] ) ) );
+        }
+
+        let mut word = || -> Result<[u8; 4], GridError> {
+            let mut bytes = [ 0u8; 4 ];
+            reader.read_exact( &mut bytes )?;
+            Ok( bytes )
+        };
+        let size = UVec3::new( u32::from_le_bytes( word()? ), u32::from_le_bytes( word()? ), u32::from_le_bytes( word()? ) );
+        let origin = Vec3::new( f32::from_le_bytes( word()? ), f32::from_le_bytes( word()? ), f32::from_le_bytes( word()? ) );
+        let spacing = f32::from_le_bytes( word()? );
+        if size.min_element() < 2 {
+            return Err( GridError::Format( "a grid needs at least 2 samples along every axis".to_string() ) );
+        }
+        if !spacing.is_finite() || spacing <= 0. || !origin.is_finite() {
+            return Err( GridError::Format( "invalid origin or spacing".to_string() ) );
+        }
+        // The byte count of the samples has to fit in memory
+        let count = ( size.x as usize ).checked_mul( size.y as usize ).and_then( | n | n.checked_mul( size.z as usize ) );
+        let Some( count ) = count.filter( | &n | n <= usize::MAX / 4 ) else {
+            return Err( GridError::Format( format!( "too many samples, {} x {} x {}", size.x, size.y, size.z ) ) );
+        };
+
+        let mut bytes = vec![];
+        reader.read_to_end( &mut bytes )?;
+        if bytes.len() != count * 4 {
+            return Err( GridError::Format( format!( "expected {} distances, found {} bytes", count, bytes.len() ) ) );
+        }
+        let values = bytes.chunks_exact( 4 ).map( | b | f32::from_le_bytes( [ b[ 0 ], b[ 1 ], b[ 2 ], b[ 3 ] ] ) ).collect();
+        Ok( DistanceGrid { origin, spacing, size, values } )
+    }
+
+    pub fn write<W: Write>( &self, writer: &mut W ) -> Result<(), GridError> {
+        writer.write_all( MAGIC )?;
+        for value in self.size.to_array() {
+            writer.write_all( &value.to_le_bytes() )?;
+        }
+        for value in self.origin.to_array().into_iter().chain( [ self.spacing ] ).chain( self.values.iter().copied() ) {
+            writer.write_all( &value.to_le_bytes() )?;
+        }
+        Ok( () )
+    }
+
+    pub fn load<P: AsRef<Path>>( path: P ) -> Result<DistanceGrid, GridError> {
+        DistanceGrid::read( &mut BufReader::new( File::open( path )? ) )
+    }
+
+    pub fn save<P: AsRef<Path>>( &self, path: P ) -> Result<(), GridError> {
+        let mut writer = BufWriter::new( File::create( path )? );
+        self.write( &mut writer )?;
+        writer.flush()?;
+        Ok( () )
+    }
+}
+
+// Weights of the 4 samples around a point at t between the middle two
+fn catmull_rom( t: f32 ) -> [f32; 4] {
+    let ( t2, t3 ) = ( t * t, t * t * t );
+    [
+        0.5 * ( -t3 + 2. * t2 - t ),
+        0.5 * ( 3. * t3 - 5. * t2 + 2. ),
+        0.5 * ( -3. * t3 + 4. * t2 + t ),
+        0.5 * ( t3 - t2 )
+    ]
+}
+
+// A shape given by a distance grid. Between samples the distance is only as accurate as the interpolation, and
+// outside of the grid it is estimated from the distance to the grid and the values on its border.
+pub struct GridShape {
+    pub grid: Arc<DistanceGrid>,
+    pub interpolation: Interpolation,
+    pub material: Arc<Material>
+}
+
+impl Hittable for GridShape {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        bounded_distance( &self.grid.bounds(), pos, | p | self.grid.sample( p, self.interpolation ) )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        self.grid.bounds()
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use glam::{UVec3, Vec3};
+    use super::{DistanceGrid, GridError, GridShape, Interpolation};
+    use crate::bvh::Aabb;
+    use crate::material::Material;
+    use crate::rays::{Hittable, Sphere};
+
+    fn sphere_grid() -> DistanceGrid {
+        let sphere = Sphere { position: Vec3::ZERO, radius: 1., material: Arc::new( Material::lambertian( Vec3::ONE ) ) };
+        DistanceGrid::bake( | p | sphere.distance( p ), Aabb::new( Vec3::splat( -1.5 ), Vec3::splat( 1.5 ) ), 0.1, 2 ).unwrap()
+    }
+
+    #[test]
+    fn bake_and_sample() {
+        let grid = sphere_grid();
+        assert_eq!( grid.size, UVec3::splat( 31 ) );
+        // Exact at the samples, close in between
+        assert!( ( grid.sample( Vec3::new( 0.5, 0., 0. ), Interpolation::Trilinear ) + 0.5 ).abs() < 1e-5 );
+        for interpolation in [ Interpolation::Trilinear, Interpolation::Tricubic ] {
+            for pos in [ Vec3::new( 0.33, 0.71, -0.12 ), Vec3::new( -1.04, 0.27, 0.5 ) ] {
+                let error = grid.sample( pos, interpolation ) - ( pos.length() - 1. );
+                assert!( error.abs() < 0.01, "{:?} at {}: {}", interpolation, pos, error );
+            }
+        }
+
+        // Outside the grid the distance still grows and stays below the true one
+        let shape = GridShape { grid: Arc::new( grid ), interpolation: Interpolation::Trilinear, material: Arc::new( Material::lambertian( Vec3::ONE ) ) };
+        let far = shape.distance( Vec3::new( 4., 0., 0. ) );
+        assert!( far > 2.5 && far <= 3. + 1e-5, "{}", far );
+    }
+
+    // Workers sample their slices at the same time instead of one after the other
+    #[test]
+    fn bake_in_parallel() {
+        let ( active, most ) = ( AtomicUsize::new( 0 ), AtomicUsize::new( 0 ) );
+        DistanceGrid::bake( | p | {
+            most.fetch_max( active.fetch_add( 1, Ordering::SeqCst ) + 1, Ordering::SeqCst );
+            std::thread::sleep( std::time::Duration::from_millis( 1 ) );
+            active.fetch_sub( 1, Ordering::SeqCst );
+            p.length()
+        }, Aabb::new( Vec3::ZERO, Vec3::ONE ), 0.5, 3 ).unwrap();
+        assert!( most.load( Ordering::SeqCst ) > 1 );
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let grid = sphere_grid();
+        let mut data = vec![];
+        grid.write( &mut data ).unwrap();
+        assert_eq!( data.len(), 8 + 7 * 4 + 31 * 31 * 31 * 4 );
+        assert_eq!( DistanceGrid::read( &mut data.as_slice() ).unwrap(), grid );
+
+        assert!( matches!( DistanceGrid::read( &mut &data[ ..data.len() - 4 ] ), Err( GridError::Format( _ ) ) ) );
+        data[ 7 ] = 9;
+        assert!( matches!( DistanceGrid::read( &mut data.as_slice() ), Err( GridError::Format( _ ) ) ) );
+
+        // Sizes whose sample count overflows are rejected before reading the samples
+        let mut data = vec![];
+        grid.write( &mut data ).unwrap();
+        data[ 8..20 ].fill( 0xff );
+        assert!( matches!( DistanceGrid::read( &mut data.as_slice() ), Err( GridError::Format( _ ) ) ) );
+        assert!( DistanceGrid::bake( | p | p.length(), Aabb::new( Vec3::ZERO, Vec3::splat( 1e6 ) ), 1e-3, 1 ).is_none() );
+    }
+}
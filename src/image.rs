@@ -1,7 +1,40 @@
+use std::fmt;
 use std::fs::File;
 use std::path::Path;
-use std::io::BufWriter;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::ops::{AddAssign, DivAssign};
+use glam::{Vec3, Vec4};
+use crate::color::{ColorSpace, ColorTransform};
+
+#[derive( Debug )]
+pub enum ImageError {
+    Io( std::io::Error ),
+    Format( String )
+}
+
+impl fmt::Display for ImageError {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            ImageError::Io( e ) => write!( f, "could not access image file: {}", e ),
+            ImageError::Format( message ) => write!( f, "invalid image file: {}", message )
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source( &self ) -> Option<&( dyn std::error::Error + 'static )> {
+        match self {
+            ImageError::Io( e ) => Some( e ),
+            ImageError::Format( _ ) => None
+        }
+    }
+}
+
+impl From<std::io::Error> for ImageError {
+    fn from( e: std::io::Error ) -> Self {
+        ImageError::Io( e )
+    }
+}
 
 #[derive( Clone, Copy )]
 pub struct Color( pub u32, pub u32, pub u32 );
@@ -30,11 +63,11 @@ impl DivAssign for Color {
 
 impl ColorSink {
     pub fn get_width(&self) -> u32 {
-        return self.width;
+        self.width
     }
 
     pub fn get_height(&self) -> u32 {
-        return self.height;
+        self.height
     }
 }
 
@@ -74,57 +107,462 @@ impl ColorSink {
         self.data[start as usize..end as usize].clone_from_slice(&data);
     }
 
+    // Copy a block of `width` x `height` pixels in row order to (x, y)
+    pub fn set_tile(&mut self, x: u32, y: u32, width: u32, height: u32, data: &[Color]) {
+        if x + width > self.width || y + height > self.height || data.len() != (width * height) as usize {
+            panic!("Tile out of bounds.");
+        }
+
+        for row in 0..height {
+            let start = ((y + row) * self.width + x) as usize;
+            let src = (row * width) as usize;
+            self.data[start..start + width as usize].copy_from_slice(&data[src..src + width as usize]);
+        }
+    }
+
     pub fn get_data(&self) -> Box<[Color]> {
         self.data.clone()
     }
 }
 
-pub fn write_png_image( in_data: ColorSink, path: &str ) {
-    let path = Path::new(path);
-    let file = File::create(path).unwrap();
-    let ref mut w = BufWriter::new(file);
+// Linear floating point RGBA pixels that samples are accumulated into. Every pixel keeps the weighted sum of its
+// samples and the sum of their weights, so an image can be built up over several passes and is only averaged when it
+// is read. Tone mapping and quantization to 8 bits happen in `to_color_sink`, when the image is exported.
+#[derive( Clone, Debug, PartialEq )]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    sums: Box<[Vec4]>,
+    weights: Box<[f32]>
+}
+
+impl Framebuffer {
+    pub fn new( width: u32, height: u32 ) -> Self {
+        if width == 0 || height == 0 {
+            panic!( "Width and height must be greater than 0." );
+        }
+
+        Self::try_new( width, height ).expect( "Image is too large." )
+    }
+
+    // Like `new`, but returns None for an empty image or one too large to address, for sizes read from files
+    pub fn try_new( width: u32, height: u32 ) -> Option<Self> {
+        if width == 0 || height == 0 {
+            return None;
+        }
 
-    let mut encoder = png::Encoder::new(w, in_data.width, in_data.height ); // Width is 2 pixels and height is 1.
-    encoder.set_color(png::ColorType::Rgb);
-    encoder.set_depth(png::BitDepth::Eight);
+        let count = ( width as usize ).checked_mul( height as usize )?;
+        if count > isize::MAX as usize / std::mem::size_of::<Vec4>() {
+            return None;
+        }
+        Some( Self { width, height, sums: vec![ Vec4::ZERO; count ].into_boxed_slice(), weights: vec![ 0.; count ].into_boxed_slice() } )
+    }
+
+    pub fn get_width( &self ) -> u32 {
+        self.width
+    }
 
-    let mut im_data = vec![ 0; (in_data.width * in_data.height * 3) as usize ].into_boxed_slice();
-    for i in 0..(in_data.width * in_data.height) as usize {
-        im_data[i * 3 + 0] = in_data.data[i].0 as u8;
-        im_data[i * 3 + 1] = in_data.data[i].1 as u8;
-        im_data[i * 3 + 2] = in_data.data[i].2 as u8;
+    pub fn get_height( &self ) -> u32 {
+        self.height
     }
 
-    let mut writer = encoder.write_header().unwrap();
-    writer.write_image_data( &im_data ).unwrap(); // Save
+    fn index( &self, x: u32, y: u32 ) -> usize {
+        if x >= self.width || y >= self.height {
+            panic!( "Pixel out of bounds." );
+        }
+
+        y as usize * self.width as usize + x as usize
+    }
+
+    // Add a sample with the given weight to pixel (x, y)
+    pub fn add_sample( &mut self, x: u32, y: u32, color: Vec4, weight: f32 ) {
+        let i = self.index( x, y );
+        self.sums[ i ] += color * weight;
+        self.weights[ i ] += weight;
+    }
+
+    // Add a sample at a position in pixels to the 4 pixels with the nearest centers using a tent filter of one pixel
+    // radius. As in the renderer, pixel (x, y) is centered on (x, y), so the samples at x + dx, y + dy for the offsets
+    // of `render::sample_offsets` are spread around that pixel. The parts that fall outside of the image are lost.
+    pub fn splat( &mut self, x: f32, y: f32, color: Vec4, weight: f32 ) {
+        let ( left, top ) = ( x.floor(), y.floor() );
+        let ( tx, ty ) = ( x - left, y - top );
+        for ( dx, wx ) in [ ( 0, 1. - tx ), ( 1, tx ) ] {
+            for ( dy, wy ) in [ ( 0, 1. - ty ), ( 1, ty ) ] {
+                let ( px, py ) = ( left as i64 + dx, top as i64 + dy );
+                if px >= 0 && py >= 0 && px < self.width as i64 && py < self.height as i64 && wx * wy > 0. {
+                    self.add_sample( px as u32, py as u32, color, weight * wx * wy );
+                }
+            }
+        }
+    }
+
+    // The weighted average of the samples of pixel (x, y), zero if it has none
+    pub fn get_pixel( &self, x: u32, y: u32 ) -> Vec4 {
+        let i = self.index( x, y );
+        if self.weights[ i ] > 0. { self.sums[ i ] / self.weights[ i ] } else { Vec4::ZERO }
+    }
+
+    pub fn get_weight( &self, x: u32, y: u32 ) -> f32 {
+        self.weights[ self.index( x, y ) ]
+    }
+
+    // Replace the samples of pixel (x, y) by a single one
+    pub fn set_pixel( &mut self, x: u32, y: u32, color: Vec4 ) {
+        let i = self.index( x, y );
+        self.sums[ i ] = color;
+        self.weights[ i ] = 1.;
+    }
+
+    // Replace the pixels of a block of `width` x `height` pixels at (x, y) by the colors in row order
+    pub fn set_tile( &mut self, x: u32, y: u32, width: u32, height: u32, data: &[Vec4] ) {
+        if x as u64 + width as u64 > self.width as u64 || y as u64 + height as u64 > self.height as u64
+            || data.len() != width as usize * height as usize {
+            panic!( "Tile out of bounds." );
+        }
+
+        for ( i, row ) in data.chunks_exact( width as usize ).enumerate() {
+            let start = ( y as usize + i ) * self.width as usize + x as usize;
+            self.sums[ start..start + width as usize ].copy_from_slice( row );
+            self.weights[ start..start + width as usize ].fill( 1. );
+        }
+    }
+
+    // Add all samples of another pass over the same image
+    pub fn accumulate( &mut self, other: &Framebuffer ) {
+        if self.width != other.width || self.height != other.height {
+            panic!( "Framebuffers must have the same size." );
+        }
+
+        for ( sum, other ) in self.sums.iter_mut().zip( other.sums.iter() ) {
+            *sum += *other;
+        }
+        for ( weight, other ) in self.weights.iter_mut().zip( other.weights.iter() ) {
+            *weight += *other;
+        }
+    }
+
+    // Multiply the color of every pixel by `gain`, leaving alpha as it is
+    pub fn apply_gain( &mut self, gain: f32 ) {
+        for sum in self.sums.iter_mut() {
+            *sum *= Vec4::new( gain, gain, gain, 1. );
+        }
+    }
+
+    // Replace the color of every pixel by f of its average, keeping the weights and alpha
+    pub fn map_colors<F: Fn( Vec3 ) -> Vec3>( &mut self, f: F ) {
+        for ( sum, &weight ) in self.sums.iter_mut().zip( self.weights.iter() ) {
+            if weight > 0. {
+                *sum = ( f( sum.truncate() / weight ) * weight ).extend( sum.w );
+            }
+        }
+    }
+
+    // Average the pixels, map them to display colors and quantize those to 8 bits
+    pub fn to_color_sink( &self, transform: &ColorTransform ) -> ColorSink {
+        let mut cs = ColorSink::new( self.width, self.height );
+        for y in 0..self.height {
+            for x in 0..self.width {
+                cs.set_pixel( x, y, quantize( transform.apply( self.get_pixel( x, y ).truncate() ) ) );
+            }
+        }
+        cs
+    }
+}
+
+// Map a linear color in [0, 1] to the nearest 8-bit value
+pub fn quantize( color: Vec3 ) -> Color {
+    let color = ( color.clamp( Vec3::ZERO, Vec3::ONE ) * 255. ).round();
+    Color( color.x as u32, color.y as u32, color.z as u32 )
+}
+
+#[derive( Clone, Copy, Debug, PartialEq, Eq )]
+pub enum BitDepth {
+    Eight,
+    Sixteen
+}
+
+#[derive( Clone, Debug )]
+pub struct PngSettings {
+    pub bit_depth: BitDepth,
+    // Write RGBA instead of RGB
+    pub alpha: bool,
+    // Text chunks as keyword and text, keywords are 1 to 79 Latin-1 characters
+    pub text: Vec<( String, String )>
+}
+
+impl Default for PngSettings {
+    fn default() -> Self {
+        PngSettings { bit_depth: BitDepth::Eight, alpha: false, text: vec![] }
+    }
+}
+
+// A decoded PNG, with the colors as they are stored in the file mapped to [0, 1], so usually not linear
+pub struct PngImage {
+    pub image: Framebuffer,
+    pub text: Vec<( String, String )>
+}
+
+impl From<png::DecodingError> for ImageError {
+    fn from( e: png::DecodingError ) -> Self {
+        match e {
+            png::DecodingError::IoError( e ) => ImageError::Io( e ),
+            e => ImageError::Format( e.to_string() )
+        }
+    }
+}
+
+impl From<png::EncodingError> for ImageError {
+    fn from( e: png::EncodingError ) -> Self {
+        match e {
+            png::EncodingError::IoError( e ) => ImageError::Io( e ),
+            e => ImageError::Format( e.to_string() )
+        }
+    }
+}
+
+// Encode the linear image with the transform and write it as a PNG, tagged with the color space where PNG can
+// describe it
+pub fn write_png<W: Write>( image: &Framebuffer, transform: &ColorTransform, writer: W, settings: &PngSettings ) -> Result<(), ImageError> {
+    let mut encoder = png::Encoder::new( writer, image.width, image.height );
+    encoder.set_color( if settings.alpha { png::ColorType::Rgba } else { png::ColorType::Rgb } );
+    encoder.set_depth( match settings.bit_depth {
+        BitDepth::Eight => png::BitDepth::Eight,
+        BitDepth::Sixteen => png::BitDepth::Sixteen
+    } );
+    match transform.color_space {
+        ColorSpace::Srgb => encoder.set_srgb( png::SrgbRenderingIntent::Perceptual ),
+        ColorSpace::Linear => encoder.set_source_gamma( png::ScaledFloat::new( 1. ) ),
+        ColorSpace::DisplayP3 => {
+            encoder.set_source_gamma( png::ScaledFloat::new( 1. / 2.2 ) );
+            encoder.set_source_chromaticities( png::SourceChromaticities::new( ( 0.3127, 0.329 ), ( 0.68, 0.32 ), ( 0.265, 0.69 ), ( 0.15, 0.06 ) ) );
+        },
+        ColorSpace::Rec709 => {}
+    }
+    for ( keyword, text ) in &settings.text {
+        // Plain tEXt chunks can only hold Latin-1, iTXt holds any UTF-8
+        if text.is_ascii() {
+            encoder.add_text_chunk( keyword.clone(), text.clone() )?;
+        } else {
+            encoder.add_itxt_chunk( keyword.clone(), text.clone() )?;
+        }
+    }
+
+    let channels = if settings.alpha { 4 } else { 3 };
+    let mut data = Vec::with_capacity( ( image.width * image.height * channels ) as usize * 2 );
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let pixel = image.get_pixel( x, y );
+            let color = transform.apply( pixel.truncate() ).extend( pixel.w.clamp( 0., 1. ) );
+            for &value in &color.to_array()[ ..channels as usize ] {
+                match settings.bit_depth {
+                    BitDepth::Eight => data.push( ( value * 255. ).round() as u8 ),
+                    BitDepth::Sixteen => data.extend_from_slice( &( ( value * 65535. ).round() as u16 ).to_be_bytes() )
+                }
+            }
+        }
+    }
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data( &data )?;
+    writer.finish()?;
+    Ok( () )
+}
+
+// Read a PNG of any color type and bit depth. Palettes and transparency are expanded, gray is spread over the color
+// channels and images without alpha are opaque.
+pub fn read_png<R: Read>( reader: R ) -> Result<PngImage, ImageError> {
+    let mut decoder = png::Decoder::new( reader );
+    decoder.set_transformations( png::Transformations::EXPAND );
+    let mut reader = decoder.read_info()?;
+    let mut data = vec![ 0; reader.output_buffer_size() ];
+    let frame = reader.next_frame( &mut data )?;
+
+    let ( color_type, bit_depth ) = reader.output_color_type();
+    let channels = color_type.samples();
+    let ( size, max ) = match bit_depth {
+        png::BitDepth::Sixteen => ( 2, 65535. ),
+        png::BitDepth::Eight => ( 1, 255. ),
+        depth => return Err( ImageError::Format( format!( "unexpected bit depth {:?} after expansion", depth ) ) )
+    };
+
+    let mut image = Framebuffer::try_new( frame.width, frame.height )
+        .ok_or_else( || ImageError::Format( "image is empty or too large".to_string() ) )?;
+    for y in 0..frame.height {
+        let line = &data[ y as usize * frame.line_size..];
+        for x in 0..frame.width {
+            let value = | channel: usize | {
+                let i = ( x as usize * channels + channel ) * size;
+                let value = if size == 2 { u16::from_be_bytes( [ line[ i ], line[ i + 1 ] ] ) as f32 } else { line[ i ] as f32 };
+                value / max
+            };
+            let pixel = match color_type {
+                png::ColorType::Grayscale => Vec4::new( value( 0 ), value( 0 ), value( 0 ), 1. ),
+                png::ColorType::GrayscaleAlpha => Vec4::new( value( 0 ), value( 0 ), value( 0 ), value( 1 ) ),
+                png::ColorType::Rgb => Vec4::new( value( 0 ), value( 1 ), value( 2 ), 1. ),
+                png::ColorType::Rgba => Vec4::new( value( 0 ), value( 1 ), value( 2 ), value( 3 ) ),
+                png::ColorType::Indexed => return Err( ImageError::Format( "palette was not expanded".to_string() ) )
+            };
+            image.set_pixel( x, y, pixel );
+        }
+    }
+
+    let info = reader.info();
+    let mut text: Vec<( String, String )> = info.uncompressed_latin1_text.iter().map( | chunk | ( chunk.keyword.clone(), chunk.text.clone() ) ).collect();
+    for chunk in &info.compressed_latin1_text {
+        text.push( ( chunk.keyword.clone(), chunk.get_text()? ) );
+    }
+    for chunk in &info.utf8_text {
+        text.push( ( chunk.keyword.clone(), chunk.get_text()? ) );
+    }
+    Ok( PngImage { image, text } )
 }
 
-pub fn read_png_image( path: &str ) -> ColorSink {
-    let path = Path::new(path);
-    let file = File::open(path).unwrap();
-    let decoder = png::Decoder::new(file);
-    let mut reader = decoder.read_info().unwrap();
+pub fn save_png<P: AsRef<Path>>( image: &Framebuffer, transform: &ColorTransform, path: P, settings: &PngSettings ) -> Result<(), ImageError> {
+    let mut writer = BufWriter::new( File::create( path )? );
+    write_png( image, transform, &mut writer, settings )?;
+    writer.flush()?;
+    Ok( () )
+}
+
+pub fn load_png<P: AsRef<Path>>( path: P ) -> Result<PngImage, ImageError> {
+    read_png( BufReader::new( File::open( path )? ) )
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Vec3, Vec4};
+    use super::{quantize, read_png, write_png, BitDepth, Framebuffer, ImageError, PngSettings};
+    use crate::color::{ColorSpace, ColorTransform};
+
+    #[test]
+    fn accumulate_and_average() {
+        let mut fb = Framebuffer::new( 3, 2 );
+        fb.add_sample( 1, 1, Vec4::new( 1., 0., 0., 1. ), 1. );
+        fb.add_sample( 1, 1, Vec4::new( 0., 0.5, 0., 1. ), 3. );
+        assert_eq!( fb.get_pixel( 1, 1 ), Vec4::new( 0.25, 0.375, 0., 1. ) );
+        assert_eq!( fb.get_pixel( 0, 0 ), Vec4::ZERO );
+
+        // A second pass counts as much as its weights
+        let mut pass = Framebuffer::new( 3, 2 );
+        pass.set_pixel( 1, 1, Vec4::new( 4., 0., 0., 4. ) );
+        fb.accumulate( &pass );
+        assert_eq!( fb.get_weight( 1, 1 ), 5. );
+        assert_eq!( fb.get_pixel( 1, 1 ), Vec4::new( 1., 0.3, 0., 1.6 ) );
+    }
+
+    #[test]
+    fn size_limits() {
+        assert!( Framebuffer::try_new( 0, 3 ).is_none() );
+        assert!( Framebuffer::try_new( u32::MAX, u32::MAX ).is_none() );
+        assert_eq!( Framebuffer::try_new( 3, 2 ), Some( Framebuffer::new( 3, 2 ) ) );
+    }
+
+    #[test]
+    fn splat_weights() {
+        let mut fb = Framebuffer::new( 4, 4 );
+        // On a pixel center all of the weight goes to that pixel
+        fb.splat( 1., 2., Vec4::ONE, 1. );
+        assert_eq!( fb.get_weight( 1, 2 ), 1. );
+        assert_eq!( fb.get_weight( 2, 2 ), 0. );
+
+        // Between four centers it is shared equally, and the weights always add up to the sample's
+        fb.splat( 2.5, 0.5, Vec4::ONE, 2. );
+        for ( x, y ) in [ ( 2, 0 ), ( 3, 0 ), ( 2, 1 ), ( 3, 1 ) ] {
+            assert_eq!( fb.get_weight( x, y ), 0.5 );
+        }
+        fb.splat( 2.2, 0.9, Vec4::ONE, 1. );
+        let total: f32 = ( 0..4 ).flat_map( | y | ( 0..4 ).map( move | x | ( x, y ) ) ).map( | ( x, y ) | fb.get_weight( x, y ) ).sum();
+        assert!( ( total - 4. ).abs() < 1e-5 );
+
+        // Off the edge of the image the outside part is dropped
+        fb.splat( -0.5, -0.5, Vec4::ONE, 1. );
+        assert!( ( fb.get_weight( 0, 0 ) - 0.25 ).abs() < 1e-6 );
+    }
+
+    #[test]
+    fn quantize_at_export() {
+        let mut fb = Framebuffer::new( 1, 1 );
+        // Averaging in floating point keeps what integer averages of quantized samples would round away
+        for value in [ 0.5, 0.5, 0.5, 0.51 ] {
+            fb.add_sample( 0, 0, Vec4::new( value, value * 4., -value, 1. ), 1. );
+        }
+        let linear = ColorTransform { color_space: ColorSpace::Linear, ..ColorTransform::default() };
+        let color = fb.to_color_sink( &linear ).get_pixel( 0, 0 );
+        assert_eq!( ( color.0, color.1, color.2 ), ( 128, 255, 0 ) );
+        let color = quantize( Vec3::new( 0.2, 1. / 255., 0.999 ) );
+        assert_eq!( ( color.0, color.1, color.2 ), ( 51, 1, 255 ) );
+    }
+
+    fn png( color_type: png::ColorType, bit_depth: png::BitDepth, palette: Option<&[u8]>, data: &[u8] ) -> Vec<u8> {
+        let mut file = vec![];
+        let mut encoder = png::Encoder::new( &mut file, 2, 1 );
+        encoder.set_color( color_type );
+        encoder.set_depth( bit_depth );
+        if let Some( palette ) = palette {
+            encoder.set_palette( palette );
+            encoder.set_trns( vec![ 255, 0 ] );
+        }
+        encoder.add_ztxt_chunk( "Title".to_string(), "two pixels".to_string() ).unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data( data ).unwrap();
+        writer.finish().unwrap();
+        file
+    }
 
-    if( reader.info().color_type != png::ColorType::Rgb ) {
-        panic!("Image must be RGB");
+    #[test]
+    fn png_round_trip() {
+        let mut image = Framebuffer::new( 5, 3 );
+        for y in 0..3 {
+            for x in 0..5 {
+                image.set_pixel( x, y, Vec4::new( x as f32 / 4., y as f32 / 2., 0.3, 1. - x as f32 / 8. ) );
+            }
+        }
+        let linear = ColorTransform { color_space: ColorSpace::Linear, ..ColorTransform::default() };
+        let text = vec![ ( "Software".to_string(), "RVK".to_string() ), ( "Comment".to_string(), "rvk render --scene größe.toml".to_string() ) ];
+
+        for ( bit_depth, alpha, max ) in [ ( BitDepth::Sixteen, true, 65535. ), ( BitDepth::Eight, false, 255. ) ] {
+            let mut file = vec![];
+            write_png( &image, &linear, &mut file, &PngSettings { bit_depth, alpha, text: text.clone() } ).unwrap();
+            let png = read_png( file.as_slice() ).unwrap();
+            assert_eq!( png.text, text );
+            for y in 0..3 {
+                for x in 0..5 {
+                    let ( expected, value ) = ( image.get_pixel( x, y ), png.image.get_pixel( x, y ) );
+                    let expected = if alpha { expected } else { expected.truncate().extend( 1. ) };
+                    assert!( ( value - expected ).abs().max_element() <= 0.5 / max + 1e-6, "{:?} {} {}", bit_depth, value, expected );
+                }
+            }
+        }
     }
 
-    let width = reader.info().width;
-    let height = reader.info().height;
+    #[test]
+    fn png_formats() {
+        let gray = read_png( png( png::ColorType::Grayscale, png::BitDepth::Eight, None, &[ 0, 51 ] ).as_slice() ).unwrap();
+        assert_eq!( gray.image.get_pixel( 1, 0 ), Vec4::new( 0.2, 0.2, 0.2, 1. ) );
+        assert_eq!( gray.text, vec![ ( "Title".to_string(), "two pixels".to_string() ) ] );
 
-    let mut data = vec![ 0 as u8; (width * height * 3) as usize ].into_boxed_slice();
-    reader.next_frame( &mut data ).unwrap();
+        // Gray with alpha at 16 bits, big endian
+        let data = [ 0xff, 0xff, 0x80, 0x00, 0x00, 0x00, 0xff, 0xff ];
+        let gray = read_png( png( png::ColorType::GrayscaleAlpha, png::BitDepth::Sixteen, None, &data ).as_slice() ).unwrap();
+        assert_eq!( gray.image.get_pixel( 0, 0 ), Vec4::new( 1., 1., 1., 32768. / 65535. ) );
+        assert_eq!( gray.image.get_pixel( 1, 0 ), Vec4::new( 0., 0., 0., 1. ) );
 
-    let mut color_data = vec![ Color(0, 0, 0); (width * height) as usize ].into_boxed_slice();
-    for i  in 0..(width * height) as usize {
-        color_data[i].0 = data[i * 3 + 0] as u32;
-        color_data[i].1 = data[i * 3 + 1] as u32;
-        color_data[i].2 = data[i * 3 + 2] as u32;
+        // Palette entries with transparency, packed into 4 bits
+        let palette = [ 255, 0, 0, 0, 0, 255 ];
+        let indexed = read_png( png( png::ColorType::Indexed, png::BitDepth::Four, Some( &palette ), &[ 0x01 ] ).as_slice() ).unwrap();
+        assert_eq!( indexed.image.get_pixel( 0, 0 ), Vec4::new( 1., 0., 0., 1. ) );
+        assert_eq!( indexed.image.get_pixel( 1, 0 ), Vec4::new( 0., 0., 1., 0. ) );
     }
 
-    ColorSink {
-        width,
-        height,
-        data: color_data
+    #[test]
+    fn png_errors() {
+        assert!( matches!( read_png( &b"not a png"[ .. ] ), Err( ImageError::Format( _ ) ) ) );
+        let file = png( png::ColorType::Rgb, png::BitDepth::Eight, None, &[ 1, 2, 3, 4, 5, 6 ] );
+        assert!( read_png( &file[ ..file.len() - 20 ] ).is_err() );
+
+        // Keywords longer than 79 characters can not be stored
+        let settings = PngSettings { text: vec![ ( "k".repeat( 80 ), "text".to_string() ) ], ..PngSettings::default() };
+        assert!( write_png( &Framebuffer::new( 1, 1 ), &ColorTransform::default(), vec![], &settings ).is_err() );
     }
 }
@@ -0,0 +1,386 @@
+use glam::Vec3;
+use crate::camera::Ray;
+use crate::material::Material;
+use crate::rays::{Hittable, MarchSettings, Segment, SegmentEnd, World};
+use crate::sampling::Rng;
+
+// Monte Carlo path tracer. Every bounce picks a single new direction by importance sampling the material's BSDF and
+// multiplies the throughput by the sample weight. Paths are cut short with Russian roulette once they carry little
+// energy.
+// Non-specular surfaces also sample one of the world's lights directly, so emission of those lights found by the next
+// bounce is only counted after a specular bounce or for camera rays. Emitters that are not lights, like emissive
+// planes, are counted whenever they are hit.
+#[derive( Clone, Copy, Debug )]
+pub struct PathTracer {
+    // Surface interactions before a path is terminated
+    pub max_depth: u32,
+    // Depth from which Russian roulette may terminate paths
+    pub roulette_depth: u32
+}
+
+impl Default for PathTracer {
+    fn default() -> Self {
+        PathTracer { max_depth: 16, roulette_depth: 3 }
+    }
+}
+
+impl PathTracer {
+    // Linear radiance arriving at the ray origin from the ray direction
+    pub fn radiance( &self, world: &World, ray: &Ray, march: &MarchSettings, rng: &mut Rng ) -> Vec3 {
+        let mut radiance = Vec3::ZERO;
+        let mut throughput = Vec3::ONE;
+        let mut origin = ray.origin;
+        let mut direction = ray.direction;
+        let mut length = ray.cum_length;
+        let mut specular_bounce = true;
+        // The transparent shape the path is currently travelling through
+        let mut inside = None;
+
+        for depth in 0..self.max_depth {
+            let segment = march_segment( world, inside, origin, direction, length, march );
+            length += segment.length;
+            // The medium is the material where the path entered the shape
+            if let Some( medium ) = inside {
+                throughput *= medium.material_at( origin ).transmittance( segment.length );
+            }
+
+            let shape = match segment.end {
+                SegmentEnd::Surface( shape ) => shape,
+                SegmentEnd::Escaped( _ ) => {
+                    radiance += throughput * world.background;
+                    break;
+                },
+                // Most likely a grazing ray that crawls along a surface, treat it as absorbed
+                SegmentEnd::OutOfSteps => break
+            };
+
+            let position = segment.position();
+            let mut normal = shape.calc_normal( position );
+            let front_face = normal.dot( direction ) <= 0.;
+            if !front_face {
+                normal = -normal;
+            }
+
+            let material = shape.material_at( position );
+            if material.emission != Vec3::ZERO && ( specular_bounce || !world.lights.iter().any( | light | light.covers( world, shape, position ) ) ) {
+                radiance += throughput * material.emission;
+            }
+
+            let wo = -direction;
+            let offset = march.hit_distance( length ) * 4.;
+            let lit_from = position + normal * offset;
+            if !material.is_specular() {
+                radiance += throughput * Self::direct_light( world, &material, lit_from, wo, normal, march, rng );
+            }
+
+            let Some( sample ) = material.sample( wo, normal, front_face, rng ) else { break };
+            // Start just off the surface on the side the new direction leaves to
+            let side = if sample.direction.dot( normal ) >= 0. { 1. } else { -1. };
+            origin = position + normal * offset * side;
+            if side < 0. {
+                inside = if front_face { Some( shape ) } else { None };
+            }
+            direction = sample.direction;
+            throughput *= sample.weight;
+            specular_bounce = sample.specular;
+
+            if depth >= self.roulette_depth {
+                let survive = throughput.max_element().clamp( 0.05, 1. );
+                if rng.next_f32() >= survive {
+                    break;
+                }
+                throughput /= survive;
+            }
+        }
+
+        radiance
+    }
+
+    // Light reflected towards wo from one randomly picked light, scaled up by the number of lights
+    fn direct_light( world: &World, material: &Material, origin: Vec3, wo: Vec3, normal: Vec3, march: &MarchSettings, rng: &mut Rng ) -> Vec3 {
+        if world.lights.is_empty() {
+            return Vec3::ZERO;
+        }
+        let count = world.lights.len();
+        let light = &world.lights[ usize::min( ( rng.next_f32() * count as f32 ) as usize, count - 1 ) ];
+
+        let Some( sample ) = light.sample( world, origin, rng ) else { return Vec3::ZERO };
+        let reflected = material.eval( wo, sample.direction, normal );
+        if reflected == Vec3::ZERO {
+            return Vec3::ZERO;
+        }
+        if !sample.visible && world.occluded( origin, sample.direction, sample.distance, march ) {
+            return Vec3::ZERO;
+        }
+        sample.contribution * reflected * count as f32
+    }
+}
+
+// Deterministic shading with every light evaluated once per surface. Shadows come from `World::shadow`, so they get
+// soft edges without sampling the lights. Specular surfaces are followed in their main direction, everything else is
+// only lit directly, apart from an ambient term darkened by ambient occlusion.
+#[derive( Clone, Copy, Debug )]
+pub struct DirectLighting {
+    // Specular bounces followed before a path is cut off
+    pub max_depth: u32,
+    // Penumbra factor passed to `World::shadow`, larger is harder
+    pub shadow_hardness: f32,
+    // Fraction of the albedo that is lit regardless of the lights
+    pub ambient: f32,
+    pub occlusion: AmbientOcclusion
+}
+
+impl Default for DirectLighting {
+    fn default() -> Self {
+        DirectLighting { max_depth: 16, shadow_hardness: 16., ambient: 0.03, occlusion: AmbientOcclusion::default() }
+    }
+}
+
+impl DirectLighting {
+    // Linear radiance arriving at the ray origin from the ray direction
+    pub fn radiance( &self, world: &World, ray: &Ray, march: &MarchSettings ) -> Vec3 {
+        let mut throughput = Vec3::ONE;
+        let mut origin = ray.origin;
+        let mut direction = ray.direction;
+        let mut length = ray.cum_length;
+        let mut inside = None;
+
+        for _ in 0..self.max_depth {
+            let segment = march_segment( world, inside, origin, direction, length, march );
+            length += segment.length;
+            // The medium is the material where the path entered the shape
+            if let Some( medium ) = inside {
+                throughput *= medium.material_at( origin ).transmittance( segment.length );
+            }
+
+            let shape = match segment.end {
+                SegmentEnd::Surface( shape ) => shape,
+                SegmentEnd::Escaped( _ ) => return throughput * world.background,
+                SegmentEnd::OutOfSteps => return Vec3::ZERO
+            };
+
+            let position = segment.position();
+            let mut normal = shape.calc_normal( position );
+            let front_face = normal.dot( direction ) <= 0.;
+            if !front_face {
+                normal = -normal;
+            }
+            let material = shape.material_at( position );
+            let wo = -direction;
+            let offset = march.hit_distance( length ) * 4.;
+
+            if let Some( ( next, weight ) ) = material.specular_direction( wo, normal, front_face ) {
+                let side = if next.dot( normal ) >= 0. { 1. } else { -1. };
+                origin = position + normal * offset * side;
+                if side < 0. {
+                    inside = if front_face { Some( shape ) } else { None };
+                }
+                throughput *= weight;
+                direction = next;
+                continue;
+            }
+
+            origin = position + normal * offset;
+            let mut reflected = Vec3::ZERO;
+            for light in &world.lights {
+                let Some( sample ) = light.illuminate( world, origin ) else { continue };
+                let bsdf = material.eval( wo, sample.direction, normal );
+                if bsdf == Vec3::ZERO {
+                    continue;
+                }
+                let max_t = f32::min( sample.distance, march.max_distance );
+                let visibility = world.shadow( origin, sample.direction, max_t, self.shadow_hardness );
+                reflected += sample.contribution * bsdf * visibility;
+            }
+            let ambient = self.ambient * self.occlusion.evaluate( world, origin, normal );
+            return throughput * ( material.emission + reflected + material.albedo() * ambient );
+        }
+
+        Vec3::ZERO
+    }
+}
+
+// The next segment of a path that has come path_length so far, marched through the interior of `inside` if the path
+// has been refracted into it
+fn march_segment<'a>( world: &'a World, inside: Option<&'a dyn Hittable>, origin: Vec3, direction: Vec3, path_length: f32, march: &MarchSettings ) -> Segment<'a> {
+    match inside {
+        Some( shape ) => world.march_inside( shape, origin, direction, march.max_distance - path_length, march.max_steps ),
+        None => world.march_with( origin, direction, path_length, march.max_steps, march )
+    }
+}
+
+// Ambient occlusion from distance samples along the surface normal, see `World::ambient_occlusion`
+#[derive( Clone, Copy, Debug )]
+pub struct AmbientOcclusion {
+    pub steps: u32,
+    // Distance between the samples, the furthest one sets the size of the features that occlude
+    pub step_size: f32
+}
+
+impl Default for AmbientOcclusion {
+    fn default() -> Self {
+        AmbientOcclusion { steps: 5, step_size: 0.15 }
+    }
+}
+
+impl AmbientOcclusion {
+    pub fn evaluate( &self, world: &World, pos: Vec3, normal: Vec3 ) -> f32 {
+        world.ambient_occlusion( pos, normal, self.steps, self.step_size )
+    }
+
+    // Occlusion of the first surface along the ray as a gray value, escaped rays are white
+    pub fn radiance( &self, world: &World, ray: &Ray, march: &MarchSettings ) -> Vec3 {
+        let segment = world.march_with( ray.origin, ray.direction, ray.cum_length, march.max_steps, march );
+        match segment.end {
+            SegmentEnd::Surface( shape ) => {
+                let position = segment.position();
+                let mut normal = shape.calc_normal( position );
+                if normal.dot( ray.direction ) > 0. {
+                    normal = -normal;
+                }
+                Vec3::splat( self.evaluate( world, position, normal ) )
+            },
+            SegmentEnd::Escaped( _ ) => Vec3::ONE,
+            SegmentEnd::OutOfSteps => Vec3::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use glam::{Mat4, Vec3};
+    use super::{AmbientOcclusion, DirectLighting, PathTracer};
+    use crate::bvh::Aabb;
+    use crate::camera::Ray;
+    use crate::light::Light;
+    use crate::material::{Bsdf, Material};
+    use crate::rays::{Hittable, MarchSettings, Sphere, Wall, World};
+    use crate::sampling::Rng;
+    use crate::shapes::Plane;
+    use crate::transform::Group;
+
+    // Inside a closed box that emits and reflects everywhere the radiance is emission / ( 1 - albedo )
+    #[test]
+    fn furnace() {
+        let material = Arc::new( Material { emission: Vec3::splat( 0.25 ), ..Material::lambertian( Vec3::splat( 0.5 ) ) } );
+        let walls: Vec<Box<dyn Hittable>> = [ Vec3::X, Vec3::NEG_X, Vec3::Y, Vec3::NEG_Y, Vec3::Z, Vec3::NEG_Z ].iter()
+            .map( | &n | Box::new( Wall { position: n * 2., rotation: Mat4::IDENTITY, size: Vec3::splat( 2.1 ) - n.abs() * 2., material: material.clone() } ) as Box<dyn Hittable> )
+            .collect();
+        let world = World::new( walls );
+        let tracer = PathTracer { max_depth: 64, ..PathTracer::default() };
+
+        let mut rng = Rng::new( 3, 0 );
+        let n = 4000;
+        let mut total = Vec3::ZERO;
+        for i in 0..n {
+            let direction = Vec3::new( ( i as f32 ).sin(), ( i as f32 * 1.3 ).cos(), 0.5 ).normalize();
+            let ray = Ray { origin: Vec3::ZERO, direction, reflect_count: 0, cum_length: 0., weigth: 0. };
+            total += tracer.radiance( &world, &ray, &MarchSettings::default(), &mut rng );
+        }
+        let mean = total / n as f32;
+        assert!( ( mean.x - 0.5 ).abs() < 0.03, "mean radiance {}", mean );
+    }
+
+    // A white floor lit straight from above reflects irradiance / pi, except where a sphere casts its shadow
+    #[test]
+    fn direct_light_on_floor() {
+        let white = Arc::new( Material::lambertian( Vec3::ONE ) );
+        let mut world = World::new( vec![
+            Box::new( Wall { position: Vec3::new( 0., -1., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 100., 0.1, 100. ), material: white.clone() } ),
+            Box::new( Sphere { position: Vec3::new( 0., 2., 0. ), radius: 1., material: white } )
+        ] );
+        world.lights.push( Light::Directional { direction: Vec3::NEG_Y, irradiance: Vec3::splat( std::f32::consts::PI ) } );
+        let shading = DirectLighting { ambient: 0., ..DirectLighting::default() };
+        let down = | x: f32 | Ray { origin: Vec3::new( x, 0., 0. ), direction: Vec3::NEG_Y, reflect_count: 0, cum_length: 0., weigth: 0. };
+
+        let lit = shading.radiance( &world, &down( 5. ), &MarchSettings::default() );
+        assert!( ( lit - Vec3::ONE ).length() < 1e-3, "{}", lit );
+        assert_eq!( shading.radiance( &world, &down( 0. ), &MarchSettings::default() ), Vec3::ZERO );
+        let penumbra = shading.radiance( &world, &down( 1.05 ), &MarchSettings::default() );
+        assert!( penumbra.x > 0. && penumbra.x < 1., "{}", penumbra );
+    }
+
+    // An emissive sphere of radiance l and radius r right above a white floor at distance d lights it with
+    // irradiance pi * l * r^2 / d^2, even as the second shape of a group
+    #[test]
+    fn emitter_in_group() {
+        let white = Arc::new( Material::lambertian( Vec3::ONE ) );
+        let glowing = Arc::new( Material { emission: Vec3::splat( 10. ), ..Material::lambertian( Vec3::ZERO ) } );
+        let group = Group::new( vec![
+            Box::new( Sphere { position: Vec3::new( 20., 2., 0. ), radius: 1., material: white.clone() } ),
+            Box::new( Sphere { position: Vec3::new( 0., 2., 0. ), radius: 0.5, material: glowing } )
+        ] ).unwrap();
+        let world = World::new( vec![
+            Box::new( Wall { position: Vec3::new( 0., -1., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 100., 0.1, 100. ), material: white } ),
+            Box::new( group )
+        ] );
+        assert_eq!( world.lights.len(), 1 );
+        assert!( matches!( world.lights[ 0 ], Light::Shape { index: 1, bounds } if bounds == Aabb::new( Vec3::new( -0.5, 1.5, -0.5 ), Vec3::new( 0.5, 2.5, 0.5 ) ) ) );
+
+        // Only the directly sampled light reaches the camera after one bounce
+        let tracer = PathTracer { max_depth: 1, ..PathTracer::default() };
+        let mut rng = Rng::new( 5, 0 );
+        let ray = Ray { origin: Vec3::ZERO, direction: Vec3::NEG_Y, reflect_count: 0, cum_length: 0., weigth: 0. };
+        let n = 4000;
+        let mean = ( 0..n ).map( | _ | tracer.radiance( &world, &ray, &MarchSettings::default(), &mut rng ).x ).sum::<f32>() / n as f32;
+        let expected = 10. * 0.25 / ( 2.9 * 2.9 );
+        assert!( ( mean - expected ).abs() < expected * 0.05, "{} {}", mean, expected );
+    }
+
+    // Under an endless emissive plane of radiance 1 a floor with albedo 0.5 reflects radiance 0.5. The plane has no
+    // finite bounds, so it can't be a light and is only found by the bounces that hit it.
+    #[test]
+    fn emissive_plane_above_floor() {
+        let gray = Arc::new( Material::lambertian( Vec3::splat( 0.5 ) ) );
+        let glowing = Arc::new( Material { emission: Vec3::ONE, ..Material::lambertian( Vec3::ZERO ) } );
+        let world = World::new( vec![
+            Box::new( Wall { position: Vec3::new( 0., -1., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 100., 0.1, 100. ), material: gray } ),
+            Box::new( Plane { normal: Vec3::NEG_Y, offset: -2., material: glowing } )
+        ] );
+        assert!( world.lights.is_empty() );
+
+        let tracer = PathTracer { max_depth: 2, ..PathTracer::default() };
+        let mut rng = Rng::new( 7, 0 );
+        let ray = Ray { origin: Vec3::ZERO, direction: Vec3::NEG_Y, reflect_count: 0, cum_length: 0., weigth: 0. };
+        let n = 2000;
+        let mean = ( 0..n ).map( | _ | tracer.radiance( &world, &ray, &MarchSettings::default(), &mut rng ).x ).sum::<f32>() / n as f32;
+        assert!( ( mean - 0.5 ).abs() < 0.02, "{}", mean );
+    }
+
+    #[test]
+    fn occlusion_in_corner() {
+        let white = Arc::new( Material::lambertian( Vec3::ONE ) );
+        let world = World::new( vec![
+            Box::new( Wall { position: Vec3::new( 0., -1., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 10., 1., 10. ), material: white.clone() } ),
+            Box::new( Wall { position: Vec3::new( 1., 0., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 1., 10., 10. ), material: white } )
+        ] );
+        let occlusion = AmbientOcclusion::default();
+        let at = | x: f32 | occlusion.evaluate( &world, Vec3::new( x, 0., 0. ), Vec3::Y );
+        assert!( at( -5. ) > 0.999 );
+        assert!( at( -0.01 ) < at( -0.1 ) && at( -0.1 ) < 1., "{} {}", at( -0.01 ), at( -0.1 ) );
+
+        let down = Ray { origin: Vec3::new( -5., 1., 0. ), direction: Vec3::NEG_Y, reflect_count: 0, cum_length: 0., weigth: 0. };
+        assert!( ( occlusion.radiance( &world, &down, &MarchSettings::default() ) - Vec3::ONE ).length() < 1e-3 );
+    }
+
+    // Looking through the center of an absorbing glass sphere at a white sky. At normal incidence 4% is reflected
+    // at every boundary, light inside bounces between them with absorption a over each crossing.
+    #[test]
+    fn glass_sphere() {
+        let glass = Arc::new( Material { bsdf: Bsdf::Dielectric { ior: 1.5, tint: Vec3::ONE, absorption: Vec3::splat( 0.5 ) }, emission: Vec3::ZERO } );
+        let mut world = World::new( vec![ Box::new( Sphere { position: Vec3::ZERO, radius: 1., material: glass } ) ] );
+        world.background = Vec3::ONE;
+        let tracer = PathTracer { max_depth: 64, ..PathTracer::default() };
+
+        let mut rng = Rng::new( 9, 0 );
+        let ray = Ray { origin: Vec3::new( 0., 0., -5. ), direction: Vec3::Z, reflect_count: 0, cum_length: 0., weigth: 0. };
+        let n = 20_000;
+        let mean = ( 0..n ).map( | _ | tracer.radiance( &world, &ray, &MarchSettings::default(), &mut rng ).x ).sum::<f32>() / n as f32;
+
+        let a = f32::exp( -0.5 * 2. );
+        let inside = 0.96 * a / ( 1. - 0.04 * a );
+        let expected = 0.04 + 0.96 * inside;
+        assert!( ( mean - expected ).abs() < 0.01, "{} != {}", mean, expected );
+    }
+}
@@ -0,0 +1,27 @@
+// RVK renders scenes of signed distance fields by marching rays through them.
+//
+// A `scene::Scene` holds the camera settings and a `rays::World` of shapes, `render::render` turns those into a
+// linear `image::Framebuffer`. Its `to_color_sink` tone maps and encodes it with a `color::ColorTransform` and
+// quantizes it into an `image::ColorSink`. `image::save_png` does the same while writing 8 or 16-bit PNGs with text
+// chunks. Linear images can also be kept in OpenEXR and Radiance HDR files with `exr::save` and `rgbe::save`.
+
+pub mod bvh;
+pub mod camera;
+pub mod color;
+pub mod csg;
+pub mod domain;
+pub mod exr;
+pub mod fractal;
+pub mod grid;
+pub mod image;
+pub mod integrator;
+pub mod light;
+pub mod material;
+pub mod mesh;
+pub mod rays;
+pub mod render;
+pub mod rgbe;
+pub mod sampling;
+pub mod scene;
+pub mod shapes;
+pub mod transform;
@@ -0,0 +1,201 @@
+use std::f32::consts::{PI, TAU};
+use glam::Vec3;
+use crate::bvh::Aabb;
+use crate::rays::{Hittable, MarchSettings, SegmentEnd, World, EPSILON};
+use crate::sampling::{to_world, Rng};
+
+#[derive( Clone, Copy, Debug )]
+pub enum Light {
+    // Emits `intensity` in every direction, falls off with the squared distance
+    Point { position: Vec3, intensity: Vec3 },
+    // A point light limited to a cone, fading out between the inner and outer angle (in radians)
+    Spot { position: Vec3, direction: Vec3, intensity: Vec3, inner_angle: f32, outer_angle: f32 },
+    // Light from infinitely far away travelling along `direction`
+    Directional { direction: Vec3, irradiance: Vec3 },
+    // A one-sided rectangle spanned by two edges from `corner`, emitting to the side of edge_u x edge_v.
+    // It is not part of the geometry, so it can't be seen directly.
+    Area { corner: Vec3, edge_u: Vec3, edge_v: Vec3, radiance: Vec3 },
+    // An emissive part of a shape in the world, referenced by the shape's index in `World::content` and the bounds
+    // of the part
+    Shape { index: usize, bounds: Aabb }
+}
+
+// A direction towards a light as seen from a point
+pub struct LightSample {
+    // Normalized direction from the point to the light
+    pub direction: Vec3,
+    // Distance to the sampled point on the light, infinite for directional lights
+    pub distance: f32,
+    // Incident radiance divided by the density of the sampled direction. For delta lights this is the irradiance
+    // on a surface facing the light.
+    pub contribution: Vec3,
+    // The light is known to be visible, no shadow ray is needed
+    pub visible: bool
+}
+
+impl Light {
+    // Like `sample`, but without randomness: area and shape lights are treated as a point at their center that
+    // emits as much as the whole light. The distance stops short of shape lights so a shadow query does not hit them.
+    pub fn illuminate( &self, world: &World, pos: Vec3 ) -> Option<LightSample> {
+        match *self {
+            Light::Point { .. } | Light::Spot { .. } | Light::Directional { .. } => self.sample( world, pos, &mut Rng::new( 0, 0 ) ),
+            Light::Area { corner, edge_u, edge_v, radiance } => {
+                let normal = edge_u.cross( edge_v );
+                let area = normal.length();
+                let to_light = corner + ( edge_u + edge_v ) * 0.5 - pos;
+                let distance = to_light.length();
+                let direction = to_light / distance;
+                let cos_light = -direction.dot( normal / area );
+                if cos_light <= 0. {
+                    return None;
+                }
+                Some( LightSample { direction, distance, contribution: radiance * area * cos_light / ( distance * distance ), visible: false } )
+            },
+            Light::Shape { index, bounds } => {
+                let shape = world.content().get( index )?;
+                let radius = bounds.size().length() * 0.5;
+                let to_light = bounds.center() - pos;
+                let distance = to_light.length();
+                if distance <= radius {
+                    return None;
+                }
+                // Seen from afar the bounding sphere covers pi * r^2 / d^2 of solid angle
+                let contribution = shape.material_at( bounds.center() ).emission * PI * radius * radius / ( distance * distance );
+                Some( LightSample { direction: to_light / distance, distance: distance - radius, contribution, visible: false } )
+            }
+        }
+    }
+
+    // Pick a direction from pos towards the light. Visibility is not checked, except for shape lights which are
+    // found by marching towards them.
+    pub fn sample( &self, world: &World, pos: Vec3, rng: &mut Rng ) -> Option<LightSample> {
+        match *self {
+            Light::Point { position, intensity } => {
+                let to_light = position - pos;
+                let distance = to_light.length();
+                Some( LightSample { direction: to_light / distance, distance, contribution: intensity / ( distance * distance ), visible: false } )
+            },
+            Light::Spot { position, direction, intensity, inner_angle, outer_angle } => {
+                let to_light = position - pos;
+                let distance = to_light.length();
+                let cos_angle = ( -to_light / distance ).dot( direction.normalize() );
+                let falloff = smoothstep( outer_angle.cos(), inner_angle.cos(), cos_angle );
+                if falloff <= 0. {
+                    return None;
+                }
+                Some( LightSample { direction: to_light / distance, distance, contribution: intensity * falloff / ( distance * distance ), visible: false } )
+            },
+            Light::Directional { direction, irradiance } => {
+                Some( LightSample { direction: -direction.normalize(), distance: f32::INFINITY, contribution: irradiance, visible: false } )
+            },
+            Light::Area { corner, edge_u, edge_v, radiance } => {
+                let point = corner + edge_u * rng.next_f32() + edge_v * rng.next_f32();
+                let normal = edge_u.cross( edge_v );
+                let area = normal.length();
+                let to_light = point - pos;
+                let distance = to_light.length();
+                let direction = to_light / distance;
+                let cos_light = -direction.dot( normal / area );
+                if cos_light <= 0. {
+                    return None;
+                }
+                // Convert the density from area to solid angle
+                let pdf = distance * distance / ( area * cos_light );
+                Some( LightSample { direction, distance, contribution: radiance / pdf, visible: false } )
+            },
+            Light::Shape { bounds, .. } => {
+                let center = bounds.center();
+                let radius = bounds.size().length() * 0.5;
+                let to_center = center - pos;
+                let distance = to_center.length();
+
+                // Sample the cone around the bounding sphere of the shape, or the whole sphere when inside of it
+                let ( direction, pdf ) = if distance > radius {
+                    let cos_max = f32::sqrt( f32::max( 0., 1. - radius * radius / ( distance * distance ) ) );
+                    let cos_theta = 1. - rng.next_f32() * ( 1. - cos_max );
+                    let sin_theta = f32::sqrt( f32::max( 0., 1. - cos_theta * cos_theta ) );
+                    let phi = TAU * rng.next_f32();
+                    let local = Vec3::new( sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta );
+                    ( to_world( local, to_center / distance ), 1. / ( TAU * ( 1. - cos_max ) ) )
+                } else {
+                    let z = 1. - 2. * rng.next_f32();
+                    let r = f32::sqrt( f32::max( 0., 1. - z * z ) );
+                    let phi = TAU * rng.next_f32();
+                    ( Vec3::new( r * phi.cos(), r * phi.sin(), z ), 1. / ( 4. * PI ) )
+                };
+
+                // Other emitters of the same shape are lights of their own, so only hits within these bounds count
+                let segment = world.march( pos, direction, distance + radius, MarchSettings::default().max_steps );
+                let hit_pos = pos + direction * segment.length;
+                match segment.end {
+                    SegmentEnd::Surface( hit ) if self.covers( world, hit, hit_pos ) => {
+                        let emission = hit.material_at( hit_pos ).emission;
+                        Some( LightSample { direction, distance: segment.length, contribution: emission / pdf, visible: true } )
+                    },
+                    _ => None
+                }
+            }
+        }
+    }
+
+    // Whether pos on the surface of a shape in the world is part of this shape light, so `sample` can find it
+    pub fn covers( &self, world: &World, shape: &dyn Hittable, pos: Vec3 ) -> bool {
+        match *self {
+            Light::Shape { index, bounds } => world.content().get( index )
+                .is_some_and( | light | std::ptr::addr_eq( shape, light.as_ref() ) && bounds.distance( pos ) <= EPSILON * 10. ),
+            _ => false
+        }
+    }
+}
+
+fn smoothstep( edge0: f32, edge1: f32, x: f32 ) -> f32 {
+    if edge0 == edge1 {
+        return if x >= edge1 { 1. } else { 0. };
+    }
+    let t = ( ( x - edge0 ) / ( edge1 - edge0 ) ).clamp( 0., 1. );
+    t * t * ( 3. - 2. * t )
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+    use super::Light;
+    use crate::rays::World;
+    use crate::sampling::Rng;
+
+    #[test]
+    fn point_light_falls_off_with_distance() {
+        let world = World::new( vec![] );
+        let light = Light::Point { position: Vec3::new( 0., 4., 0. ), intensity: Vec3::splat( 16. ) };
+        let sample = light.sample( &world, Vec3::ZERO, &mut Rng::new( 0, 0 ) ).unwrap();
+        assert_eq!( sample.direction, Vec3::Y );
+        assert_eq!( sample.distance, 4. );
+        assert_eq!( sample.contribution, Vec3::ONE );
+    }
+
+    #[test]
+    fn spot_light_cone() {
+        let world = World::new( vec![] );
+        let light = Light::Spot { position: Vec3::new( 0., 4., 0. ), direction: Vec3::NEG_Y, intensity: Vec3::ONE, inner_angle: 0.2, outer_angle: 0.4 };
+        let mut rng = Rng::new( 0, 0 );
+        assert!( light.sample( &world, Vec3::ZERO, &mut rng ).is_some() );
+        assert!( light.sample( &world, Vec3::new( 4., 0., 0. ), &mut rng ).is_none() );
+    }
+
+    // A small area light far away acts like a point light with intensity radiance * area
+    #[test]
+    fn small_area_light() {
+        let world = World::new( vec![] );
+        let light = Light::Area { corner: Vec3::new( -0.05, 10., -0.05 ), edge_u: Vec3::new( 0.1, 0., 0. ), edge_v: Vec3::new( 0., 0., 0.1 ), radiance: Vec3::splat( 100. ) };
+        let mut rng = Rng::new( 0, 0 );
+        let n = 1000;
+        let irradiance: f32 = ( 0..n )
+            .map( | _ | light.sample( &world, Vec3::ZERO, &mut rng ).map_or( 0., | s | s.contribution.x * s.direction.y ) )
+            .sum::<f32>() / n as f32;
+        assert!( ( irradiance - 0.01 ).abs() < 1e-4, "{}", irradiance );
+
+        // The light emits downwards only
+        let flipped = Light::Area { corner: Vec3::new( -0.05, 10., -0.05 ), edge_u: Vec3::new( 0., 0., 0.1 ), edge_v: Vec3::new( 0.1, 0., 0. ), radiance: Vec3::splat( 100. ) };
+        assert!( flipped.sample( &world, Vec3::ZERO, &mut rng ).is_none() );
+    }
+}
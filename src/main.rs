@@ -1,138 +1,199 @@
-use std::thread;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use clap::Parser;
 use glam::Vec3;
-use crate::camera::Camera;
-use crate::image::ColorSink;
-
-mod image;
-mod rays;
-mod camera;
-
-fn color_palette( t: f32, a: Vec3, b: Vec3, c: Vec3, d: Vec3 ) -> Vec3 {
-    a + b * Vec3::new( f32::cos( 6.28318 * ( c.x * t + d.x ) ), f32::cos( 6.28318 * ( c.y * t + d.y ) ), f32::cos( 6.28318 * ( c.z * t + d.z ) ) )
-}
-
-// in: x, y in range [0, 1]
-fn calc_pixel( x: f32, y: f32, camera: & Camera, world: & rays::World ) -> image::Color {
-
-    let ray = camera.get_ray( x, y );
-    let castresult = world.cast( ray, 500. );
-
-    let mut col = Vec3::new( 0.2, 0.2, 0.2 );
-    if let Some( castresult ) = castresult {
-        match castresult {
-            rays::CastResult::Hit( hit ) => {
-                // col = hit.shape.material().color * ( 1. - hit.bounces as f32 / 25. );
-                // col = color_palette( hit.bounces as f32 / 1.1 + 1.2 + hit.distance / 2.5, Vec3::new( 0.5, 0.5, 0.5 ), Vec3::new( 0.5, 0.5, 0.5 ), Vec3::new( 1.0, 0.6, 0.3 ), Vec3::new( 0.2, 0.8, 0.3 ) );
-                // col = color_palette(hit.distance / 2.5, Vec3::new( 0.5, 0.5, 0.5 ), Vec3::new( 0.5, 0.5, 0.5 ), Vec3::new( 1.0, 0.6, 0.3 ), Vec3::new( 0.2, 0.8, 0.3 ) );
-                // col = color_palette(hit.bounces as f32 * 2. + 2., Vec3::new( 0.5, 0.5, 0.5 ), Vec3::new( 0.6, 0.2, 0.5 ), Vec3::new( 0.7, 0.6, 1.0 ), Vec3::new( 0.6, 0.9, 0.3 ) );
-                col = color_palette(
-                    hit.weight / 10. + 2.,
-                    Vec3::new( 0.5, 0.5, 0.5 ),
-                    Vec3::new( 0.6, 0.6, 0.3 ),
-                    Vec3::new( 0.7, 0.6, 1.0 ),
-                    Vec3::new( 0.6, 0.9, 0.3 )
-                );
-                // col = Vec3::new( 1., 0., 0. ) * ( hit.bounces as f32 / 40. );
-                // col = hit.position;
-            },
-            rays::CastResult::Miss( miss ) => {
-                // col = miss.position * ( 1. - miss.bounces as f32 / 25. );
-                // col = Vec3::new( 1., 0., 0. ) * ( hit.bounces as f32 / 40. );
-                // col = hit.position;
-            }
+use rvk::bvh::Aabb;
+use rvk::color::{self, ColorSpace, ColorTransform, ToneMap};
+use rvk::exr::{self, ExrSettings, Precision};
+use rvk::grid::DistanceGrid;
+use rvk::image::{self, BitDepth, Framebuffer, PngSettings};
+use rvk::material::Material;
+use rvk::mesh::Mesh;
+use rvk::rays::{Hittable, MarchSettings, World};
+use rvk::integrator::{AmbientOcclusion, DirectLighting, PathTracer};
+use rvk::render::{self, Progress, RenderSettings, Shading};
+use rvk::rgbe;
+use rvk::scene::{self, Scene};
+
+mod cli;
+
+fn load_scene( path: &Path ) -> Scene {
+    match scene::load( path ) {
+        Ok( scene ) => scene,
+        Err( e ) => {
+            eprintln!( "{}: {}", path.display(), e );
+            std::process::exit( 1 );
         }
     }
-
-    // Map the color to [0, 255]
-    let mut color = col;
-    color.x = f32::min(1., f32::max(0., color.x ) );
-    color.y = f32::min(1., f32::max(0., color.y ) );
-    color.z = f32::min(1., f32::max(0., color.z ) );
-    image::Color((color.x * 255.) as u32, (color.y * 255.) as u32, (color.z * 255.) as u32 )
 }
 
-fn generation() {
-
-    let width = 512;
-    let height = 512;
-    let slices = 16;
-    let child_block_size = width * height / slices;
-
-    // Make a vector to hold the children which are spawned.
-    let mut children = vec![];
-
-    let time = std::time::Instant::now();
-    for i in 0..slices {
-        // Spin up another thread
-        children.push(thread::spawn(move || -> ColorSink {
-
-            let camera = camera::Camera::new(
-                Vec3::new( 0., 0., -2.0 ),
-                Vec3::new( 0., 0., 1. ).normalize(),
-                Vec3::new( 0., 1., 0. ).normalize(),
-                90.,
-                width as f32 / height as f32,
-                1.
-            );
-
-            let world = rays::World::new();
-
-            let mut color_sink = image::ColorSink::new(width, height / slices);
-
-            let total = width * height / slices;
-            for x in 0..(width) {
-                for ry in 0..(height/slices) {
-                    let y = ry + i * height / slices;
-
-                    let mut col = calc_pixel(( x as f32 - 0.25 ) / width as f32, ( y as f32 - 0.25 ) / height as f32, & camera, & world );
-                    col += calc_pixel(( x as f32 + 0.25 ) / width as f32, ( y as f32 - 0.25 ) / height as f32, & camera, & world );
-                    col += calc_pixel(( x as f32 + 0.25 ) / width as f32, ( y as f32 + 0.25 ) / height as f32, & camera, & world );
-                    col += calc_pixel(( x as f32 - 0.25 ) / width as f32, ( y as f32 + 0.25 ) / height as f32, & camera, & world );
-                    col /= image::Color(4, 4, 4);
-
-                    color_sink.set_pixel(x, ry, col );
-                }
-                if x % 3 == 0 {
-                    println!("Thread {}: {:.1}%", i, (x * height / slices) as f32 / total as f32 * 100. );
-                }
-            }
-            return color_sink;
-        }));
-    }
+fn fail( message: &str ) -> ! {
+    eprintln!( "{}", message );
+    std::process::exit( 1 );
+}
 
-    let mut color_sink = image::ColorSink::new(width, height);
-    let mut i = 0;
-    for child in children {
-        // Wait for the thread to finish. Returns a result.
-        let r = child.join();
+fn extension( path: &Path ) -> String {
+    path.extension().map( | e | e.to_string_lossy().to_lowercase() ).unwrap_or_default()
+}
 
-        // copy the sink into the main sink
-        let child_sink = r.unwrap();
-        color_sink.set_block( child_block_size * i, child_sink.get_data() );
+// The linear image and the text stored with it
+fn load_image( path: &Path ) -> ( Framebuffer, Vec<( String, String )> ) {
+    let image = match extension( path ).as_str() {
+        "exr" => exr::load( path ).map( | image | ( image, vec![] ) ),
+        "hdr" => rgbe::load( path ).map( | image | ( image, vec![] ) ),
+        _ => image::load_png( path ).map( | mut png | {
+            png.image.map_colors( | c | c.to_array().map( color::srgb_decode ).into() );
+            ( png.image, png.text )
+        } )
+    };
+    image.unwrap_or_else( | e | fail( &format!( "{}: {}", path.display(), e ) ) )
+}
 
-        i += 1;
+// Write the image to OpenEXR or Radiance files, which only get exposure and tone mapping and stay linear, or encode
+// and quantize it for a PNG with the text chunks
+fn save_image( image: &Framebuffer, text: Vec<( String, String )>, path: &Path, args: &cli::ExportArgs ) {
+    let transform = ColorTransform {
+        exposure: args.exposure,
+        tone_map: match args.tone_map {
+            cli::ToneMapArg::None => ToneMap::None,
+            cli::ToneMapArg::Reinhard => ToneMap::Reinhard,
+            cli::ToneMapArg::Aces => ToneMap::Aces,
+            cli::ToneMapArg::Agx => ToneMap::Agx
+        },
+        color_space: match args.color_space {
+            cli::ColorSpaceArg::Srgb => ColorSpace::Srgb,
+            cli::ColorSpaceArg::Rec709 => ColorSpace::Rec709,
+            cli::ColorSpaceArg::DisplayP3 => ColorSpace::DisplayP3,
+            cli::ColorSpaceArg::Linear => ColorSpace::Linear
+        }
+    };
+    let precision = match args.exr_precision {
+        cli::PrecisionArg::Half => Precision::Half,
+        cli::PrecisionArg::Float => Precision::Float
+    };
+    let linear = || {
+        let mut linear = image.clone();
+        linear.map_colors( | c | transform.tone_map( c ) );
+        linear
+    };
+    let result = match extension( path ).as_str() {
+        "exr" => exr::save( &linear(), path, &ExrSettings { precision, ..ExrSettings::default() } ),
+        "hdr" => rgbe::save( &linear(), path ),
+        _ => {
+            let bit_depth = if args.png_16_bit { BitDepth::Sixteen } else { BitDepth::Eight };
+            image::save_png( image, &transform, path, &PngSettings { bit_depth, alpha: args.alpha, text } )
+        }
+    };
+    if let Err( e ) = result {
+        fail( &format!( "{}: {}", path.display(), e ) );
     }
-
-    println!("Done in {:.1} seconds", time.elapsed().as_secs_f32() );
-
-    image::write_png_image( color_sink, "Output/out.png" );
 }
 
-fn process( cs: &mut ColorSink ) {
-    for x in 0..cs.get_width() {
-        for y in 0..cs.get_height() {
-            let mut col = cs.get_pixel(x, y);
-            col.0 = (col.0 as f32 * 1.0) as u32;
-            col.1 = (col.1 as f32 * 1.0) as u32;
-            col.2 = (col.2 as f32 * 1.0) as u32;
-            cs.set_pixel(x, y, col);
-        }
+fn report_progress( progress: Progress ) {
+    if progress.tiles_done == progress.tiles_total {
+        println!( "\rDone in {:.1} seconds                ", progress.elapsed.as_secs_f32() );
+    } else if progress.tiles_done.is_multiple_of( 8 ) {
+        print!( "\rRendering: {:.1}% ({}/{} tiles)", progress.fraction() * 100., progress.tiles_done, progress.tiles_total );
+        let _ = std::io::stdout().flush();
     }
 }
 
 fn main() {
-    // let mut cs = image::read_png_image( "Output/out.png" );
-    // process( &mut cs );
-    // image::write_png_image( cs, "Output/out2.png" );
-    generation();
+    let cli = cli::Cli::parse();
+    match cli.command {
+        cli::Command::Render( args ) => {
+            let scene = load_scene( &args.scene );
+            let occlusion = AmbientOcclusion { steps: args.ao_steps, step_size: args.ao_step_size };
+            let mut settings = RenderSettings {
+                width: args.width,
+                height: args.height,
+                samples: args.samples,
+                threads: args.threads.map( | t | t as usize ),
+                tile_size: args.tile_size,
+                march: MarchSettings {
+                    max_bounces: args.max_bounces,
+                    max_steps: args.max_steps,
+                    max_path_steps: args.max_path_steps,
+                    max_distance: args.max_distance,
+                    relaxation: args.relaxation,
+                    pixel_angle: 0.
+                },
+                cone_prepass: args.cone_prepass,
+                shading: match args.shading {
+                    cli::ShadingArg::Palette => Shading::Palette,
+                    cli::ShadingArg::Occlusion => Shading::Occlusion,
+                    cli::ShadingArg::Direct => Shading::Direct,
+                    cli::ShadingArg::Path => Shading::PathTrace
+                },
+                occlusion,
+                direct: DirectLighting { max_depth: args.max_depth, shadow_hardness: args.shadow_hardness, occlusion, ..DirectLighting::default() },
+                path_tracer: PathTracer { max_depth: args.max_depth, ..PathTracer::default() }
+            };
+            let camera = scene.camera.build( settings.width as f32 / settings.height as f32 );
+            if args.footprint_epsilon {
+                settings.march.pixel_angle = camera.pixel_angle( settings.height );
+            }
+
+            let framebuffer = render::render_with_progress( &camera, &scene.world, &settings, &report_progress );
+
+            // Record how the image was made, so it can be rendered again
+            let text = vec![
+                ( "Software".to_string(), format!( "RVK {}", env!( "CARGO_PKG_VERSION" ) ) ),
+                ( "Comment".to_string(), std::env::args_os().map( | a | a.to_string_lossy().into_owned() ).collect::<Vec<_>>().join( " " ) )
+            ];
+            save_image( &framebuffer, text, &args.output, &args.export );
+        },
+        cli::Command::Postprocess( args ) => {
+            let ( mut image, text ) = load_image( &args.input );
+            image.apply_gain( args.gain );
+            save_image( &image, text, &args.output, &args.export );
+        },
+        cli::Command::Info( args ) => {
+            let scene = load_scene( &args.scene );
+            let camera = scene.camera;
+            println!( "Scene: {}", args.scene.display() );
+            println!( "Camera: position {}, direction {}, up {}, fov {}, near plane {}", camera.position, camera.direction, camera.up, camera.fov, camera.near_plane );
+            let content = scene.world.content();
+            let mirrors = content.iter().filter( | shape | shape.material().is_mirror() ).count();
+            println!( "Shapes: {} ({} mirrors)", content.len(), mirrors );
+            println!( "Lights: {}", scene.world.lights.len() );
+        },
+        cli::Command::Bake( args ) => {
+            let world = match ( &args.scene, &args.mesh ) {
+                ( Some( path ), _ ) => load_scene( path ).world,
+                ( None, Some( path ) ) => match Mesh::load( path, Arc::new( Material::lambertian( Vec3::ONE ) ) ) {
+                    Ok( mesh ) => World::new( vec![ Box::new( mesh ) ] ),
+                    Err( e ) => fail( &format!( "{}: {}", path.display(), e ) )
+                },
+                ( None, None ) => unreachable!( "clap requires an input" )
+            };
+            let shapes: Vec<&dyn Hittable> = match args.shape {
+                Some( i ) => match world.content().get( i ) {
+                    Some( shape ) => vec![ shape.as_ref() ],
+                    None => fail( &format!( "the scene has only {} shapes", world.content().len() ) )
+                },
+                None => world.content().iter().map( | shape | shape.as_ref() ).collect()
+            };
+            let bounds = shapes.iter().fold( Aabb::EMPTY, | total, shape | total.union( &shape.bounds() ) );
+            if !bounds.is_finite() {
+                fail( "only bounded shapes can be baked, remove planes and endless repetitions" );
+            }
+            let margin = Vec3::splat( bounds.size().max_element() * args.padding.max( 0. ) );
+            let bounds = Aabb::new( bounds.min - margin, bounds.max + margin );
+            let spacing = bounds.size().max_element() / ( args.resolution - 1 ) as f32;
+            let threads = args.threads.map( | t | t as usize )
+                .unwrap_or_else( || std::thread::available_parallelism().map( | n | n.get() ).unwrap_or( 1 ) );
+
+            let start = std::time::Instant::now();
+            let distance = | p | shapes.iter().map( | shape | shape.distance( p ) ).fold( f32::INFINITY, f32::min );
+            let Some( grid ) = DistanceGrid::bake( distance, bounds, spacing, threads ) else {
+                fail( &format!( "a grid with a resolution of {} has too many samples", args.resolution ) );
+            };
+            if let Err( e ) = grid.save( &args.output ) {
+                fail( &format!( "{}: {}", args.output.display(), e ) );
+            }
+            println!( "Baked {} x {} x {} samples in {:.1} seconds", grid.size.x, grid.size.y, grid.size.z, start.elapsed().as_secs_f32() );
+        }
+    }
 }
@@ -0,0 +1,361 @@
+use std::f32::consts::{PI, TAU};
+use glam::Vec3;
+use crate::rays::reflect;
+use crate::sampling::{cosine_hemisphere, cosine_hemisphere_pdf, to_world, Rng};
+
+// How a surface scatters light
+#[derive( Clone, Debug, PartialEq )]
+pub enum Bsdf {
+    // Ideal diffuse reflection
+    Lambertian { albedo: Vec3 },
+    // Conductor with a GGX microfacet distribution, a roughness of 0 is a perfect mirror
+    Metal { albedo: Vec3, roughness: f32 },
+    // Smooth glass-like boundary that reflects or refracts according to the Fresnel equations. Light passing
+    // through is multiplied by `tint`, and inside the shape it is absorbed by `absorption` per unit of distance.
+    Dielectric { ior: f32, tint: Vec3, absorption: Vec3 },
+    // Simplified Disney principled BSDF: a diffuse lobe with retro-reflection at grazing angles blended with a GGX
+    // specular lobe. `specular` scales the reflectance at normal incidence of non-metals, 0.5 is 4%.
+    Principled { base_color: Vec3, metallic: f32, roughness: f32, specular: f32 }
+}
+
+// Materials are shared between shapes through an `Arc`, see `scene::parse`
+#[derive( Clone, Debug, PartialEq )]
+pub struct Material {
+    pub bsdf: Bsdf,
+    // Radiance emitted by the surface
+    pub emission: Vec3
+}
+
+pub struct BsdfSample {
+    pub direction: Vec3,
+    // BSDF * cos(theta) / pdf, the factor for the path throughput
+    pub weight: Vec3,
+    // Sampled from a delta distribution, `eval` is zero for such directions
+    pub specular: bool
+}
+
+impl Material {
+    pub fn lambertian( albedo: Vec3 ) -> Material {
+        Material { bsdf: Bsdf::Lambertian { albedo }, emission: Vec3::ZERO }
+    }
+
+    pub fn mirror( albedo: Vec3 ) -> Material {
+        Material { bsdf: Bsdf::Metal { albedo, roughness: 0. }, emission: Vec3::ZERO }
+    }
+
+    // A black surface that only emits
+    pub fn emissive( radiance: Vec3 ) -> Material {
+        Material { bsdf: Bsdf::Lambertian { albedo: Vec3::ZERO }, emission: radiance }
+    }
+
+    // The overall color of the surface, for shading that does not evaluate the BSDF
+    pub fn albedo( &self ) -> Vec3 {
+        match self.bsdf {
+            Bsdf::Lambertian { albedo } | Bsdf::Metal { albedo, .. } => albedo,
+            Bsdf::Dielectric { tint, .. } => tint,
+            Bsdf::Principled { base_color, .. } => base_color
+        }
+    }
+
+    // A perfectly smooth metal, the kind of surface the ray marcher follows reflections on
+    pub fn is_mirror( &self ) -> bool {
+        matches!( self.bsdf, Bsdf::Metal { roughness, .. } if roughness <= 0. )
+    }
+
+    // Scatters only into delta directions, so light sources can't be sampled for it
+    pub fn is_specular( &self ) -> bool {
+        self.is_mirror() || matches!( self.bsdf, Bsdf::Dielectric { .. } )
+    }
+
+    // BSDF * cos(theta) for light arriving from wi and leaving towards wo. Both point away from the surface, n is
+    // the normal on the side of wo. Zero for specular materials.
+    pub fn eval( &self, wo: Vec3, wi: Vec3, n: Vec3 ) -> Vec3 {
+        let ( cos_o, cos_i ) = ( wo.dot( n ), wi.dot( n ) );
+        if cos_o <= 0. || cos_i <= 0. || self.is_specular() {
+            return Vec3::ZERO;
+        }
+        match self.bsdf {
+            Bsdf::Lambertian { albedo } => albedo / PI * cos_i,
+            Bsdf::Metal { albedo, roughness } => ggx_eval( wo, wi, n, alpha( roughness ), albedo ),
+            Bsdf::Principled { base_color, metallic, roughness, specular } => {
+                let ( diffuse, f0 ) = principled_lobes( base_color, metallic, roughness, specular, wo, wi, n );
+                diffuse * cos_i + ggx_eval( wo, wi, n, alpha( roughness ), f0 )
+            },
+            Bsdf::Dielectric { .. } => Vec3::ZERO
+        }
+    }
+
+    // Density of sampling wi with `sample`, zero for specular materials
+    pub fn pdf( &self, wo: Vec3, wi: Vec3, n: Vec3 ) -> f32 {
+        let ( cos_o, cos_i ) = ( wo.dot( n ), wi.dot( n ) );
+        if cos_o <= 0. || cos_i <= 0. || self.is_specular() {
+            return 0.;
+        }
+        match self.bsdf {
+            Bsdf::Lambertian { .. } => cosine_hemisphere_pdf( cos_i ),
+            Bsdf::Metal { roughness, .. } => ggx_pdf( wo, wi, n, alpha( roughness ) ),
+            Bsdf::Principled { metallic, roughness, .. } => {
+                let p_specular = specular_probability( metallic );
+                ( 1. - p_specular ) * cosine_hemisphere_pdf( cos_i ) + p_specular * ggx_pdf( wo, wi, n, alpha( roughness ) )
+            },
+            Bsdf::Dielectric { .. } => 0.
+        }
+    }
+
+    // Pick a direction for light to arrive from, for a path leaving towards wo. n is the normal on the side of wo
+    // and `front_face` tells whether that is the outside of the shape.
+    pub fn sample( &self, wo: Vec3, n: Vec3, front_face: bool, rng: &mut Rng ) -> Option<BsdfSample> {
+        match self.bsdf {
+            Bsdf::Lambertian { albedo } => {
+                let direction = cosine_hemisphere( n, rng.next_f32(), rng.next_f32() );
+                Some( BsdfSample { direction, weight: albedo, specular: false } )
+            },
+            Bsdf::Metal { albedo, .. } if self.is_mirror() => {
+                let direction = reflect( wo, n );
+                Some( BsdfSample { direction, weight: schlick( albedo, wo.dot( n ) ), specular: true } )
+            },
+            Bsdf::Metal { roughness, .. } => {
+                let h = sample_ggx_half( n, alpha( roughness ), rng.next_f32(), rng.next_f32() );
+                self.sampled( wo, reflect( wo, h ), n )
+            },
+            Bsdf::Dielectric { ior, tint, .. } => {
+                // Choose between reflection and refraction by the Fresnel reflectance, so both have a weight of 1
+                let eta = if front_face { 1. / ior } else { ior };
+                match refract( wo, n, eta ) {
+                    Some( ( refracted, reflectance ) ) if rng.next_f32() >= reflectance => {
+                        Some( BsdfSample { direction: refracted, weight: tint, specular: true } )
+                    },
+                    _ => Some( BsdfSample { direction: reflect( wo, n ), weight: Vec3::ONE, specular: true } )
+                }
+            },
+            Bsdf::Principled { metallic, roughness, .. } => {
+                let direction = if rng.next_f32() < specular_probability( metallic ) {
+                    let h = sample_ggx_half( n, alpha( roughness ), rng.next_f32(), rng.next_f32() );
+                    reflect( wo, h )
+                } else {
+                    cosine_hemisphere( n, rng.next_f32(), rng.next_f32() )
+                };
+                self.sampled( wo, direction, n )
+            }
+        }
+    }
+
+    fn sampled( &self, wo: Vec3, direction: Vec3, n: Vec3 ) -> Option<BsdfSample> {
+        let pdf = self.pdf( wo, direction, n );
+        if pdf <= 0. {
+            return None;
+        }
+        Some( BsdfSample { direction, weight: self.eval( wo, direction, n ) / pdf, specular: false } )
+    }
+
+    // Fraction of light left after travelling distance through the inside of the shape, following Beer-Lambert
+    pub fn transmittance( &self, distance: f32 ) -> Vec3 {
+        match self.bsdf {
+            Bsdf::Dielectric { absorption, .. } => ( -absorption * distance ).exp(),
+            _ => Vec3::ONE
+        }
+    }
+
+    // Mix towards other by t, for surfaces that smoothly turn into another material. BSDFs of the same type have
+    // their parameters interpolated, different types switch over halfway.
+    pub fn blend( &self, other: &Material, t: f32 ) -> Material {
+        let bsdf = match ( &self.bsdf, &other.bsdf ) {
+            ( Bsdf::Lambertian { albedo: a }, Bsdf::Lambertian { albedo: b } ) => Bsdf::Lambertian { albedo: a.lerp( *b, t ) },
+            ( Bsdf::Metal { albedo: a, roughness: ra }, Bsdf::Metal { albedo: b, roughness: rb } ) => {
+                Bsdf::Metal { albedo: a.lerp( *b, t ), roughness: lerp( *ra, *rb, t ) }
+            },
+            ( Bsdf::Dielectric { ior: ia, tint: ta, absorption: aa }, Bsdf::Dielectric { ior: ib, tint: tb, absorption: ab } ) => {
+                Bsdf::Dielectric { ior: lerp( *ia, *ib, t ), tint: ta.lerp( *tb, t ), absorption: aa.lerp( *ab, t ) }
+            },
+            (
+                Bsdf::Principled { base_color: ca, metallic: ma, roughness: ra, specular: sa },
+                Bsdf::Principled { base_color: cb, metallic: mb, roughness: rb, specular: sb }
+            ) => Bsdf::Principled {
+                base_color: ca.lerp( *cb, t ),
+                metallic: lerp( *ma, *mb, t ),
+                roughness: lerp( *ra, *rb, t ),
+                specular: lerp( *sa, *sb, t )
+            },
+            _ => if t < 0.5 { self.bsdf.clone() } else { other.bsdf.clone() }
+        };
+        Material { bsdf, emission: self.emission.lerp( other.emission, t ) }
+    }
+
+    // The single most important direction of a specular material and its weight, for shading without random
+    // sampling. Dielectrics are looked through unless the light is totally reflected.
+    pub fn specular_direction( &self, wo: Vec3, n: Vec3, front_face: bool ) -> Option<( Vec3, Vec3 )> {
+        match self.bsdf {
+            Bsdf::Metal { albedo, .. } if self.is_mirror() => Some( ( reflect( wo, n ), schlick( albedo, wo.dot( n ) ) ) ),
+            Bsdf::Dielectric { ior, tint, .. } => {
+                let eta = if front_face { 1. / ior } else { ior };
+                match refract( wo, n, eta ) {
+                    Some( ( refracted, _ ) ) => Some( ( refracted, tint ) ),
+                    None => Some( ( reflect( wo, n ), Vec3::ONE ) )
+                }
+            },
+            _ => None
+        }
+    }
+}
+
+// Direction of light refracted into the surface coming from wo, and the Fresnel reflectance.
+// eta is the index of refraction on the side of wo divided by the one on the other side.
+// None on total internal reflection.
+pub fn refract( wo: Vec3, n: Vec3, eta: f32 ) -> Option<( Vec3, f32 )> {
+    let cos_i = wo.dot( n ).clamp( 0., 1. );
+    let sin2_t = eta * eta * ( 1. - cos_i * cos_i );
+    if sin2_t >= 1. {
+        return None;
+    }
+    let cos_t = f32::sqrt( 1. - sin2_t );
+    let r_s = ( eta * cos_i - cos_t ) / ( eta * cos_i + cos_t );
+    let r_p = ( cos_i - eta * cos_t ) / ( cos_i + eta * cos_t );
+    let direction = -wo * eta + n * ( eta * cos_i - cos_t );
+    Some( ( direction.normalize(), ( r_s * r_s + r_p * r_p ) * 0.5 ) )
+}
+
+fn lerp( a: f32, b: f32, t: f32 ) -> f32 {
+    a + ( b - a ) * t
+}
+
+fn schlick( f0: Vec3, cos_theta: f32 ) -> Vec3 {
+    f0 + ( Vec3::ONE - f0 ) * ( 1. - cos_theta.clamp( 0., 1. ) ).powi( 5 )
+}
+
+// Perceptually linear roughness to the GGX alpha, kept above 0 where the distribution degenerates
+fn alpha( roughness: f32 ) -> f32 {
+    f32::max( roughness * roughness, 0.001 )
+}
+
+fn specular_probability( metallic: f32 ) -> f32 {
+    0.5 + 0.5 * metallic.clamp( 0., 1. )
+}
+
+// Diffuse BSDF and specular reflectance at normal incidence of the principled model
+fn principled_lobes( base_color: Vec3, metallic: f32, roughness: f32, specular: f32, wo: Vec3, wi: Vec3, n: Vec3 ) -> ( Vec3, Vec3 ) {
+    let metallic = metallic.clamp( 0., 1. );
+    let h = ( wo + wi ).normalize();
+    let cos_d = wi.dot( h );
+    let fd90 = 0.5 + 2. * roughness * cos_d * cos_d;
+    let retro = | cos: f32 | 1. + ( fd90 - 1. ) * ( 1. - cos ).powi( 5 );
+    let diffuse = base_color / PI * retro( wi.dot( n ) ) * retro( wo.dot( n ) ) * ( 1. - metallic );
+    let f0 = Vec3::splat( 0.08 * specular ).lerp( base_color, metallic );
+    ( diffuse, f0 )
+}
+
+// GGX normal distribution
+fn ggx_d( cos_h: f32, alpha: f32 ) -> f32 {
+    let a2 = alpha * alpha;
+    let d = cos_h * cos_h * ( a2 - 1. ) + 1.;
+    a2 / ( PI * d * d )
+}
+
+// Smith masking for one direction
+fn smith_g1( cos_v: f32, alpha: f32 ) -> f32 {
+    let a2 = alpha * alpha;
+    2. * cos_v / ( cos_v + f32::sqrt( a2 + ( 1. - a2 ) * cos_v * cos_v ) )
+}
+
+// Microfacet reflection times cos(theta_i), D G F / ( 4 cos_o cos_i ) * cos_i
+fn ggx_eval( wo: Vec3, wi: Vec3, n: Vec3, alpha: f32, f0: Vec3 ) -> Vec3 {
+    let h = ( wo + wi ).normalize();
+    let ( cos_o, cos_i ) = ( wo.dot( n ), wi.dot( n ) );
+    let g = smith_g1( cos_o, alpha ) * smith_g1( cos_i, alpha );
+    schlick( f0, wo.dot( h ) ) * ggx_d( n.dot( h ), alpha ) * g / ( 4. * cos_o )
+}
+
+fn ggx_pdf( wo: Vec3, wi: Vec3, n: Vec3, alpha: f32 ) -> f32 {
+    let h = ( wo + wi ).normalize();
+    let wo_h = wo.dot( h );
+    if wo_h <= 0. {
+        return 0.;
+    }
+    ggx_d( n.dot( h ), alpha ) * n.dot( h ) / ( 4. * wo_h )
+}
+
+// Microfacet normal around n with a density of D(h) * cos(theta_h)
+fn sample_ggx_half( n: Vec3, alpha: f32, u1: f32, u2: f32 ) -> Vec3 {
+    let a2 = alpha * alpha;
+    let cos_theta = f32::sqrt( ( 1. - u1 ) / ( 1. + ( a2 - 1. ) * u1 ) );
+    let sin_theta = f32::sqrt( f32::max( 0., 1. - cos_theta * cos_theta ) );
+    let phi = TAU * u2;
+    to_world( Vec3::new( sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta ), n )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use glam::Vec3;
+    use super::{refract, Bsdf, Material};
+    use crate::sampling::{to_world, Rng};
+
+    // Mean of the sample weights, which estimates the directional albedo
+    fn sampled_albedo( material: &Material, wo: Vec3, n: usize ) -> Vec3 {
+        let mut rng = Rng::new( 5, 0 );
+        ( 0..n ).filter_map( | _ | material.sample( wo, Vec3::Z, true, &mut rng ) ).map( | s | s.weight ).sum::<Vec3>() / n as f32
+    }
+
+    // The same estimated by integrating `eval` over uniformly distributed directions
+    fn integrated_albedo( material: &Material, wo: Vec3, n: usize ) -> Vec3 {
+        let mut rng = Rng::new( 6, 0 );
+        ( 0..n ).map( | _ | {
+            let z = rng.next_f32();
+            let r = f32::sqrt( 1. - z * z );
+            let phi = 2. * PI * rng.next_f32();
+            let wi = to_world( Vec3::new( r * phi.cos(), r * phi.sin(), z ), Vec3::Z );
+            material.eval( wo, wi, Vec3::Z ) * 2. * PI
+        } ).sum::<Vec3>() / n as f32
+    }
+
+    #[test]
+    fn lambertian_reflects_albedo() {
+        let material = Material::lambertian( Vec3::splat( 0.6 ) );
+        let wo = Vec3::new( 0.3, 0., 1. ).normalize();
+        assert!( ( sampled_albedo( &material, wo, 1000 ) - Vec3::splat( 0.6 ) ).length() < 1e-5 );
+        assert!( ( integrated_albedo( &material, wo, 100_000 ).x - 0.6 ).abs() < 0.01 );
+    }
+
+    #[test]
+    fn sampling_matches_eval() {
+        let materials = [
+            Material { bsdf: Bsdf::Metal { albedo: Vec3::new( 0.9, 0.6, 0.3 ), roughness: 0.6 }, emission: Vec3::ZERO },
+            Material { bsdf: Bsdf::Principled { base_color: Vec3::splat( 0.5 ), metallic: 0.3, roughness: 0.7, specular: 0.5 }, emission: Vec3::ZERO }
+        ];
+        let wo = Vec3::new( 0.5, 0.2, 1. ).normalize();
+        for material in &materials {
+            let sampled = sampled_albedo( material, wo, 200_000 );
+            let integrated = integrated_albedo( material, wo, 400_000 );
+            assert!( ( sampled - integrated ).abs().max_element() < 0.02, "{} != {}", sampled, integrated );
+            assert!( sampled.max_element() <= 1. );
+        }
+    }
+
+    #[test]
+    fn mirror_reflects() {
+        let material = Material::mirror( Vec3::ONE );
+        let wo = Vec3::new( 1., 0., 1. ).normalize();
+        let sample = material.sample( wo, Vec3::Z, true, &mut Rng::new( 0, 0 ) ).unwrap();
+        assert!( sample.specular );
+        assert!( ( sample.direction - Vec3::new( -1., 0., 1. ).normalize() ).length() < 1e-6 );
+        assert_eq!( material.eval( wo, sample.direction, Vec3::Z ), Vec3::ZERO );
+    }
+
+    #[test]
+    fn dielectric_fresnel() {
+        // 4% reflection at normal incidence into glass
+        let ( direction, reflectance ) = refract( Vec3::Z, Vec3::Z, 1. / 1.5 ).unwrap();
+        assert!( ( reflectance - 0.04 ).abs() < 1e-6 );
+        assert!( ( direction - Vec3::NEG_Z ).length() < 1e-6 );
+
+        // Snell's law, sin(theta_i) = 1.5 * sin(theta_t)
+        let wo = Vec3::new( 0.8, 0., 0.6 );
+        let ( direction, _ ) = refract( wo, Vec3::Z, 1. / 1.5 ).unwrap();
+        assert!( ( direction.x + 0.8 / 1.5 ).abs() < 1e-6 );
+
+        // Leaving glass at a grazing angle is totally reflected
+        assert!( refract( wo, Vec3::Z, 1.5 ).is_none() );
+        let glass = Material { bsdf: Bsdf::Dielectric { ior: 1.5, tint: Vec3::ONE, absorption: Vec3::ZERO }, emission: Vec3::ZERO };
+        let sample = glass.sample( wo, Vec3::Z, false, &mut Rng::new( 0, 0 ) ).unwrap();
+        assert!( sample.direction.z > 0. );
+    }
+}
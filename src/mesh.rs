@@ -0,0 +1,531 @@
+// Triangle meshes loaded from OBJ or PLY files, rendered through their exact signed distance. The nearest triangle
+// is found with a BVH, and the sign comes from the angle weighted pseudo normal of the closest feature of it:
+// Bærentzen and Aanæs, "Signed distance computation using the angle weighted pseudonormal", 2005.
+// The sign is only meaningful for closed meshes.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use glam::Vec3;
+use crate::bvh::{Aabb, Bvh};
+use crate::material::Material;
+use crate::rays::Hittable;
+
+#[derive( Debug )]
+pub enum MeshError {
+    Io( std::io::Error ),
+    // `line` is 0 when the error isn't tied to a line, like in binary PLY data
+    Parse { line: usize, message: String },
+    UnknownFormat( String )
+}
+
+impl fmt::Display for MeshError {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            MeshError::Io( e ) => write!( f, "could not read mesh file: {}", e ),
+            MeshError::Parse { line: 0, message } => write!( f, "could not parse mesh file: {}", message ),
+            MeshError::Parse { line, message } => write!( f, "could not parse mesh file: line {}: {}", line, message ),
+            MeshError::UnknownFormat( extension ) => write!( f, "unknown mesh format \"{}\", expected obj or ply", extension )
+        }
+    }
+}
+
+impl std::error::Error for MeshError {
+    fn source( &self ) -> Option<&( dyn std::error::Error + 'static )> {
+        match self {
+            MeshError::Io( e ) => Some( e ),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for MeshError {
+    fn from( e: std::io::Error ) -> Self {
+        MeshError::Io( e )
+    }
+}
+
+fn parse_error( line: usize, message: &str ) -> MeshError {
+    MeshError::Parse { line, message: message.to_string() }
+}
+
+// Vertex positions and triangles indexing into them
+#[derive( Clone, Debug, Default, PartialEq )]
+pub struct TriangleList {
+    pub vertices: Vec<Vec3>,
+    pub triangles: Vec<[u32; 3]>
+}
+
+impl TriangleList {
+    // Load an .obj or .ply file, by its extension
+    pub fn load<P: AsRef<Path>>( path: P ) -> Result<TriangleList, MeshError> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then( | e | e.to_str() ).unwrap_or( "" ).to_ascii_lowercase();
+        match extension.as_str() {
+            "obj" => parse_obj( &fs::read_to_string( path )? ),
+            "ply" => parse_ply( &fs::read( path )? ),
+            _ => Err( MeshError::UnknownFormat( extension ) )
+        }
+    }
+
+    // Split a polygon into a fan of triangles around its first vertex
+    fn push_polygon( &mut self, polygon: &[u32] ) {
+        for i in 1..polygon.len().saturating_sub( 1 ) {
+            self.triangles.push( [ polygon[ 0 ], polygon[ i ], polygon[ i + 1 ] ] );
+        }
+    }
+}
+
+// Wavefront OBJ, only vertex positions and faces are used. Polygons are split into triangles.
+pub fn parse_obj( source: &str ) -> Result<TriangleList, MeshError> {
+    let mut list = TriangleList::default();
+    let mut polygon = vec![];
+    for ( i, line ) in source.lines().enumerate() {
+        let line_number = i + 1;
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some( "v" ) => {
+                let mut coordinate = || words.next().and_then( | w | w.parse::<f32>().ok() ).ok_or_else( || parse_error( line_number, "expected 3 coordinates" ) );
+                list.vertices.push( Vec3::new( coordinate()?, coordinate()?, coordinate()? ) );
+            },
+            Some( "f" ) => {
+                polygon.clear();
+                for word in words {
+                    // Faces are v, v/vt, v//vn or v/vt/vn, negative indices count back from the last vertex
+                    let index: i64 = word.split( '/' ).next().unwrap().parse().map_err( | _ | parse_error( line_number, "expected a vertex index" ) )?;
+                    let index = if index < 0 { list.vertices.len() as i64 + index } else { index - 1 };
+                    if index < 0 || index >= list.vertices.len() as i64 {
+                        return Err( parse_error( line_number, "vertex index out of range" ) );
+                    }
+                    polygon.push( index as u32 );
+                }
+                if polygon.len() < 3 {
+                    return Err( parse_error( line_number, "a face needs at least 3 vertices" ) );
+                }
+                list.push_polygon( &polygon );
+            },
+            _ => ()
+        }
+    }
+    Ok( list )
+}
+
+#[derive( Clone, Copy, PartialEq )]
+enum PlyFormat {
+    Ascii,
+    LittleEndian,
+    BigEndian
+}
+
+#[derive( Clone, Copy )]
+enum PlyType {
+    I8, U8, I16, U16, I32, U32, F32, F64
+}
+
+impl PlyType {
+    fn parse( name: &str ) -> Option<PlyType> {
+        match name {
+            "char" | "int8" => Some( PlyType::I8 ),
+            "uchar" | "uint8" => Some( PlyType::U8 ),
+            "short" | "int16" => Some( PlyType::I16 ),
+            "ushort" | "uint16" => Some( PlyType::U16 ),
+            "int" | "int32" => Some( PlyType::I32 ),
+            "uint" | "uint32" => Some( PlyType::U32 ),
+            "float" | "float32" => Some( PlyType::F32 ),
+            "double" | "float64" => Some( PlyType::F64 ),
+            _ => None
+        }
+    }
+
+    fn size( self ) -> usize {
+        match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8
+        }
+    }
+}
+
+struct PlyProperty {
+    name: String,
+    // The type of the length for list properties
+    count: Option<PlyType>,
+    value: PlyType
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>
+}
+
+// Reads the values of the body of a PLY file one at a time, from text or binary data
+struct PlyReader<'a> {
+    format: PlyFormat,
+    data: &'a [u8],
+    position: usize,
+    words: std::str::SplitAsciiWhitespace<'a>
+}
+
+impl PlyReader<'_> {
+    fn read( &mut self, kind: PlyType ) -> Result<f64, MeshError> {
+        if self.format == PlyFormat::Ascii {
+            return self.words.next().and_then( | w | w.parse().ok() ).ok_or_else( || parse_error( 0, "expected a number" ) );
+        }
+        let bytes = self.data.get( self.position..self.position + kind.size() ).ok_or_else( || parse_error( 0, "unexpected end of data" ) )?;
+        self.position += kind.size();
+        let mut buffer = [ 0u8; 8 ];
+        buffer[ ..bytes.len() ].copy_from_slice( bytes );
+        if self.format == PlyFormat::BigEndian {
+            buffer[ ..bytes.len() ].reverse();
+        }
+        Ok( match kind {
+            PlyType::I8 => buffer[ 0 ] as i8 as f64,
+            PlyType::U8 => buffer[ 0 ] as f64,
+            PlyType::I16 => i16::from_le_bytes( [ buffer[ 0 ], buffer[ 1 ] ] ) as f64,
+            PlyType::U16 => u16::from_le_bytes( [ buffer[ 0 ], buffer[ 1 ] ] ) as f64,
+            PlyType::I32 => i32::from_le_bytes( [ buffer[ 0 ], buffer[ 1 ], buffer[ 2 ], buffer[ 3 ] ] ) as f64,
+            PlyType::U32 => u32::from_le_bytes( [ buffer[ 0 ], buffer[ 1 ], buffer[ 2 ], buffer[ 3 ] ] ) as f64,
+            PlyType::F32 => f32::from_le_bytes( [ buffer[ 0 ], buffer[ 1 ], buffer[ 2 ], buffer[ 3 ] ] ) as f64,
+            PlyType::F64 => f64::from_le_bytes( buffer )
+        } )
+    }
+}
+
+// Stanford PLY in ascii or binary, using the x, y and z of the vertex element and the vertex_indices of the face
+// element. Other elements and properties are skipped.
+pub fn parse_ply( data: &[u8] ) -> Result<TriangleList, MeshError> {
+    // The header is text up to the end_header line
+    let header_end = data.windows( 11 ).position( | w | w == b"end_header\n" || w == b"end_header\r" )
+        .ok_or_else( || parse_error( 0, "missing end_header" ) )?;
+    let body_start = data[ header_end.. ].iter().position( | &b | b == b'\n' ).map_or( data.len(), | i | header_end + i + 1 );
+    let header = std::str::from_utf8( &data[ ..header_end ] ).map_err( | _ | parse_error( 0, "header is not text" ) )?;
+
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = vec![];
+    for ( i, line ) in header.lines().enumerate() {
+        let line_number = i + 1;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let unsupported = || parse_error( line_number, "unsupported header line" );
+        match words.as_slice() {
+            [ "ply" ] if i == 0 => (),
+            _ if i == 0 => return Err( parse_error( 1, "not a PLY file" ) ),
+            [ "format", kind, _ ] => format = Some( match *kind {
+                "ascii" => PlyFormat::Ascii,
+                "binary_little_endian" => PlyFormat::LittleEndian,
+                "binary_big_endian" => PlyFormat::BigEndian,
+                _ => return Err( unsupported() )
+            } ),
+            [ "comment", .. ] | [ "obj_info", .. ] | [] => (),
+            [ "element", name, count ] => elements.push( PlyElement {
+                name: name.to_string(),
+                count: count.parse().map_err( | _ | unsupported() )?,
+                properties: vec![]
+            } ),
+            [ "property", "list", count, value, name ] => elements.last_mut().ok_or_else( unsupported )?.properties.push( PlyProperty {
+                name: name.to_string(),
+                count: Some( PlyType::parse( count ).ok_or_else( unsupported )? ),
+                value: PlyType::parse( value ).ok_or_else( unsupported )?
+            } ),
+            [ "property", value, name ] => elements.last_mut().ok_or_else( unsupported )?.properties.push( PlyProperty {
+                name: name.to_string(),
+                count: None,
+                value: PlyType::parse( value ).ok_or_else( unsupported )?
+            } ),
+            _ => return Err( unsupported() )
+        }
+    }
+    let format = format.ok_or_else( || parse_error( 0, "missing format" ) )?;
+
+    let body = &data[ body_start.. ];
+    let text = if format == PlyFormat::Ascii { std::str::from_utf8( body ).map_err( | _ | parse_error( 0, "data is not text" ) )? } else { "" };
+    let mut reader = PlyReader { format, data: body, position: 0, words: text.split_ascii_whitespace() };
+
+    let mut list = TriangleList::default();
+    let mut polygon = vec![];
+    for element in &elements {
+        for _ in 0..element.count {
+            let mut vertex = Vec3::ZERO;
+            for property in &element.properties {
+                match property.count {
+                    Some( count_type ) => {
+                        let count = reader.read( count_type )? as usize;
+                        polygon.clear();
+                        for _ in 0..count {
+                            polygon.push( reader.read( property.value )? );
+                        }
+                        if element.name == "face" && ( property.name == "vertex_indices" || property.name == "vertex_index" ) {
+                            if polygon.iter().any( | &index | index < 0. ) {
+                                return Err( parse_error( 0, "negative vertex index" ) );
+                            }
+                            list.push_polygon( &polygon.iter().map( | &index | index as u32 ).collect::<Vec<_>>() );
+                        }
+                    },
+                    None => {
+                        let value = reader.read( property.value )? as f32;
+                        match property.name.as_str() {
+                            "x" => vertex.x = value,
+                            "y" => vertex.y = value,
+                            "z" => vertex.z = value,
+                            _ => ()
+                        }
+                    }
+                }
+            }
+            if element.name == "vertex" {
+                list.vertices.push( vertex );
+            }
+        }
+    }
+    if list.triangles.iter().flatten().any( | &index | index as usize >= list.vertices.len() ) {
+        return Err( parse_error( 0, "vertex index out of range" ) );
+    }
+    Ok( list )
+}
+
+struct Face {
+    vertices: [u32; 3],
+    normal: Vec3,
+    // Pseudo normals of the edges from vertex i to i + 1
+    edge_normals: [Vec3; 3]
+}
+
+// Which part of a triangle a point is closest to
+#[derive( Clone, Copy, Debug, PartialEq )]
+enum Feature {
+    Face,
+    // The edge from vertex i to i + 1
+    Edge( usize ),
+    Vertex( usize )
+}
+
+pub struct Mesh {
+    vertices: Vec<Vec3>,
+    vertex_normals: Vec<Vec3>,
+    faces: Vec<Face>,
+    bvh: Bvh,
+    bounds: Aabb,
+    pub material: Arc<Material>
+}
+
+impl Mesh {
+    // Degenerate triangles without an area are left out
+    pub fn new( list: TriangleList, material: Arc<Material> ) -> Mesh {
+        let TriangleList { vertices, triangles } = list;
+        let corners = | t: &[u32; 3] | t.map( | i | vertices[ i as usize ] );
+
+        let mut faces: Vec<Face> = triangles.iter()
+            .filter_map( | t | {
+                let [ a, b, c ] = corners( t );
+                let normal = ( b - a ).cross( c - a ).try_normalize()?;
+                Some( Face { vertices: *t, normal, edge_normals: [ Vec3::ZERO; 3 ] } )
+            } )
+            .collect();
+
+        // Edge pseudo normals are the sum of the normals of the faces on either side, vertex pseudo normals the sum
+        // of the normals of the faces around it weighted by the angle they make at the vertex
+        let mut edges: HashMap<( u32, u32 ), Vec3> = HashMap::new();
+        let mut vertex_normals = vec![ Vec3::ZERO; vertices.len() ];
+        for face in &faces {
+            let points = corners( &face.vertices );
+            for i in 0..3 {
+                let ( from, to ) = ( face.vertices[ i ], face.vertices[ ( i + 1 ) % 3 ] );
+                *edges.entry( ( from.min( to ), from.max( to ) ) ).or_default() += face.normal;
+                let ( u, v ) = ( points[ ( i + 1 ) % 3 ] - points[ i ], points[ ( i + 2 ) % 3 ] - points[ i ] );
+                vertex_normals[ face.vertices[ i ] as usize ] += face.normal * u.angle_between( v );
+            }
+        }
+        for face in &mut faces {
+            for i in 0..3 {
+                let ( from, to ) = ( face.vertices[ i ], face.vertices[ ( i + 1 ) % 3 ] );
+                face.edge_normals[ i ] = edges[ &( from.min( to ), from.max( to ) ) ];
+            }
+        }
+
+        let face_bounds: Vec<Aabb> = faces.iter().map( | face | Aabb::from_points( &corners( &face.vertices ) ) ).collect();
+        let bounds = face_bounds.iter().fold( Aabb::EMPTY, | total, b | total.union( b ) );
+        Mesh { bvh: Bvh::new( &face_bounds ), vertices, vertex_normals, faces, bounds, material }
+    }
+
+    pub fn load<P: AsRef<Path>>( path: P, material: Arc<Material> ) -> Result<Mesh, MeshError> {
+        Ok( Mesh::new( TriangleList::load( path )?, material ) )
+    }
+
+    pub fn triangle_count( &self ) -> usize {
+        self.faces.len()
+    }
+
+    fn corners( &self, face: &Face ) -> [Vec3; 3] {
+        face.vertices.map( | i | self.vertices[ i as usize ] )
+    }
+}
+
+impl Hittable for Mesh {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        // Far from the mesh its bounds are a good enough and much cheaper estimate
+        let outside = self.bounds.distance( pos );
+        if outside > self.bounds.size().max_element() * 0.5 {
+            return outside;
+        }
+
+        let Some( ( index, distance ) ) = self.bvh.nearest( pos, | i | {
+            let [ a, b, c ] = self.corners( &self.faces[ i ] );
+            ( closest_point( pos, a, b, c ).0 - pos ).length()
+        } ) else {
+            return f32::INFINITY;
+        };
+
+        let face = &self.faces[ index ];
+        let [ a, b, c ] = self.corners( face );
+        let ( closest, feature ) = closest_point( pos, a, b, c );
+        let pseudo_normal = match feature {
+            Feature::Face => face.normal,
+            Feature::Edge( i ) => face.edge_normals[ i ],
+            Feature::Vertex( i ) => self.vertex_normals[ face.vertices[ i ] as usize ]
+        };
+        if ( pos - closest ).dot( pseudo_normal ) < 0. { -distance } else { distance }
+    }
+
+    fn bounds( &self ) -> Aabb {
+        self.bounds
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// The point of triangle abc closest to p and the feature it lies on.
+// Real-Time Collision Detection by Christer Ericson, section 5.1.5
+fn closest_point( p: Vec3, a: Vec3, b: Vec3, c: Vec3 ) -> ( Vec3, Feature ) {
+    let ( ab, ac, ap ) = ( b - a, c - a, p - a );
+    let ( d1, d2 ) = ( ab.dot( ap ), ac.dot( ap ) );
+    if d1 <= 0. && d2 <= 0. {
+        return ( a, Feature::Vertex( 0 ) );
+    }
+
+    let bp = p - b;
+    let ( d3, d4 ) = ( ab.dot( bp ), ac.dot( bp ) );
+    if d3 >= 0. && d4 <= d3 {
+        return ( b, Feature::Vertex( 1 ) );
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0. && d1 >= 0. && d3 <= 0. {
+        return ( a + ab * ( d1 / ( d1 - d3 ) ), Feature::Edge( 0 ) );
+    }
+
+    let cp = p - c;
+    let ( d5, d6 ) = ( ab.dot( cp ), ac.dot( cp ) );
+    if d6 >= 0. && d5 <= d6 {
+        return ( c, Feature::Vertex( 2 ) );
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0. && d2 >= 0. && d6 <= 0. {
+        return ( a + ac * ( d2 / ( d2 - d6 ) ), Feature::Edge( 2 ) );
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0. && d4 - d3 >= 0. && d5 - d6 >= 0. {
+        return ( b + ( c - b ) * ( ( d4 - d3 ) / ( ( d4 - d3 ) + ( d5 - d6 ) ) ), Feature::Edge( 1 ) );
+    }
+
+    let denominator = 1. / ( va + vb + vc );
+    ( a + ab * ( vb * denominator ) + ac * ( vc * denominator ), Feature::Face )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use glam::Vec3;
+    use super::{closest_point, parse_obj, parse_ply, Feature, Mesh, MeshError, TriangleList};
+    use crate::material::Material;
+    use crate::rays::Hittable;
+
+    // A cube from -1 to 1 with its faces as quads
+    const CUBE_OBJ: &str = "
+        # cube
+        o cube
+        v -1 -1 -1
+        v  1 -1 -1
+        v  1  1 -1
+        v -1  1 -1
+        v -1 -1  1
+        v  1 -1  1
+        v  1  1  1
+        v -1  1  1
+        vn 0 0 -1
+        f 1//1 4//1 3//1 2//1
+        f 5 6 7 8
+        f 1 2 6 5
+        f 4 8 7 3
+        f 1 5 8 4
+        f -7 -6 -2 -3
+    ";
+
+    fn cube() -> Mesh {
+        Mesh::new( parse_obj( CUBE_OBJ ).unwrap(), Arc::new( Material::lambertian( Vec3::ONE ) ) )
+    }
+
+    #[test]
+    fn closest_features() {
+        let ( a, b, c ) = ( Vec3::ZERO, Vec3::X, Vec3::Y );
+        let ( point, feature ) = closest_point( Vec3::new( 0.2, 0.2, 1. ), a, b, c );
+        assert!( ( point - Vec3::new( 0.2, 0.2, 0. ) ).length() < 1e-6 && feature == Feature::Face );
+        assert_eq!( closest_point( Vec3::new( 0.5, -1., 0. ), a, b, c ), ( Vec3::new( 0.5, 0., 0. ), Feature::Edge( 0 ) ) );
+        assert_eq!( closest_point( Vec3::new( 1., 1., 0. ), a, b, c ), ( Vec3::new( 0.5, 0.5, 0. ), Feature::Edge( 1 ) ) );
+        assert_eq!( closest_point( Vec3::new( -1., -1., 0. ), a, b, c ), ( a, Feature::Vertex( 0 ) ) );
+    }
+
+    #[test]
+    fn cube_distance() {
+        let cube = cube();
+        assert_eq!( cube.triangle_count(), 12 );
+        assert_eq!( cube.distance( Vec3::ZERO ), -1. );
+        assert!( ( cube.distance( Vec3::new( 0.5, 0.2, 0.8 ) ) + 0.2 ).abs() < 1e-6 );
+        assert_eq!( cube.distance( Vec3::new( 3., 0.5, 0. ) ), 2. );
+        // Outside past an edge and past a corner, where the sign comes from the pseudo normals
+        assert!( ( cube.distance( Vec3::new( 2., 2., 0.3 ) ) - 2f32.sqrt() ).abs() < 1e-6 );
+        assert!( ( cube.distance( Vec3::splat( -2. ) ) - 3f32.sqrt() ).abs() < 1e-6 );
+        assert_eq!( cube.bounds().max, Vec3::ONE );
+    }
+
+    #[test]
+    fn ply_formats() {
+        let header = | format: &str | format!( "ply\nformat {} 1.0\ncomment a square\nelement vertex 4\nproperty float x\nproperty float y\nproperty float z\nproperty uchar red\nelement face 1\nproperty list uchar int vertex_indices\nend_header\n", format );
+        let expected = TriangleList {
+            vertices: vec![ Vec3::ZERO, Vec3::X, Vec3::new( 1., 1., 0. ), Vec3::Y ],
+            triangles: vec![ [ 0, 1, 2 ], [ 0, 2, 3 ] ]
+        };
+
+        let ascii = header( "ascii" ) + "0 0 0 255\n1 0 0 255\n1 1 0 255\n0 1 0 255\n4 0 1 2 3\n";
+        assert_eq!( parse_ply( ascii.as_bytes() ).unwrap(), expected );
+
+        for ( format, big_endian ) in [ ( "binary_little_endian", false ), ( "binary_big_endian", true ) ] {
+            let mut data = header( format ).into_bytes();
+            for vertex in &expected.vertices {
+                for c in vertex.to_array() {
+                    data.extend( if big_endian { c.to_be_bytes() } else { c.to_le_bytes() } );
+                }
+                data.push( 255 );
+            }
+            data.push( 4 );
+            for i in 0..4i32 {
+                data.extend( if big_endian { i.to_be_bytes() } else { i.to_le_bytes() } );
+            }
+            assert_eq!( parse_ply( &data ).unwrap(), expected );
+        }
+
+        assert!( matches!( parse_ply( b"ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nend_header\n" ), Err( MeshError::Parse { .. } ) ) );
+    }
+
+    #[test]
+    fn obj_errors() {
+        assert!( matches!( parse_obj( "v 0 0 0\nv 1 0 0\nf 1 2 3\n" ), Err( MeshError::Parse { line: 3, .. } ) ) );
+        assert!( matches!( parse_obj( "v 0 0\n" ), Err( MeshError::Parse { line: 1, .. } ) ) );
+    }
+}
@@ -1,11 +1,18 @@
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::Arc;
 use glam::{Vec3, Vec2, Vec2Swizzles, Mat4, Vec4, Vec4Swizzles};
+use crate::bvh::{Aabb, Bvh};
 use crate::camera;
+use crate::light::Light;
+use crate::material::Material;
+use crate::scene::{self, SceneError};
 
 pub struct Hit<'a> {
     pub position: Vec3,
     pub distance: f32,
     pub normal: Vec3,
-    pub shape: &'a Box< dyn Hittable >,
+    pub shape: &'a dyn Hittable,
     pub bounces: u32,
     pub cum_length: f32,
     pub weight: f32
@@ -15,7 +22,7 @@ pub struct Miss {
     pub position: Vec3,
     pub bounces: u32,
     pub cum_length: f32,
-    weight: f32
+    pub weight: f32
 }
 
 pub enum CastResult<'a> {
@@ -23,28 +30,184 @@ pub enum CastResult<'a> {
     Miss( Miss )
 }
 
-pub struct Material {
-    pub color: Vec3,
-    pub reflective: bool
+#[derive( Clone, Copy, Debug )]
+pub struct MarchSettings {
+    // Reflections a path may make before it is dropped
+    pub max_bounces: u32,
+    // Marching steps per segment, a segment that runs out of steps is a miss
+    pub max_steps: u32,
+    // Marching steps summed over all segments of a path
+    pub max_path_steps: u32,
+    // Total length of a path, summed over all segments
+    pub max_distance: f32,
+    // Steps are the distance times this factor, from 1 for plain sphere tracing up to below 2. A step that may have
+    // jumped over a surface is taken back and marching goes on with plain steps.
+    // https://erleuchtet.org/~cupe/permanent/enhanced_sphere_tracing.pdf
+    pub relaxation: f32,
+    // Growth of the hit distance per unit of path length, about the angle a pixel covers. Far away surfaces are hit
+    // once the ray is within the pixel's footprint instead of crawling towards them. 0 always uses EPSILON.
+    pub pixel_angle: f32
+}
+
+impl Default for MarchSettings {
+    fn default() -> Self {
+        MarchSettings { max_bounces: 500, max_steps: 500, max_path_steps: 100_000, max_distance: 500., relaxation: 1.6, pixel_angle: 0. }
+    }
+}
+
+impl MarchSettings {
+    // Distance below which a ray that has come this far along its path hits a surface
+    pub fn hit_distance( &self, path_length: f32 ) -> f32 {
+        f32::max( EPSILON, self.pixel_angle * path_length )
+    }
 }
 
-pub trait Hittable {
+pub enum SegmentEnd<'a> {
+    // Came within the hit distance of the surface of this shape
+    Surface( &'a dyn Hittable ),
+    // Went past the maximum length, holds the closest shape at the last step
+    Escaped( Option<&'a dyn Hittable> ),
+    OutOfSteps
+}
+
+// A straight part of a path, from its origin up to a surface or up to where marching stopped
+pub struct Segment<'a> {
+    pub origin: Vec3,
+    pub direction: Vec3,
+    pub length: f32,
+    pub steps: u32,
+    pub end: SegmentEnd<'a>
+}
+
+impl Segment<'_> {
+    pub fn position( &self ) -> Vec3 {
+        self.origin + self.direction * self.length
+    }
+}
+
+#[derive( Clone, Copy, Debug, PartialEq, Eq )]
+pub enum Termination {
+    // Ended on a surface that does not reflect
+    Surface,
+    Escaped,
+    OutOfSteps,
+    BounceLimit,
+    StepBudget
+}
+
+pub struct RayPath<'a> {
+    pub segments: Vec<Segment<'a>>,
+    pub termination: Termination
+}
+
+impl RayPath<'_> {
+    pub fn bounces( &self ) -> u32 {
+        self.segments.len() as u32 - 1
+    }
+
+    pub fn length( &self ) -> f32 {
+        self.segments.iter().map( | segment | segment.length ).sum()
+    }
+
+    pub fn steps( &self ) -> u32 {
+        self.segments.iter().map( | segment | segment.steps ).sum()
+    }
+}
+
+pub trait Hittable: Send + Sync {
     fn distance( &self, pos: Vec3 ) -> f32;
     fn material( &self ) -> & Material;
-    fn calc_normal(&self, pos: Vec3 ) -> Vec3 {
-        let h = 0.0001;
-        let k = Vec2::new( 1.,-1. );
-        ( k.xyy() * self.distance( pos + k.xyy() * h )
-        + k.yyx() * self.distance( pos + k.yyx() * h )
-        + k.yxy() * self.distance( pos + k.yxy() * h )
-        + k.xxx() * self.distance( pos + k.xxx() * h ) ).normalize()
+    // The material of the surface at pos, for shapes made of several materials
+    fn material_at( &self, _pos: Vec3 ) -> Cow<'_, Material> {
+        Cow::Borrowed( self.material() )
+    }
+    // Box containing the shape, `distance` must never be smaller than the distance to these bounds
+    fn bounds( &self ) -> Aabb {
+        Aabb::INFINITE
+    }
+    // Direction in which the distance grows fastest, for shapes that can work it out directly. Shapes that
+    // return None get normals from finite differences instead.
+    fn gradient( &self, _pos: Vec3 ) -> Option<Vec3> {
+        None
+    }
+    fn calc_normal( &self, pos: Vec3 ) -> Vec3 {
+        self.gradient( pos ).and_then( Vec3::try_normalize ).unwrap_or_else( || numeric_normal( self, pos ) )
+    }
+    // A value from the orbit of pos under a fractal's iteration for coloring, None for shapes that are not fractals
+    fn orbit_trap( &self, _pos: Vec3 ) -> Option<f32> {
+        None
+    }
+    // Bounds of the emissive parts of the shape, which become lights of the world. Shapes made of other shapes
+    // report the emitters among them.
+    fn emitters( &self ) -> Vec<Aabb> {
+        if self.material().emission != Vec3::ZERO { vec![ self.bounds() ] } else { vec![] }
+    }
+}
+
+// Normal from the distances at the corners of a small tetrahedron around pos. The tetrahedron grows with the
+// distance from the origin, as the spacing between floats does, so far away surfaces still get clean normals.
+pub fn numeric_normal<H: Hittable + ?Sized>( shape: &H, pos: Vec3 ) -> Vec3 {
+    let h = f32::max( 0.0001, pos.abs().max_element() * 0.00001 );
+    let k = Vec2::new( 1.,-1. );
+    ( k.xyy() * shape.distance( pos + k.xyy() * h )
+    + k.yyx() * shape.distance( pos + k.yyx() * h )
+    + k.yxy() * shape.distance( pos + k.yxy() * h )
+    + k.xxx() * shape.distance( pos + k.xxx() * h ) ).normalize()
+}
+
+// Gradient of the distance to a box with half extents `size` centered on the origin, q is abs( pos ) - size
+pub( crate ) fn box_gradient( pos: Vec3, q: Vec3 ) -> Vec3 {
+    let sign = Vec3::ONE.copysign( pos );
+    if q.max_element() > 0. {
+        q.max( Vec3::ZERO ) * sign
+    } else if q.x >= q.y && q.x >= q.z {
+        Vec3::new( sign.x, 0., 0. )
+    } else if q.y >= q.z {
+        Vec3::new( 0., sign.y, 0. )
+    } else {
+        Vec3::new( 0., 0., sign.z )
+    }
+}
+
+// Lets boxed shapes, like the ones in a scene, be used where a shape type is expected
+impl<H: Hittable + ?Sized> Hittable for Box<H> {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        self.as_ref().distance( pos )
+    }
+
+    fn material( &self ) -> & Material {
+        self.as_ref().material()
+    }
+
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        self.as_ref().material_at( pos )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        self.as_ref().bounds()
+    }
+
+    fn gradient( &self, pos: Vec3 ) -> Option<Vec3> {
+        self.as_ref().gradient( pos )
+    }
+
+    fn calc_normal( &self, pos: Vec3 ) -> Vec3 {
+        self.as_ref().calc_normal( pos )
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        self.as_ref().orbit_trap( pos )
+    }
+
+    fn emitters( &self ) -> Vec<Aabb> {
+        self.as_ref().emitters()
     }
 }
 
-struct Sphere {
-    position: Vec3,
-    radius: f32,
-    material: Material
+pub struct Sphere {
+    pub position: Vec3,
+    pub radius: f32,
+    pub material: Arc<Material>
 }
 
 impl Hittable for Sphere {
@@ -52,16 +215,24 @@ impl Hittable for Sphere {
         (pos - self.position).length() - self.radius
     }
 
+    fn gradient( &self, pos: Vec3 ) -> Option<Vec3> {
+        Some( pos - self.position )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        Aabb::new( self.position - self.radius, self.position + self.radius )
+    }
+
     fn material( &self ) -> & Material {
         & self.material
     }
 }
 
-struct Wall {
-    position: Vec3,
-    rotation: Mat4,
-    size: Vec3,
-    material: Material
+pub struct Wall {
+    pub position: Vec3,
+    pub rotation: Mat4,
+    pub size: Vec3,
+    pub material: Arc<Material>
 }
 
 impl Hittable for Wall {
@@ -73,170 +244,293 @@ impl Hittable for Wall {
         q.max( Vec3::ZERO ).length() + q.max_element().min( 0. )
     }
 
+    // The box gradient in its rotated frame, turned back by the transpose of the rotation
+    fn gradient( &self, pos: Vec3 ) -> Option<Vec3> {
+        let rp = self.rotation.transform_point3( pos - self.position );
+        Some( self.rotation.transpose().transform_vector3( box_gradient( rp, rp.abs() - self.size ) ) )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let to_world = self.rotation.inverse();
+        let corners = Aabb::new( -self.size, self.size ).corners().map( | c | to_world.transform_point3( c ) + self.position );
+        Aabb::from_points( &corners )
+    }
+
     fn material( &self ) -> & Material {
         & self.material
     }
 }
 
-// struct Triangle {
-//     a: Vec3,
-//     b: Vec3,
-//     c: Vec3,
-//     material: Material
-// }
-//
-// fn dot2( v: Vec3 ) -> f32 {
-//     v.dot(v)
-// }
-//
-// impl Hittable for Triangle {
-//     fn distance( &self, pos: Vec3 ) -> f32 {
-//         let ba = self.b - self.a; let pa = pos - self.a;
-//         let cb = self.c - self.b; let pb = pos - self.b;
-//         let ac = self.a - self.c; let pc = pos - self.c;
-//         let nor = ba.cross(ac);
-//
-//         let s = f32::signum(ba.cross(nor).dot(pa)) +
-//                 f32::signum(cb.cross(nor).dot(pb)) +
-//                 f32::signum(ac.cross(nor).dot(pc)) < 2.0;
-//
-//         return f32::sqrt(
-//             ( f32::signum(ba.cross(nor).dot(pa)) +
-//                 f32::signum(cb.cross(nor).dot(pb)) +
-//                 f32::signum(ac.cross(nor).dot(pc)) < 2.0 ) ?
-//             f32::min( f32::min(
-//                 dot2(ba*clamp(dot(ba,pa)/dot2(ba),0.0,1.0)-pa),
-//                 dot2(cb*clamp(dot(cb,pb)/dot2(cb),0.0,1.0)-pb) ),
-//                  dot2(ac*clamp(dot(ac,pc)/dot2(ac),0.0,1.0)-pc) )
-//             :
-//                 nor.dor(pa)*nor.dot(pa)/dot2(nor) );
-//     }
-//
-//     fn material( &self ) -> & Material {
-//         & self.material
-//     }
-// }
+pub const EPSILON: f32 = 0.0001;
+
+// Marching steps for a shadow query before the light is assumed to be visible
+const SHADOW_STEPS: u32 = 256;
 
 pub struct World {
-    content: Vec<Box< dyn Hittable>>
+    content: Vec<Box< dyn Hittable>>,
+    bvh: Bvh,
+    // Radiance of rays that escape the scene
+    pub background: Vec3,
+    pub lights: Vec<Light>
 }
 
-fn reflect( a: Vec3, n: Vec3 ) -> Vec3 {
+pub fn reflect( a: Vec3, n: Vec3 ) -> Vec3 {
     let reverse_a = -a;
     reverse_a - 2. * reverse_a.dot( n ) * n
 }
 
 impl World {
-    pub fn new() -> World {
-        World {
-            content: vec![
-                Box::new( Wall { position: Vec3::new( 0., -10., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 100., 0.1, 100. ), material: Material { color: Vec3::new( 245. / 255., 243. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Wall { position: Vec3::new( 0., 10., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 100., 0.1, 100. ), material: Material { color: Vec3::new( 245. / 255., 243. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Wall { position: Vec3::new( 0., 0., 10. ), rotation: Mat4::IDENTITY, size: Vec3::new( 100., 100., 0.1 ), material: Material { color: Vec3::new( 245. / 255., 243. / 255., 193. / 255. ), reflective: true } } ),
-                // Box::new( Wall { position: Vec3::new( 0., 0., -10. ), rotation:Mat4::look_at_rh( Vec3::new( 0.5, 0.5, 0.5).normalize(), Vec3::ZERO, Vec3::new(0.,1.,0.) ), size: Vec3::new( 100., 100., 0.1 ), material: Material { color: Vec3::new( 245. / 255., 243. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Wall { position: Vec3::new( 0., 0., -10. ), rotation: Mat4::IDENTITY, size: Vec3::new( 100., 100., 0.1 ), material: Material { color: Vec3::new( 245. / 255., 243. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Wall { position: Vec3::new( -10.0, 0., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 0.1, 100., 100. ), material: Material { color: Vec3::new( 245. / 255., 243. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Wall { position: Vec3::new( 10., 0., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 0.1, 100., 100. ), material: Material { color: Vec3::new( 245. / 255., 243. / 255., 193. / 255. ), reflective: true } } ),
-                // Box::new( Wall { position: Vec3::new( 10., 0., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 1.5, 1.5, 1.5 ), material: Material { color: Vec3::new( 245. / 255., 243. / 255., 193. / 255. ), reflective: true } } ),
-                // Box::new( Wall { position: Vec3::new( 3.4, 0., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 0.1, 100., 100. ), material: Material { color: Vec3::new( 245. / 255., 243. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Sphere { position: Vec3::new( 0., 0., 0. ), radius: 1.0, material: Material { color: Vec3::new( 39. / 255., 225. / 255., 193. / 255. ), reflective: true } } ),
-                // Box::new( Wall { position: Vec3::new( 0., 0., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 0.7, 0.7, 0.7 ), material: Material { color: Vec3::new( 245. / 255., 243. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Sphere { position: Vec3::new( -10., -10., -10. ), radius: 5.0, material: Material { color: Vec3::new( 39. / 255., 225. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Sphere { position: Vec3::new( 10., -10., 10. ), radius: 5.0, material: Material { color: Vec3::new( 39. / 255., 225. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Sphere { position: Vec3::new( -10., 10., 10. ), radius: 5.0, material: Material { color: Vec3::new( 39. / 255., 225. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Sphere { position: Vec3::new( 10., 10., -10. ), radius: 5.0, material: Material { color: Vec3::new( 39. / 255., 225. / 255., 193. / 255. ), reflective: true } } ),
-
-                Box::new( Sphere { position: Vec3::new( 10., 10., 10. ), radius: 5.0, material: Material { color: Vec3::new( 39. / 255., 225. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Sphere { position: Vec3::new( -10., 10., -10. ), radius: 5.0, material: Material { color: Vec3::new( 39. / 255., 225. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Sphere { position: Vec3::new( 10., -10., -10. ), radius: 5.0, material: Material { color: Vec3::new( 39. / 255., 225. / 255., 193. / 255. ), reflective: true } } ),
-                Box::new( Sphere { position: Vec3::new( -10., -10., 10. ), radius: 5.0, material: Material { color: Vec3::new( 39. / 255., 225. / 255., 193. / 255. ), reflective: true } } ),
-            ]
+    pub fn new( content: Vec<Box<dyn Hittable>> ) -> World {
+        let bounds: Vec<Aabb> = content.iter().map( | shape | shape.bounds() ).collect();
+        // Every emissive shape is a light, so it can be sampled directly. Emitters without finite bounds can't be
+        // sampled, the path tracer adds their emission wherever its rays hit them.
+        let lights = content.iter().enumerate()
+            .flat_map( | ( index, shape ) | shape.emitters().into_iter().filter( Aabb::is_finite ).map( move | bounds | Light::Shape { index, bounds } ) )
+            .collect();
+        World { content, bvh: Bvh::new( &bounds ), background: Vec3::ZERO, lights }
+    }
+
+    // Load the shapes of a scene description file, see `scene::load` for the format.
+    pub fn from_file<P: AsRef<Path>>( path: P ) -> Result<World, SceneError> {
+        scene::load( path ).map( | scene | scene.world )
+    }
+
+    pub fn content( &self ) -> &[Box<dyn Hittable>] {
+        &self.content
+    }
+
+    // The shape closest to pos and its distance
+    pub fn nearest( &self, pos: Vec3 ) -> Option<( &dyn Hittable, f32 )> {
+        self.bvh.nearest( pos, | i | self.content[ i ].distance( pos ) )
+            .map( | ( i, dist ) | ( self.content[ i ].as_ref(), dist ) )
+    }
+
+    // Whether anything blocks the straight line from origin up to distance
+    pub fn occluded( &self, origin: Vec3, direction: Vec3, distance: f32, settings: &MarchSettings ) -> bool {
+        let segment = self.march( origin, direction, f32::min( distance, settings.max_distance ), settings.max_steps );
+        !matches!( segment.end, SegmentEnd::Escaped( _ ) )
+    }
+
+    // Visibility in [0, 1] along the line from origin up to max_t, with a penumbra estimated from how closely the
+    // march passes by other surfaces. Larger k gives harder shadows.
+    // https://iquilezles.org/articles/rmshadows/
+    pub fn shadow( &self, origin: Vec3, direction: Vec3, max_t: f32, k: f32 ) -> f32 {
+        let mut visibility: f32 = 1.;
+        let mut t = 0.;
+        for _ in 0..SHADOW_STEPS {
+            if t >= max_t {
+                break;
+            }
+            let Some( ( _, dist ) ) = self.nearest( origin + direction * t ) else { break };
+            if dist < EPSILON {
+                return 0.;
+            }
+            // A surface this close to the ray relative to how far it got would cover part of a light of angular
+            // size about 1 / k
+            visibility = visibility.min( k * dist / f32::max( EPSILON, t ) );
+            t += dist;
+        }
+        visibility.clamp( 0., 1. )
+    }
+
+    // How open the surface at pos is in [0, 1], 0 being fully occluded. Compares the distance at a few points along
+    // the normal with how far they are from the surface, closer points weigh more.
+    // https://iquilezles.org/articles/nvscene2008/rwwtt.pdf
+    pub fn ambient_occlusion( &self, pos: Vec3, normal: Vec3, steps: u32, step_size: f32 ) -> f32 {
+        let mut occlusion = 0.;
+        let mut weight = 1.;
+        let mut total = 0.;
+        for i in 1..=steps {
+            let h = step_size * i as f32;
+            let dist = self.nearest( pos + normal * h ).map_or( h, | ( _, dist ) | dist );
+            occlusion += weight * ( h - dist ).max( 0. ) / h;
+            total += weight;
+            weight *= 0.5;
+        }
+        if total == 0. {
+            return 1.;
         }
+        ( 1. - occlusion / total ).clamp( 0., 1. )
     }
 
-    pub fn cast( &self, ray: camera::Ray, max_distance: f32 ) -> Option< CastResult > {
+    // March a single straight segment from origin until it comes within EPSILON of a surface, travels further than
+    // max_length or runs out of steps. Takes plain sphere tracing steps.
+    pub fn march( &self, origin: Vec3, direction: Vec3, max_length: f32, max_steps: u32 ) -> Segment<'_> {
+        let plain = MarchSettings { max_distance: max_length, relaxation: 1., pixel_angle: 0., ..MarchSettings::default() };
+        self.march_with( origin, direction, 0., max_steps, &plain )
+    }
 
-        if self.content.len() == 0 {
-            return None;
+    // Like `march`, with the step relaxation and hit distance of `settings`. The segment continues a path that has
+    // travelled path_length so far, which counts towards the maximum distance and the pixel footprint.
+    pub fn march_with( &self, origin: Vec3, direction: Vec3, path_length: f32, max_steps: u32, settings: &MarchSettings ) -> Segment<'_> {
+        let max_length = settings.max_distance - path_length;
+        let mut relaxation = settings.relaxation.clamp( 1., 1.99 );
+        let ( mut previous_t, mut previous_dist ) = ( 0., 0. );
+        let mut t = 0.;
+        let mut end = SegmentEnd::OutOfSteps;
+        let mut steps = 0;
+        while steps < max_steps {
+            steps += 1;
+            let Some( ( shape, min_dist ) ) = self.nearest( origin + direction * t ) else {
+                end = SegmentEnd::Escaped( None );
+                break;
+            };
+
+            // The empty spheres around this point and the previous one do not overlap, so there may be a surface in
+            // the gap. Go back to a plain step from the previous point.
+            if relaxation > 1. && min_dist.abs() + previous_dist < t - previous_t {
+                relaxation = 1.;
+                t = previous_t + previous_dist;
+                continue;
+            }
+            previous_t = t;
+            previous_dist = min_dist;
+
+            let hit = min_dist < settings.hit_distance( path_length + t );
+            t += if hit { min_dist } else { min_dist * relaxation };
+            if t > max_length {
+                end = SegmentEnd::Escaped( Some( shape ) );
+                break;
+            }
+
+            if hit {
+                end = SegmentEnd::Surface( shape );
+                break;
+            }
         }
 
+        Segment { origin, direction, length: t, steps, end }
+    }
+
+    // Like `march`, but from a point inside of shape up to where the ray leaves it again. Only the shape itself is
+    // considered, its negated distance is the distance to its boundary.
+    pub fn march_inside<'a>( &'a self, shape: &'a dyn Hittable, origin: Vec3, direction: Vec3, max_length: f32, max_steps: u32 ) -> Segment<'a> {
         let mut t = 0.;
-        for _i in 0..500 {
-            let mut closest_shape = None;
-            let mut min_dist = f32::MAX;
-            for shape in &self.content {
-                let dist = shape.distance( ray.origin + ray.direction * t );
-                if dist < min_dist {
-                    min_dist = dist;
-                    closest_shape = Some( shape );
-                }
+        let mut end = SegmentEnd::OutOfSteps;
+        let mut steps = 0;
+        while steps < max_steps {
+            steps += 1;
+            let dist = -shape.distance( origin + direction * t );
+            if dist < EPSILON {
+                end = SegmentEnd::Surface( shape );
+                break;
             }
 
-            t += min_dist;
-            if ray.cum_length + t > max_distance {
-                return Some( CastResult::Hit( Hit {
-                    position: ray.origin + ray.direction * t,
-                    distance: t,
-                    normal: Vec3::ZERO,
-                    shape: closest_shape.unwrap(),
-                    bounces: ray.reflect_count,
-                    cum_length: ray.cum_length + t,
-                    weight: ray.weigth
-                } ) );
+            t += dist;
+            if t > max_length {
+                end = SegmentEnd::Escaped( Some( shape ) );
+                break;
             }
+        }
 
-            let EPSILON = 0.0001;
-            if min_dist < EPSILON {
+        Segment { origin, direction, length: t, steps, end }
+    }
 
-                let shape = closest_shape.unwrap();
-                if shape.material().reflective {
-                    // We hit something reflective
+    // Follow a ray through all of its reflections. Every reflection starts a new segment, the path ends when a segment
+    // does not end on a mirror or when one of the limits in `settings` is reached.
+    pub fn trace( &self, ray: &camera::Ray, settings: &MarchSettings ) -> RayPath<'_> {
+        let mut segments = vec![];
+        let mut origin = ray.origin;
+        let mut direction = ray.direction;
+        let mut length = ray.cum_length;
+        let mut steps = 0;
 
-                    // Gotta save the stack somehow
-                    if ray.reflect_count > 500 {
-                        return None;
-                    }
+        let termination = loop {
+            let budget = u32::min( settings.max_steps, settings.max_path_steps - steps );
+            let segment = self.march_with( origin, direction, length, budget, settings );
+            steps += segment.steps;
+            length += segment.length;
 
-                    // Reflect around the normal
-                    let position = ray.origin + ray.direction * t;
-                    let normal = shape.calc_normal( position );
-                    let direction = reflect( -ray.direction, normal ).normalize();
-                    return self.cast(
-                        camera::Ray {
-                            origin: position + direction * EPSILON * 2.,
-                            direction,
-                            reflect_count: ray.reflect_count + 1,
-                            cum_length: ray.cum_length + t,
-                            weigth: ray.weigth + 1. // f32::sin( t )
-                        },
-                        max_distance
-                    );
-                }
+            let termination = match segment.end {
+                SegmentEnd::Surface( shape ) if shape.material_at( segment.position() ).is_mirror() => {
+                    if segments.len() as u32 >= settings.max_bounces {
+                        Some( Termination::BounceLimit )
+                    } else {
+                        // Reflect around the normal and continue from just above the surface
+                        let position = segment.position();
+                        direction = reflect( -segment.direction, shape.calc_normal( position ) ).normalize();
+                        origin = position + direction * settings.hit_distance( length ) * 2.;
+                        None
+                    }
+                },
+                SegmentEnd::Surface( _ ) => Some( Termination::Surface ),
+                SegmentEnd::Escaped( _ ) => Some( Termination::Escaped ),
+                SegmentEnd::OutOfSteps if steps >= settings.max_path_steps => Some( Termination::StepBudget ),
+                SegmentEnd::OutOfSteps => Some( Termination::OutOfSteps )
+            };
 
-                return Some( CastResult::Hit( Hit {
-                    position: ray.origin + ray.direction * t,
-                    distance: t,
-                    normal: shape.calc_normal( ray.origin + ray.direction * t ),
-                    shape: closest_shape.unwrap(),
-                    bounces: ray.reflect_count,
-                    cum_length: ray.cum_length + t,
-                    weight: ray.weigth
-                } ) );
+            segments.push( segment );
+            if let Some( termination ) = termination {
+                break termination;
             }
+        };
+
+        RayPath { segments, termination }
+    }
+
+    pub fn cast( &self, ray: camera::Ray, settings: &MarchSettings ) -> Option< CastResult<'_> > {
+
+        if self.content.is_empty() {
+            return None;
         }
 
-        return Some( CastResult::Miss( Miss {
-            bounces: ray.reflect_count,
-            cum_length: ray.cum_length + t,
-            position: ray.origin + ray.direction * t,
-            weight: ray.weigth
-        } ) );
+        let path = self.trace( &ray, settings );
+        let last = path.segments.last()?;
+        let bounces = ray.reflect_count + path.bounces();
+        let cum_length = ray.cum_length + path.length();
+        let weight = ray.weigth + path.bounces() as f32;
+
+        match ( path.termination, &last.end ) {
+            ( Termination::Surface, SegmentEnd::Surface( shape ) ) => Some( CastResult::Hit( Hit {
+                position: last.position(),
+                distance: last.length,
+                normal: shape.calc_normal( last.position() ),
+                shape: *shape,
+                bounces,
+                cum_length,
+                weight
+            } ) ),
+            // Rays that travel too far report the shape that was closest when they gave up
+            ( Termination::Escaped, SegmentEnd::Escaped( Some( shape ) ) ) => Some( CastResult::Hit( Hit {
+                position: last.position(),
+                distance: last.length,
+                normal: Vec3::ZERO,
+                shape: *shape,
+                bounces,
+                cum_length,
+                weight
+            } ) ),
+            ( Termination::BounceLimit, _ ) => None,
+            _ => Some( CastResult::Miss( Miss {
+                bounces,
+                cum_length,
+                position: last.position(),
+                weight
+            } ) )
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use glam::Vec3;
+    use glam::Mat4;
+    use std::sync::Arc;
+    use super::{numeric_normal, Hittable, MarchSettings, SegmentEnd, Sphere, Termination, Wall, World};
+    use crate::camera::Ray;
+    use crate::material::Material;
+
+    fn mirrors() -> World {
+        let mirror = Arc::new( Material::mirror( Vec3::ONE ) );
+        World::new( vec![
+            Box::new( Wall { position: Vec3::new( -2., 0., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 0.1, 100., 100. ), material: mirror.clone() } ),
+            Box::new( Wall { position: Vec3::new( 2., 0., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 0.1, 100., 100. ), material: mirror } )
+        ] )
+    }
+
+    fn ray( direction: Vec3 ) -> Ray {
+        Ray { origin: Vec3::ZERO, direction, reflect_count: 0, cum_length: 0., weigth: 0. }
+    }
 
     #[test]
     fn reflect_x_axis() {
@@ -270,4 +564,138 @@ mod tests {
         let reflected = super::reflect( -ray, n );
         assert_eq!( reflected, result );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let material = Arc::new( Material::lambertian( Vec3::ONE ) );
+        let mut content: Vec<Box<dyn Hittable>> = vec![
+            Box::new( Wall { position: Vec3::new( 0., -10., 0. ), rotation: Mat4::from_rotation_z( 0.3 ), size: Vec3::new( 100., 0.1, 100. ), material: material.clone() } )
+        ];
+        for x in -6..6_i32 {
+            for y in -6..6 {
+                for z in -6..6 {
+                    let position = Vec3::new( x as f32, y as f32, z as f32 ) * 2.3;
+                    content.push( Box::new( Sphere { position, radius: 0.2 + ( x + y + z ).rem_euclid( 5 ) as f32 * 0.3, material: material.clone() } ) );
+                }
+            }
+        }
+        let world = World::new( content );
+
+        for i in 0..2000 {
+            let t = i as f32;
+            let pos = Vec3::new( ( t * 0.37 ).sin() * 16., ( t * 0.71 ).cos() * 16., ( t * 0.13 ).sin() * 16. );
+            let expected = world.content().iter().map( | shape | shape.distance( pos ) ).fold( f32::MAX, f32::min );
+            let ( _, found ) = world.nearest( pos ).unwrap();
+            assert!( ( found - expected ).abs() < 1e-5, "{} != {} at {}", found, expected, pos );
+        }
+    }
+
+    #[test]
+    fn trace_between_parallel_mirrors() {
+        let settings = MarchSettings { max_bounces: 20_000, max_steps: 100, max_path_steps: u32::MAX, max_distance: f32::MAX, ..MarchSettings::default() };
+        let world = mirrors();
+        let path = world.trace( &ray( Vec3::X ), &settings );
+        assert_eq!( path.termination, Termination::BounceLimit );
+        assert_eq!( path.segments.len(), 20_001 );
+        assert_eq!( path.bounces(), 20_000 );
+        assert!( path.segments.iter().all( | segment | matches!( segment.end, SegmentEnd::Surface( _ ) ) ) );
+        assert!( ( path.segments[ 1 ].direction - Vec3::NEG_X ).length() < 1e-5 );
+    }
+
+    #[test]
+    fn trace_step_budget() {
+        let settings = MarchSettings { max_bounces: 20_000, max_steps: 100, max_path_steps: 50, max_distance: f32::MAX, ..MarchSettings::default() };
+        let world = mirrors();
+        let path = world.trace( &ray( Vec3::new( 1., 0.01, 0. ).normalize() ), &settings );
+        assert_eq!( path.termination, Termination::StepBudget );
+        assert_eq!( path.steps(), 50 );
+    }
+
+    #[test]
+    fn shadow_penumbra() {
+        let material = Arc::new( Material::lambertian( Vec3::ONE ) );
+        let world = World::new( vec![ Box::new( Sphere { position: Vec3::new( 0., 5., 0. ), radius: 1., material } ) ] );
+        let shadow = | x: f32 | world.shadow( Vec3::new( x, 0., 0. ), Vec3::Y, 10., 8. );
+        assert_eq!( shadow( 0. ), 0. );
+        assert_eq!( shadow( 3. ), 1. );
+        // Rays passing just outside of the sphere are partly shadowed, less so further out
+        let ( near, far ) = ( shadow( 1.1 ), shadow( 1.3 ) );
+        assert!( near > 0. && near < far && far < 1., "{} {}", near, far );
+        // Nothing blocks the ray before max_t
+        assert_eq!( world.shadow( Vec3::ZERO, Vec3::Y, 3., 8. ), 1. );
+    }
+
+    #[test]
+    fn march_inside_sphere() {
+        let material = Arc::new( Material::lambertian( Vec3::ONE ) );
+        let world = World::new( vec![ Box::new( Sphere { position: Vec3::ZERO, radius: 2., material } ) ] );
+        let shape = world.content()[ 0 ].as_ref();
+        let segment = world.march_inside( shape, Vec3::new( 0., 1., 0. ), Vec3::X, 100., 100 );
+        assert!( matches!( segment.end, SegmentEnd::Surface( _ ) ) );
+        assert!( ( segment.position().length() - 2. ).abs() < 1e-3 );
+    }
+
+    #[test]
+    fn trace_max_distance() {
+        let settings = MarchSettings { max_distance: 30., ..MarchSettings::default() };
+        let world = mirrors();
+        let path = world.trace( &ray( Vec3::X ), &settings );
+        assert_eq!( path.termination, Termination::Escaped );
+        assert!( path.length() > 30. && path.length() < 36. );
+    }
+
+    #[test]
+    fn analytic_normals() {
+        let material = Arc::new( Material::lambertian( Vec3::ONE ) );
+        let sphere = Sphere { position: Vec3::new( 1., 2., 3. ), radius: 2., material: material.clone() };
+        let wall = Wall { position: Vec3::new( 0., 1., 0. ), rotation: Mat4::from_rotation_y( 0.6 ), size: Vec3::new( 1., 0.5, 2. ), material };
+        let points = [ Vec3::new( 3., 2.5, 3.2 ), Vec3::new( -0.4, 1.6, 0.1 ), Vec3::new( 0.2, 3.1, -1.3 ), Vec3::new( 1.2, -0.2, 0.3 ) ];
+        for shape in [ &sphere as &dyn Hittable, &wall ] {
+            for pos in points {
+                let ( analytic, numeric ) = ( shape.calc_normal( pos ), numeric_normal( shape, pos ) );
+                assert!( ( analytic - numeric ).length() < 1e-2, "{} against {} at {}", analytic, numeric, pos );
+            }
+        }
+        // The gradient of the box rotates with it
+        let normal = wall.calc_normal( Vec3::new( 0., 1., 0. ) + Mat4::from_rotation_y( -0.6 ).transform_vector3( Vec3::new( 1., 0., 0. ) ) );
+        assert!( ( normal - Mat4::from_rotation_y( -0.6 ).transform_vector3( Vec3::X ) ).length() < 1e-5, "{}", normal );
+    }
+
+    #[test]
+    fn numeric_normal_far_away() {
+        // A fixed step of 0.0001 is below the float spacing this far out and gives garbage
+        let material = Arc::new( Material::lambertian( Vec3::ONE ) );
+        let sphere = Sphere { position: Vec3::new( 20000., 0., 0. ), radius: 1000., material };
+        let pos = Vec3::new( 20000., 0., 0. ) + Vec3::new( 1., 1., 0. ).normalize() * 1000.;
+        let normal = numeric_normal( &sphere, pos );
+        assert!( ( normal - Vec3::new( 1., 1., 0. ).normalize() ).length() < 1e-3, "{}", normal );
+    }
+
+    #[test]
+    fn relaxed_march() {
+        let material = Arc::new( Material::lambertian( Vec3::ONE ) );
+        // A thin wall that a relaxed step from the origin jumps over
+        let thin = World::new( vec![ Box::new( Wall { position: Vec3::new( 3., 0., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 0.01, 1., 1. ), material: material.clone() } ) ] );
+        let segment = thin.march_with( Vec3::ZERO, Vec3::X, 0., 100, &MarchSettings::default() );
+        assert!( matches!( segment.end, SegmentEnd::Surface( _ ) ) );
+        assert!( ( segment.length - 2.99 ).abs() < 1e-3, "{}", segment.length );
+
+        // Along a wall the plain steps stay short, relaxed ones get to the sphere at the end sooner
+        let world = World::new( vec![
+            Box::new( Wall { position: Vec3::new( 10., -1., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 10., 0.5, 1. ), material: material.clone() } ),
+            Box::new( Sphere { position: Vec3::new( 25., 0., 0. ), radius: 1., material } )
+        ] );
+        let plain = world.march_with( Vec3::ZERO, Vec3::X, 0., 500, &MarchSettings { relaxation: 1., ..MarchSettings::default() } );
+        let relaxed = world.march_with( Vec3::ZERO, Vec3::X, 0., 500, &MarchSettings::default() );
+        assert!( ( plain.length - 24. ).abs() < 1e-3 && ( relaxed.length - 24. ).abs() < 1e-3 );
+        assert!( relaxed.steps < plain.steps, "{} against {}", relaxed.steps, plain.steps );
+
+        // A hit distance that grows along the path ends grazing rays sooner, a little further from the surface
+        let direction = Vec3::new( 1., -0.05, 0. ).normalize();
+        let fine = world.march_with( Vec3::ZERO, direction, 0., 500, &MarchSettings::default() );
+        let footprint = world.march_with( Vec3::ZERO, direction, 0., 500, &MarchSettings { pixel_angle: 0.002, ..MarchSettings::default() } );
+        assert!( matches!( footprint.end, SegmentEnd::Surface( _ ) ) );
+        assert!( footprint.steps < fine.steps && ( footprint.length - fine.length ).abs() < 0.5, "{} {}", footprint.steps, fine.steps );
+        assert_eq!( MarchSettings::default().hit_distance( 1000. ), super::EPSILON );
+    }
+}
@@ -0,0 +1,373 @@
+use std::f32::consts::TAU;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use glam::{Vec3, Vec4};
+use crate::camera::{Camera, Ray};
+use crate::image::Framebuffer;
+use crate::integrator::{AmbientOcclusion, DirectLighting, PathTracer};
+use crate::rays::{CastResult, MarchSettings, World, EPSILON};
+use crate::sampling::Rng;
+
+pub fn color_palette( t: f32, a: Vec3, b: Vec3, c: Vec3, d: Vec3 ) -> Vec3 {
+    a + b * Vec3::new( f32::cos( TAU * ( c.x * t + d.x ) ), f32::cos( TAU * ( c.y * t + d.y ) ), f32::cos( TAU * ( c.z * t + d.z ) ) )
+}
+
+pub fn calc_pixel( ray: Ray, world: & World, march: & MarchSettings ) -> Vec3 {
+
+    let castresult = world.cast( ray, march );
+
+    let mut col = Vec3::new( 0.2, 0.2, 0.2 );
+    if let Some( castresult ) = castresult {
+        match castresult {
+            CastResult::Hit( hit ) => {
+                // col = hit.shape.material().color * ( 1. - hit.bounces as f32 / 25. );
+                // col = color_palette( hit.bounces as f32 / 1.1 + 1.2 + hit.distance / 2.5, Vec3::new( 0.5, 0.5, 0.5 ), Vec3::new( 0.5, 0.5, 0.5 ), Vec3::new( 1.0, 0.6, 0.3 ), Vec3::new( 0.2, 0.8, 0.3 ) );
+                // col = color_palette(hit.distance / 2.5, Vec3::new( 0.5, 0.5, 0.5 ), Vec3::new( 0.5, 0.5, 0.5 ), Vec3::new( 1.0, 0.6, 0.3 ), Vec3::new( 0.2, 0.8, 0.3 ) );
+                // col = color_palette(hit.bounces as f32 * 2. + 2., Vec3::new( 0.5, 0.5, 0.5 ), Vec3::new( 0.6, 0.2, 0.5 ), Vec3::new( 0.7, 0.6, 1.0 ), Vec3::new( 0.6, 0.9, 0.3 ) );
+                // Fractals shift the palette by their orbit trap to show their structure
+                let trap = hit.shape.orbit_trap( hit.position ).unwrap_or( 0. );
+                col = color_palette(
+                    hit.weight / 10. + 2. + trap,
+                    Vec3::new( 0.5, 0.5, 0.5 ),
+                    Vec3::new( 0.6, 0.6, 0.3 ),
+                    Vec3::new( 0.7, 0.6, 1.0 ),
+                    Vec3::new( 0.6, 0.9, 0.3 )
+                );
+                // col = Vec3::new( 1., 0., 0. ) * ( hit.bounces as f32 / 40. );
+                // col = hit.position;
+            },
+            CastResult::Miss( _ ) => {
+                // col = miss.position * ( 1. - miss.bounces as f32 / 25. );
+                // col = Vec3::new( 1., 0., 0. ) * ( hit.bounces as f32 / 40. );
+                // col = hit.position;
+            }
+        }
+    }
+
+    col
+}
+
+// Sub-pixel offsets in range [-0.5, 0.5], laid out on a grid that is as square as possible
+pub fn sample_offsets( samples: u32 ) -> Vec<( f32, f32 )> {
+    let columns = f32::ceil( f32::sqrt( samples as f32 ) ) as u32;
+    let rows = samples.div_ceil( columns );
+    ( 0..samples )
+        .map( | i | (
+            ( ( i % columns ) as f32 + 0.5 ) / columns as f32 - 0.5,
+            ( ( i / columns ) as f32 + 0.5 ) / rows as f32 - 0.5
+        ) )
+        .collect()
+}
+
+#[derive( Clone, Copy, Debug, PartialEq, Eq )]
+pub enum Shading {
+    // Color by the number of reflections along the path
+    Palette,
+    // Ambient occlusion of the first surface as gray values
+    Occlusion,
+    // Direct light from the scene's lights with soft shadows, gives linear radiance
+    Direct,
+    // Physically based lighting with the path tracer, gives linear radiance
+    PathTrace
+}
+
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub samples: u32,
+    // Number of worker threads, `None` uses one per available core
+    pub threads: Option<usize>,
+    // Edge length of the square tiles the image is split into
+    pub tile_size: u32,
+    pub march: MarchSettings,
+    // March cones over blocks of tile_size pixels before rendering, see `ConePrepass`
+    pub cone_prepass: bool,
+    pub shading: Shading,
+    pub occlusion: AmbientOcclusion,
+    pub direct: DirectLighting,
+    pub path_tracer: PathTracer
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            width: 512,
+            height: 512,
+            samples: 4,
+            threads: None,
+            tile_size: 16,
+            march: MarchSettings::default(),
+            cone_prepass: false,
+            shading: Shading::Palette,
+            occlusion: AmbientOcclusion::default(),
+            direct: DirectLighting::default(),
+            path_tracer: PathTracer::default()
+        }
+    }
+}
+
+impl RenderSettings {
+    pub fn thread_count( &self ) -> usize {
+        self.threads.unwrap_or_else( || thread::available_parallelism().map( | n | n.get() ).unwrap_or( 1 ) ).max( 1 )
+    }
+}
+
+#[derive( Clone, Copy, Debug, PartialEq, Eq )]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32
+}
+
+// Split the image into square tiles in row order, tiles on the right and bottom edge are cut off to fit
+pub fn tiles( width: u32, height: u32, tile_size: u32 ) -> Vec<Tile> {
+    let tile_size = tile_size.max( 1 );
+    let mut tiles = vec![];
+    for y in ( 0..height ).step_by( tile_size as usize ) {
+        for x in ( 0..width ).step_by( tile_size as usize ) {
+            tiles.push( Tile {
+                x,
+                y,
+                width: u32::min( tile_size, width - x ),
+                height: u32::min( tile_size, height - y )
+            } );
+        }
+    }
+    tiles
+}
+
+#[derive( Clone, Copy, Debug )]
+pub struct Progress {
+    pub tiles_done: usize,
+    pub tiles_total: usize,
+    pub elapsed: Duration
+}
+
+impl Progress {
+    pub fn fraction( &self ) -> f32 {
+        self.tiles_done as f32 / self.tiles_total as f32
+    }
+}
+
+// Distances that camera rays can skip without passing a surface, shared by all pixels in a block. Cones that
+// contain the rays of whole blocks are marched from the camera, first over large blocks and then over their quarters
+// starting from where the larger cone stopped, down to blocks of block_size pixels.
+// http://www.fulcrum-demo.org/wp-content/uploads/2012/04/Cone_Marching_Mandelbox_by_Seven_Fulcrum_LongVersion.pdf
+pub struct ConePrepass {
+    block_size: u32,
+    columns: u32,
+    starts: Vec<f32>,
+    // Distance evaluations made by the prepass
+    pub steps: u64
+}
+
+// Levels of blocks above the finest one, each twice the size of the one below
+const CONE_LEVELS: u32 = 3;
+
+impl ConePrepass {
+    pub fn new( camera: &Camera, world: &World, width: u32, height: u32, block_size: u32, march: &MarchSettings ) -> ConePrepass {
+        let block_size = block_size.max( 1 );
+        let mut prepass = ConePrepass { block_size, columns: 0, starts: vec![], steps: 0 };
+        for level in ( 0..=CONE_LEVELS ).rev() {
+            let size = block_size << level;
+            let ( columns, rows ) = ( width.div_ceil( size ), height.div_ceil( size ) );
+            let mut starts = Vec::with_capacity( ( columns * rows ) as usize );
+            for row in 0..rows {
+                for column in 0..columns {
+                    let start = if level == CONE_LEVELS { 0. } else { prepass.starts[ ( row / 2 * prepass.columns + column / 2 ) as usize ] };
+                    // Pixels are sampled up to half a pixel around their center
+                    let corner = | x: u32, y: u32 | camera.get_ray(
+                        ( u32::min( x, width ) as f32 - 0.5 ) / width as f32,
+                        ( u32::min( y, height ) as f32 - 0.5 ) / height as f32
+                    ).direction;
+                    let ( x, y ) = ( column * size, row * size );
+                    let corners = [ corner( x, y ), corner( x + size, y ), corner( x, y + size ), corner( x + size, y + size ) ];
+                    let ( t, steps ) = march_cone( world, camera.position, &corners, start, march );
+                    starts.push( t );
+                    prepass.steps += steps as u64;
+                }
+            }
+            prepass.columns = columns;
+            prepass.starts = starts;
+        }
+        prepass
+    }
+
+    // Distance the camera ray through pixel x, y can start at
+    pub fn start( &self, x: u32, y: u32 ) -> f32 {
+        self.starts[ ( y / self.block_size * self.columns + x / self.block_size ) as usize ]
+    }
+}
+
+// March the cone from origin that contains the rays along the corner directions, from start up to where it touches a
+// surface. Returns the distance along its axis, which no ray in the cone can hit anything before, and the steps taken.
+fn march_cone( world: &World, origin: Vec3, corners: &[Vec3; 4], start: f32, march: &MarchSettings ) -> ( f32, u32 ) {
+    let axis = ( corners[ 0 ] + corners[ 1 ] + corners[ 2 ] + corners[ 3 ] ).normalize();
+    let cos = corners.iter().map( | corner | corner.dot( axis ) ).fold( 1., f32::min );
+    let tan = f32::sqrt( 1. - cos * cos ) / cos;
+
+    let mut t = start;
+    let mut steps = 0;
+    while steps < march.max_steps && t < march.max_distance {
+        steps += 1;
+        let Some( ( _, dist ) ) = world.nearest( origin + axis * t ) else { return ( march.max_distance, steps ) };
+        // The empty sphere around the axis point still contains the cone's cross sections up to this far ahead
+        let radius = t * tan;
+        let step = ( dist - radius ) / ( 1. + tan );
+        if step < f32::max( EPSILON, radius * 0.1 ) {
+            break;
+        }
+        t += step;
+    }
+    ( t.min( march.max_distance ), steps )
+}
+
+// The average linear color of the samples of pixel x, y
+fn render_pixel( x: u32, y: u32, start: f32, offsets: &[( f32, f32 )], camera: &Camera, world: &World, settings: &RenderSettings ) -> Vec4 {
+    let samples = offsets.len() as f32;
+    let ray = | ( dx, dy ): ( f32, f32 ) | camera.get_ray( ( x as f32 + dx ) / settings.width as f32, ( y as f32 + dy ) / settings.height as f32 ).advanced( start );
+
+    let radiance = match settings.shading {
+        Shading::Palette => {
+            let mut col = Vec3::ZERO;
+            for &offset in offsets {
+                col += calc_pixel( ray( offset ), world, &settings.march );
+            }
+            col
+        },
+        Shading::Occlusion => {
+            let mut radiance = Vec3::ZERO;
+            for &offset in offsets {
+                radiance += settings.occlusion.radiance( world, &ray( offset ), &settings.march );
+            }
+            radiance
+        },
+        Shading::Direct => {
+            let mut radiance = Vec3::ZERO;
+            for &offset in offsets {
+                radiance += settings.direct.radiance( world, &ray( offset ), &settings.march );
+            }
+            radiance
+        },
+        Shading::PathTrace => {
+            let mut rng = Rng::new( ( y * settings.width + x ) as u64, 0 );
+            let mut radiance = Vec3::ZERO;
+            for &offset in offsets {
+                radiance += settings.path_tracer.radiance( world, &ray( offset ), &settings.march, &mut rng );
+            }
+            radiance
+        }
+    };
+    ( radiance / samples ).extend( 1. )
+}
+
+pub fn render( camera: &Camera, world: &World, settings: &RenderSettings ) -> Framebuffer {
+    render_with_progress( camera, world, settings, &| _ | {} )
+}
+
+// Render the image with a pool of workers that pull tiles from a shared queue until it is empty.
+// `on_progress` is called after every finished tile.
+pub fn render_with_progress( camera: &Camera, world: &World, settings: &RenderSettings, on_progress: &( dyn Fn( Progress ) + Sync ) ) -> Framebuffer {
+
+    let width = settings.width;
+    let height = settings.height;
+
+    let offsets = sample_offsets( settings.samples );
+    let tiles = tiles( width, height, settings.tile_size );
+    let prepass = settings.cone_prepass.then( || ConePrepass::new( camera, world, width, height, settings.tile_size, &settings.march ) );
+    let next_tile = AtomicUsize::new( 0 );
+    let framebuffer = Mutex::new( ( Framebuffer::new( width, height ), 0 ) );
+    let time = Instant::now();
+
+    thread::scope( | scope | {
+        for _ in 0..usize::min( settings.thread_count(), tiles.len() ) {
+            scope.spawn( || {
+                loop {
+                    let index = next_tile.fetch_add( 1, Ordering::Relaxed );
+                    let Some( tile ) = tiles.get( index ) else { break };
+
+                    let mut data = Vec::with_capacity( ( tile.width * tile.height ) as usize );
+                    for y in tile.y..( tile.y + tile.height ) {
+                        for x in tile.x..( tile.x + tile.width ) {
+                            let start = prepass.as_ref().map_or( 0., | prepass | prepass.start( x, y ) );
+                            data.push( render_pixel( x, y, start, &offsets, camera, world, settings ) );
+                        }
+                    }
+
+                    let mut guard = framebuffer.lock().unwrap();
+                    let ( image, tiles_done ) = &mut *guard;
+                    image.set_tile( tile.x, tile.y, tile.width, tile.height, &data );
+                    *tiles_done += 1;
+                    on_progress( Progress { tiles_done: *tiles_done, tiles_total: tiles.len(), elapsed: time.elapsed() } );
+                }
+            } );
+        }
+    } );
+
+    framebuffer.into_inner().unwrap().0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use glam::{Mat4, Vec3};
+    use super::{tiles, ConePrepass};
+    use crate::camera::Camera;
+    use crate::material::Material;
+    use crate::rays::{MarchSettings, Sphere, Wall, World};
+
+    #[test]
+    fn tiles_cover_image_once() {
+        let ( width, height ) = ( 37, 21 );
+        let mut covered = vec![ 0; ( width * height ) as usize ];
+        for tile in tiles( width, height, 8 ) {
+            assert!( tile.width > 0 && tile.width <= 8 );
+            assert!( tile.height > 0 && tile.height <= 8 );
+            for y in tile.y..( tile.y + tile.height ) {
+                for x in tile.x..( tile.x + tile.width ) {
+                    covered[ ( y * width + x ) as usize ] += 1;
+                }
+            }
+        }
+        assert!( covered.iter().all( | &c | c == 1 ) );
+    }
+
+    #[test]
+    fn tile_count() {
+        assert_eq!( tiles( 512, 512, 16 ).len(), 32 * 32 );
+        assert_eq!( tiles( 500, 10, 16 ).len(), 32 );
+        assert_eq!( tiles( 1, 1, 16 ).len(), 1 );
+    }
+
+    #[test]
+    fn cone_prepass_stops_before_surfaces() {
+        let material = Arc::new( Material::lambertian( Vec3::ONE ) );
+        let world = World::new( vec![
+            Box::new( Sphere { position: Vec3::new( 1., 0., 6. ), radius: 1.5, material: material.clone() } ),
+            Box::new( Wall { position: Vec3::new( 0., -2., 0. ), rotation: Mat4::IDENTITY, size: Vec3::new( 20., 0.1, 20. ), material } )
+        ] );
+        let camera = Camera::new( Vec3::ZERO, Vec3::Z, Vec3::Y, 1.2, 1.5, 1. );
+        let ( width, height ) = ( 45, 30 );
+        let march = MarchSettings::default();
+        let prepass = ConePrepass::new( &camera, &world, width, height, 4, &march );
+        assert!( prepass.steps > 0 );
+
+        let mut skipped = 0.;
+        for y in 0..height {
+            for x in 0..width {
+                let start = prepass.start( x, y );
+                skipped += start;
+                // The rays through the corners of the pixel must not hit anything before their start
+                for ( dx, dy ) in [ ( -0.5, -0.5 ), ( 0.5, -0.5 ), ( -0.5, 0.5 ), ( 0.5, 0.5 ) ] {
+                    let ray = camera.get_ray( ( x as f32 + dx ) / width as f32, ( y as f32 + dy ) / height as f32 );
+                    let segment = world.march( ray.origin, ray.direction, march.max_distance, march.max_steps );
+                    assert!( start <= segment.length + 1e-4, "pixel {}, {} starts at {} but hits at {}", x, y, start, segment.length );
+                }
+            }
+        }
+        assert!( skipped / ( width * height ) as f32 > 1. );
+    }
+}
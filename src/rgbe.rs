@@ -0,0 +1,284 @@
+// Radiance HDR files, which store every pixel as three 8-bit mantissas sharing an 8-bit exponent. Colors keep about
+// 1% precision over a huge range, alpha is lost.
+//
+// Files are written with run length encoded scanlines. Reading accepts flat, old style and run length encoded
+// scanlines in the usual top to bottom order, and applies EXPOSURE lines of the header.
+// https://radsite.lbl.gov/radiance/refer/filefmts.pdf
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use glam::Vec3;
+use crate::image::{Framebuffer, ImageError};
+
+// Scanlines of this many pixels can be run length encoded
+const RLE_WIDTHS: std::ops::RangeInclusive<u32> = 8..=0x7fff;
+
+// Shared exponent encoding, colors below 1e-32 become black and negative channels zero
+pub fn to_rgbe( color: Vec3 ) -> [u8; 4] {
+    let color = color.to_array().map( | c | c.max( 0. ) );
+    let max = color.iter().copied().fold( 0., f32::max );
+    if max < 1e-32 {
+        return [ 0; 4 ];
+    }
+    // max = m * 2^exponent with m in [0.5, 1)
+    let exponent = ( ( max.to_bits() >> 23 ) & 0xff ) as i32 - 126;
+    if exponent > 127 {
+        return [ 255; 4 ];
+    }
+    let scale = 256. / f32::powi( 2., exponent );
+    let [ r, g, b ] = color.map( | c | ( c * scale ) as u8 );
+    [ r, g, b, ( exponent + 128 ) as u8 ]
+}
+
+pub fn from_rgbe( rgbe: [u8; 4] ) -> Vec3 {
+    if rgbe[ 3 ] == 0 {
+        return Vec3::ZERO;
+    }
+    let scale = f32::powi( 2., rgbe[ 3 ] as i32 - ( 128 + 8 ) );
+    ( Vec3::new( rgbe[ 0 ] as f32, rgbe[ 1 ] as f32, rgbe[ 2 ] as f32 ) + 0.5 ) * scale
+}
+
+// Runs of 4 or more equal bytes are stored as a count above 128 and the byte, everything else as a count of up to 128
+// and the bytes themselves
+fn encode_runs( data: &[u8], out: &mut Vec<u8> ) {
+    let run_at = | i: usize | data[ i.. ].iter().take( 127 ).take_while( | &&b | b == data[ i ] ).count();
+    let mut i = 0;
+    while i < data.len() {
+        let run = run_at( i );
+        if run >= 4 {
+            out.extend_from_slice( &[ 128 + run as u8, data[ i ] ] );
+            i += run;
+            continue;
+        }
+        let start = i;
+        while i < data.len() && i - start < 128 && ( i == start || run_at( i ) < 4 ) {
+            i += 1;
+        }
+        out.push( ( i - start ) as u8 );
+        out.extend_from_slice( &data[ start..i ] );
+    }
+}
+
+pub fn write<W: Write>( image: &Framebuffer, writer: &mut W ) -> Result<(), ImageError> {
+    let ( width, height ) = ( image.get_width(), image.get_height() );
+    write!( writer, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width )?;
+
+    let mut line = vec![];
+    for y in 0..height {
+        let pixels: Vec<[u8; 4]> = ( 0..width ).map( | x | to_rgbe( image.get_pixel( x, y ).truncate() ) ).collect();
+        line.clear();
+        if RLE_WIDTHS.contains( &width ) {
+            line.extend_from_slice( &[ 2, 2, ( width >> 8 ) as u8, width as u8 ] );
+            for component in 0..4 {
+                let values: Vec<u8> = pixels.iter().map( | p | p[ component ] ).collect();
+                encode_runs( &values, &mut line );
+            }
+        } else {
+            line.extend( pixels.iter().flatten() );
+        }
+        writer.write_all( &line )?;
+    }
+    Ok( () )
+}
+
+fn format_error<T>( message: &str ) -> Result<T, ImageError> {
+    Err( ImageError::Format( message.to_string() ) )
+}
+
+// Read one scanline of `width` pixels from the start of data, returning the number of bytes used
+fn read_scanline( data: &[u8], width: usize, pixels: &mut [[u8; 4]] ) -> Result<usize, ImageError> {
+    let byte = | i: usize | data.get( i ).copied().ok_or( ImageError::Format( "unexpected end of file".to_string() ) );
+
+    if RLE_WIDTHS.contains( &( width as u32 ) ) && byte( 0 )? == 2 && byte( 1 )? == 2 && byte( 2 )? & 0x80 == 0 {
+        if ( ( byte( 2 )? as usize ) << 8 | byte( 3 )? as usize ) != width {
+            return format_error( "scanline has the wrong length" );
+        }
+        let mut i = 4;
+        for component in 0..4 {
+            let mut x = 0;
+            while x < width {
+                let count = byte( i )? as usize;
+                let ( length, literal ) = if count > 128 { ( count - 128, false ) } else { ( count, true ) };
+                if length == 0 || x + length > width {
+                    return format_error( "bad run length" );
+                }
+                for ( j, pixel ) in pixels[ x..x + length ].iter_mut().enumerate() {
+                    pixel[ component ] = byte( i + 1 + if literal { j } else { 0 } )?;
+                }
+                i += 1 + if literal { length } else { 1 };
+                x += length;
+            }
+        }
+        return Ok( i );
+    }
+
+    // Flat pixels, where old files mark repeats of the previous pixel with 1, 1, 1 and a count
+    let ( mut i, mut x, mut shift ) = ( 0, 0, 0 );
+    while x < width {
+        let pixel = [ byte( i )?, byte( i + 1 )?, byte( i + 2 )?, byte( i + 3 )? ];
+        i += 4;
+        if pixel[ ..3 ] == [ 1, 1, 1 ] && x > 0 {
+            let count = ( pixel[ 3 ] as u64 ).checked_shl( shift ).unwrap_or( u64::MAX );
+            if count > ( width - x ) as u64 {
+                return format_error( "bad run length" );
+            }
+            let previous = pixels[ x - 1 ];
+            pixels[ x..x + count as usize ].fill( previous );
+            x += count as usize;
+            shift += 8;
+        } else {
+            pixels[ x ] = pixel;
+            x += 1;
+            shift = 0;
+        }
+    }
+    Ok( i )
+}
+
+pub fn read<R: Read>( reader: &mut R ) -> Result<Framebuffer, ImageError> {
+    let mut data = vec![];
+    reader.read_to_end( &mut data )?;
+    if !data.starts_with( b"#?" ) {
+        return format_error( "not a Radiance HDR file" );
+    }
+
+    // Header lines up to an empty one, followed by the resolution line
+    let mut lines = data.split( | &b | b == b'\n' );
+    let mut header_size = 0;
+    let mut exposure = 1.;
+    let mut next_line = || -> Result<&str, ImageError> {
+        let line = lines.next().ok_or( ImageError::Format( "unexpected end of header".to_string() ) )?;
+        header_size += line.len() + 1;
+        std::str::from_utf8( line ).map_err( | _ | ImageError::Format( "header is not text".to_string() ) )
+    };
+    next_line()?;
+    loop {
+        let line = next_line()?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some( format ) = line.strip_prefix( "FORMAT=" ) {
+            if format.trim() != "32-bit_rle_rgbe" {
+                return Err( ImageError::Format( format!( "unsupported format {}", format ) ) );
+            }
+        } else if let Some( value ) = line.strip_prefix( "EXPOSURE=" ) {
+            exposure *= value.trim().parse::<f32>().map_err( | _ | ImageError::Format( "invalid exposure".to_string() ) )?;
+        }
+    }
+    let resolution: Vec<&str> = next_line()?.split_whitespace().collect();
+    let ( width, height ) = match resolution.as_slice() {
+        [ "-Y", height, "+X", width ] => ( width.parse::<u32>().ok(), height.parse::<u32>().ok() ),
+        _ => return format_error( "only top to bottom, left to right images are supported" )
+    };
+    let ( Some( width ), Some( height ) ) = ( width, height ) else { return format_error( "invalid resolution" ) };
+    if width == 0 || height == 0 || exposure <= 0. {
+        return format_error( "empty image or invalid exposure" );
+    }
+
+    // Check the size against the file before allocating the image. Every scanline takes at least 4 bytes, and run
+    // length encoding packs each component of 127 pixels into 2 bytes at most.
+    let remaining = ( data.len() - header_size.min( data.len() ) ) as u64;
+    if height as u64 * 4 > remaining || width as u64 * height as u64 * 8 > remaining * 127 {
+        return format_error( "resolution is larger than the pixel data of the file" );
+    }
+
+    let Some( mut image ) = Framebuffer::try_new( width, height ) else { return format_error( "image is too large" ) };
+    let mut pixels = vec![ [ 0u8; 4 ]; width as usize ];
+    let mut position = header_size;
+    for y in 0..height {
+        position += read_scanline( &data[ position.. ], width as usize, &mut pixels )?;
+        for ( x, &pixel ) in pixels.iter().enumerate() {
+            image.set_pixel( x as u32, y, ( from_rgbe( pixel ) / exposure ).extend( 1. ) );
+        }
+    }
+    Ok( image )
+}
+
+pub fn load<P: AsRef<Path>>( path: P ) -> Result<Framebuffer, ImageError> {
+    read( &mut BufReader::new( File::open( path )? ) )
+}
+
+pub fn save<P: AsRef<Path>>( image: &Framebuffer, path: P ) -> Result<(), ImageError> {
+    let mut writer = BufWriter::new( File::create( path )? );
+    write( image, &mut writer )?;
+    writer.flush()?;
+    Ok( () )
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Vec3, Vec4};
+    use super::{from_rgbe, read, to_rgbe, write};
+    use crate::image::{Framebuffer, ImageError};
+
+    #[test]
+    fn shared_exponent() {
+        assert_eq!( to_rgbe( Vec3::ZERO ), [ 0; 4 ] );
+        assert_eq!( to_rgbe( Vec3::new( 1., 0.5, -1. ) ), [ 128, 64, 0, 129 ] );
+        for color in [ Vec3::new( 0.2, 0.4, 0.8 ), Vec3::new( 1000., 3., 0.1 ), Vec3::splat( 1e-5 ) ] {
+            let error = ( from_rgbe( to_rgbe( color ) ) - color ).abs().max_element();
+            assert!( error <= color.max_element() / 128., "{}", color );
+        }
+    }
+
+    fn image( width: u32 ) -> Framebuffer {
+        let mut image = Framebuffer::new( width, 5 );
+        for y in 0..5 {
+            for x in 0..width {
+                // Runs of equal pixels and noise
+                let color = if x < width / 2 { Vec3::new( 4., 2., 1. ) } else { Vec3::new( ( x * 7 % 13 ) as f32, y as f32 * 0.1, 0.5 ) };
+                image.set_pixel( x, y, color.extend( 0.5 ) );
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn round_trip() {
+        // Run length encoded and flat scanlines
+        for width in [ 300, 5 ] {
+            let image = image( width );
+            let mut data = vec![];
+            write( &image, &mut data ).unwrap();
+            let copy = read( &mut data.as_slice() ).unwrap();
+            for y in 0..5 {
+                for x in 0..width {
+                    let ( expected, value ) = ( image.get_pixel( x, y ), copy.get_pixel( x, y ) );
+                    assert_eq!( value.w, 1. );
+                    assert!( ( value - expected ).truncate().abs().max_element() <= expected.max_element() / 128., "{} {}", value, expected );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn old_runs_and_exposure() {
+        let mut data = b"#?RGBE\nEXPOSURE=2\n\n-Y 1 +X 6\n".to_vec();
+        data.extend_from_slice( &[ 128, 64, 0, 129, 1, 1, 1, 4, 0, 0, 0, 0 ] );
+        let image = read( &mut data.as_slice() ).unwrap();
+        assert_eq!( image.get_pixel( 4, 0 ), Vec4::new( 128.5, 64.5, 0.5, 256. ) / 256. );
+        assert_eq!( image.get_pixel( 5, 0 ), Vec4::new( 0., 0., 0., 1. ) );
+
+        assert!( matches!( read( &mut &data[ ..data.len() - 1 ] ), Err( ImageError::Format( _ ) ) ) );
+        assert!( matches!( read( &mut &b"P6\n"[ .. ] ), Err( ImageError::Format( _ ) ) ) );
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        // Resolutions the file is too small for are rejected before anything is allocated for them
+        for resolution in [ "-Y 100000 +X 100000", "-Y 1 +X 4000000000", "-Y 4000000000 +X 1" ] {
+            let mut data = format!( "#?RGBE\n\n{}\n", resolution ).into_bytes();
+            data.extend_from_slice( &[ 128, 64, 0, 129 ] );
+            assert!( matches!( read( &mut data.as_slice() ), Err( ImageError::Format( _ ) ) ), "{}", resolution );
+        }
+
+        // Empty old style runs that keep shifting the count
+        let mut data = b"#?RGBE\n\n-Y 1 +X 2\n".to_vec();
+        data.extend_from_slice( &[ 128, 64, 0, 129 ] );
+        for _ in 0..10 {
+            data.extend_from_slice( &[ 1, 1, 1, 0 ] );
+        }
+        assert!( matches!( read( &mut data.as_slice() ), Err( ImageError::Format( _ ) ) ) );
+    }
+}
@@ -0,0 +1,96 @@
+use std::f32::consts::{PI, TAU};
+use glam::Vec3;
+
+// PCG32 random number generator, https://www.pcg-random.org/
+pub struct Rng {
+    state: u64,
+    inc: u64
+}
+
+impl Rng {
+    pub fn new( seed: u64, stream: u64 ) -> Rng {
+        let mut rng = Rng { state: 0, inc: ( stream << 1 ) | 1 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add( seed );
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32( &mut self ) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul( 6364136223846793005 ).wrapping_add( self.inc );
+        let xorshifted = ( ( ( old >> 18 ) ^ old ) >> 27 ) as u32;
+        let rot = ( old >> 59 ) as u32;
+        xorshifted.rotate_right( rot )
+    }
+
+    // Uniform in [0, 1)
+    pub fn next_f32( &mut self ) -> f32 {
+        ( self.next_u32() >> 8 ) as f32 * ( 1. / ( 1 << 24 ) as f32 )
+    }
+}
+
+// Two vectors that form an orthonormal basis together with n, n must be normalized
+// https://graphics.pixar.com/library/OrthonormalB/paper.pdf
+pub fn orthonormal_basis( n: Vec3 ) -> ( Vec3, Vec3 ) {
+    let sign = 1_f32.copysign( n.z );
+    let a = -1. / ( sign + n.z );
+    let b = n.x * n.y * a;
+    (
+        Vec3::new( 1. + sign * n.x * n.x * a, sign * b, -sign * n.x ),
+        Vec3::new( b, sign + n.y * n.y * a, -n.y )
+    )
+}
+
+// Express a direction given around the z axis around n instead
+pub fn to_world( local: Vec3, n: Vec3 ) -> Vec3 {
+    let ( t, b ) = orthonormal_basis( n );
+    t * local.x + b * local.y + n * local.z
+}
+
+// Direction in the hemisphere around n with a density of cos(theta) / pi
+pub fn cosine_hemisphere( n: Vec3, u1: f32, u2: f32 ) -> Vec3 {
+    let r = f32::sqrt( u1 );
+    let phi = TAU * u2;
+    let local = Vec3::new( r * phi.cos(), r * phi.sin(), f32::sqrt( f32::max( 0., 1. - u1 ) ) );
+    to_world( local, n )
+}
+
+// Density of `cosine_hemisphere` for a direction at theta from n
+pub fn cosine_hemisphere_pdf( cos_theta: f32 ) -> f32 {
+    f32::max( 0., cos_theta ) / PI
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+    use super::{cosine_hemisphere, orthonormal_basis, Rng};
+
+    #[test]
+    fn rng_is_uniform() {
+        let mut rng = Rng::new( 42, 7 );
+        let n = 100_000;
+        let mean = ( 0..n ).map( | _ | rng.next_f32() ).sum::<f32>() / n as f32;
+        assert!( ( mean - 0.5 ).abs() < 0.01 );
+    }
+
+    #[test]
+    fn basis_is_orthonormal() {
+        for n in [ Vec3::X, Vec3::NEG_Z, Vec3::new( 1., 2., 3. ).normalize(), Vec3::new( -0.3, 0.1, -2. ).normalize() ] {
+            let ( t, b ) = orthonormal_basis( n );
+            assert!( t.dot( n ).abs() < 1e-6 && b.dot( n ).abs() < 1e-6 && t.dot( b ).abs() < 1e-6 );
+            assert!( ( t.length() - 1. ).abs() < 1e-5 && ( b.length() - 1. ).abs() < 1e-5 );
+        }
+    }
+
+    #[test]
+    fn cosine_samples_are_in_hemisphere() {
+        let mut rng = Rng::new( 1, 1 );
+        let n = Vec3::new( 0.2, -1., 0.4 ).normalize();
+        for _ in 0..1000 {
+            let d = cosine_hemisphere( n, rng.next_f32(), rng.next_f32() );
+            assert!( d.dot( n ) >= 0. );
+            assert!( ( d.length() - 1. ).abs() < 1e-4 );
+        }
+    }
+}
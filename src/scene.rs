@@ -0,0 +1,1398 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use glam::{BVec3, EulerRot, Mat4, Quat, Vec3, Vec4};
+use toml::{Table, Value};
+use crate::camera::Camera;
+use crate::csg::{Blend, Csg, Operation};
+use crate::domain::{Bend, Elongate, Mirror, Onion, PolarRepeat, Repeat, Round, Twist};
+use crate::fractal::{Mandelbox, Mandelbulb, MengerSponge, QuaternionJulia, SierpinskiTetrahedron};
+use crate::grid::{DistanceGrid, GridShape, Interpolation};
+use crate::light::Light;
+use crate::material::{Bsdf, Material};
+use crate::mesh::{Mesh, TriangleList};
+use crate::rays::{Hittable, Sphere, Wall, World};
+use crate::shapes::{Capsule, Cone, Cylinder, Ellipsoid, HexPrism, Octahedron, Plane, Quad, RoundedBox, Torus, Triangle};
+use crate::transform::{Group, Transformed};
+
+// A scene file is a TOML document with an optional `[camera]` table, named materials and a list of shapes:
+//
+//   [camera]
+//   position = [ 0, 0, -2 ]
+//   direction = [ 0, 0, 1 ]
+//
+//   [materials.mirror]
+//   type = "metal"
+//   color = "#27e1c1"
+//
+//   [[shapes]]
+//   type = "sphere"
+//   position = [ 0, 0, 0 ]
+//   radius = 1.0
+//   material = "mirror"
+//
+// Colors are either `[ r, g, b ]` in [0, 1] or a "#rrggbb" hex string. Shapes may also use an inline material table.
+// Materials have a `type` of:
+//   lambertian:  color, the default when no type is given
+//   metal:       color, roughness (default 0, a perfect mirror)
+//   dielectric:  ior (default 1.5), color (tint of transmitted light, default white), absorption (per unit of
+//                distance inside the shape, default [ 0, 0, 0 ])
+//   emissive:    emission, a black surface that only emits
+//   principled:  color, metallic (default 0), roughness (default 0.5), specular (default 0.5)
+// Any material may have an `emission` of `[ r, g, b ]` radiance, and `background` sets the radiance of the sky.
+//
+// Shapes have a `type` of:
+//   sphere:      position, radius
+//   wall:        position, size (half extents), rotation (euler angles in degrees, default [ 0, 0, 0 ])
+//   torus:       position, major_radius, minor_radius, the ring lies in the xz plane
+//   capsule:     a, b, radius
+//   cylinder:    position, radius, half_height, along the y axis
+//   cone:        position, half_height, bottom_radius, top_radius (default 0), along the y axis
+//   plane:       normal, offset (default 0), the side the normal points to is outside
+//   rounded_box: position, size (half extents), radius
+//   ellipsoid:   position, radii
+//   octahedron:  position, size
+//   hex_prism:   position, radius (to the flat sides), half_length, along the z axis
+//   triangle:    a, b, c
+//   quad:        a, b, c, d, the corners of a planar quad in order
+//   mesh:        file, a triangle mesh in OBJ or PLY format, relative to the scene file. Only closed meshes have a
+//                well defined inside.
+//   grid:        file, a signed distance grid made by `rvk bake`, relative to the scene file, and interpolation
+//                of "trilinear" (the default) or "tricubic"
+//
+// Fractals have a position and a number of iterations up to 100, more give finer detail:
+//   mandelbulb:    power (at least 2, default 8), iterations (default 8)
+//   mandelbox:     scale (default -1.5, below -1 or above 1), iterations (default 12)
+//   menger_sponge: size (half the edge length), iterations (default 4)
+//   sierpinski:    size (half the edge length of the cube around the tetrahedron), iterations (default 8)
+//   julia:         c, the quaternion [ x, i, j, k ] of the Julia set, iterations (default 11)
+// The palette shading colors fractals by their orbit traps.
+//
+// Shapes are combined by the types union, intersection and subtraction. These have a list of `shapes` tables and
+// no material of their own, a subtraction removes all later shapes from the first. An optional `blend` of "sharp"
+// (the default), "smooth", "chamfer" or "round" with a `radius` rounds off where the surfaces meet, and smooth
+// blends mix the materials of both shapes:
+//
+//   [[shapes]]
+//   type = "union"
+//   blend = "smooth"
+//   radius = 0.3
+//   shapes = [
+//     { type = "sphere", position = [ -0.5, 0, 0 ], radius = 1, material = "red" },
+//     { type = "sphere", position = [ 0.5, 0, 0 ], radius = 1, material = "blue" }
+//   ]
+//
+// Any shape may have a `transform` table with a translation, rotation (euler angles in degrees, default
+// [ 0, 0, 0 ]) and scale (a number or [ x, y, z ], default 1), applied in the order scale, rotate, translate. The
+// type group collects a list of `shapes` so they can be transformed together, groups may be nested:
+//
+//   [[shapes]]
+//   type = "group"
+//   transform = { translation = [ 0, 1, 0 ], rotation = [ 0, 45, 0 ] }
+//   shapes = [
+//     { type = "sphere", position = [ 0, 0, 0 ], radius = 1, material = "red", transform = { scale = [ 2, 1, 1 ] } },
+//     { type = "wall", position = [ 0, -1, 0 ], size = [ 2, 0.1, 2 ], material = "blue" }
+//   ]
+//
+// Domain operators change a single `shape` table:
+//   repeat:       spacing (between copies per axis, 0 to not repeat), limit (copies on either side per axis, default
+//                 endless)
+//   polar_repeat: count, copies around the y axis
+//   mirror:       axes, a string like "xz" naming the axes to mirror the positive side along
+//   twist:        angle, in degrees per unit along the y axis
+//   bend:         angle, in degrees per unit along the x axis, bending in the xy plane
+//   elongate:     size, half lengths of straight extrusion inserted at the center of the shape
+//   round:        radius, grows the shape and rounds off its edges
+//   onion:        thickness, hollows the shape out to a shell of this thickness on either side of the surface
+// Shapes that are repeated, twisted or bent must be bounded, so no planes.
+//
+// Lights are listed as `[[lights]]` tables with a `type` of:
+//   point:       position, intensity
+//   spot:        position, direction, intensity, angle and optionally inner_angle, both in degrees
+//   directional: direction (in which the light travels), irradiance
+//   area:        corner, edge_u, edge_v, radiance, emits to the side of edge_u x edge_v
+// Shapes with an emissive material are lights as well.
+
+// Most fractal iterations a scene may ask for
+const MAX_ITERATIONS: u32 = 100;
+
+pub struct Scene {
+    pub camera: CameraSettings,
+    pub world: World
+}
+
+#[derive( Clone, Copy, Debug )]
+pub struct CameraSettings {
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub up: Vec3,
+    pub fov: f32,
+    pub near_plane: f32
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        CameraSettings {
+            position: Vec3::new( 0., 0., -2.0 ),
+            direction: Vec3::new( 0., 0., 1. ),
+            up: Vec3::new( 0., 1., 0. ),
+            fov: 90.,
+            near_plane: 1.
+        }
+    }
+}
+
+impl CameraSettings {
+    pub fn build( &self, aspect_ratio: f32 ) -> Camera {
+        Camera::new(
+            self.position,
+            self.direction.normalize(),
+            self.up.normalize(),
+            self.fov,
+            aspect_ratio,
+            self.near_plane
+        )
+    }
+}
+
+#[derive( Debug )]
+pub enum SceneError {
+    Io( std::io::Error ),
+    Parse( toml::de::Error ),
+    // `entry` names the offending table, e.g. "shapes[3]" or "materials.mirror"
+    Invalid { entry: String, field: String, message: String }
+}
+
+impl fmt::Display for SceneError {
+    fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+        match self {
+            SceneError::Io( e ) => write!( f, "could not read scene file: {}", e ),
+            SceneError::Parse( e ) => write!( f, "could not parse scene file: {}", e ),
+            SceneError::Invalid { entry, field, message } if entry.is_empty() => write!( f, "{}: {}", field, message ),
+            SceneError::Invalid { entry, field, message } if field.is_empty() => write!( f, "{}: {}", entry, message ),
+            SceneError::Invalid { entry, field, message } => write!( f, "{}.{}: {}", entry, field, message )
+        }
+    }
+}
+
+impl std::error::Error for SceneError {
+    fn source( &self ) -> Option<&( dyn std::error::Error + 'static )> {
+        match self {
+            SceneError::Io( e ) => Some( e ),
+            SceneError::Parse( e ) => Some( e ),
+            SceneError::Invalid { .. } => None
+        }
+    }
+}
+
+impl From<std::io::Error> for SceneError {
+    fn from( e: std::io::Error ) -> Self {
+        SceneError::Io( e )
+    }
+}
+
+impl From<toml::de::Error> for SceneError {
+    fn from( e: toml::de::Error ) -> Self {
+        SceneError::Parse( e )
+    }
+}
+
+// Files referenced by the scene, like meshes, are relative to the directory of the scene file
+pub fn load<P: AsRef<Path>>( path: P ) -> Result<Scene, SceneError> {
+    let source = fs::read_to_string( path.as_ref() )?;
+    parse_in( &source, path.as_ref().parent().unwrap_or( Path::new( "" ) ) )
+}
+
+// Files referenced by the scene are relative to the working directory
+pub fn parse( source: &str ) -> Result<Scene, SceneError> {
+    parse_in( source, Path::new( "" ) )
+}
+
+// What shapes can refer to while they are parsed
+struct Resources<'a> {
+    materials: HashMap<String, Arc<Material>>,
+    directory: &'a Path
+}
+
+fn parse_in( source: &str, directory: &Path ) -> Result<Scene, SceneError> {
+    let root: Table = source.parse()?;
+    let root = Entry { name: String::new(), table: &root };
+    root.check_fields( &[ "camera", "background", "materials", "shapes", "lights" ] )?;
+
+    let camera = match root.table.get( "camera" ) {
+        Some( value ) => parse_camera( &root.child( "camera", value )? )?,
+        None => CameraSettings::default()
+    };
+
+    let mut materials = HashMap::new();
+    if let Some( value ) = root.table.get( "materials" ) {
+        let table = root.child( "materials", value )?;
+        for ( name, value ) in table.table {
+            let entry = table.child( name, value )?;
+            materials.insert( name.clone(), Arc::new( parse_material( &entry )? ) );
+        }
+    }
+    let resources = Resources { materials, directory };
+
+    let mut content: Vec<Box<dyn Hittable>> = vec![];
+    if let Some( value ) = root.table.get( "shapes" ) {
+        let shapes = value.as_array().ok_or_else( || root.error( "shapes", "expected an array of tables" ) )?;
+        for ( i, value ) in shapes.iter().enumerate() {
+            let name = format!( "shapes[{}]", i );
+            let table = value.as_table().ok_or_else( || SceneError::Invalid { entry: name.clone(), field: String::new(), message: "expected a table".to_string() } )?;
+            content.push( parse_shape( &Entry { name, table }, &resources )? );
+        }
+    }
+
+    let mut world = World::new( content );
+    world.background = root.vec3_or( "background", Vec3::ZERO )?;
+
+    if let Some( value ) = root.table.get( "lights" ) {
+        let lights = value.as_array().ok_or_else( || root.error( "lights", "expected an array of tables" ) )?;
+        for ( i, value ) in lights.iter().enumerate() {
+            let name = format!( "lights[{}]", i );
+            let table = value.as_table().ok_or_else( || SceneError::Invalid { entry: name.clone(), field: String::new(), message: "expected a table".to_string() } )?;
+            world.lights.push( parse_light( &Entry { name, table } )? );
+        }
+    }
+
+    Ok( Scene { camera, world } )
+}
+
+fn parse_camera( entry: &Entry ) -> Result<CameraSettings, SceneError> {
+    entry.check_fields( &[ "position", "direction", "up", "fov", "near_plane" ] )?;
+    let default = CameraSettings::default();
+    let camera = CameraSettings {
+        position: entry.vec3_or( "position", default.position )?,
+        direction: entry.vec3_or( "direction", default.direction )?,
+        up: entry.vec3_or( "up", default.up )?,
+        fov: entry.float_or( "fov", default.fov )?,
+        near_plane: entry.float_or( "near_plane", default.near_plane )?
+    };
+    if camera.direction.length_squared() == 0. {
+        return Err( entry.error( "direction", "must not be zero" ) );
+    }
+    if camera.up.length_squared() == 0. {
+        return Err( entry.error( "up", "must not be zero" ) );
+    }
+    Ok( camera )
+}
+
+fn parse_material( entry: &Entry ) -> Result<Material, SceneError> {
+    let kind = if entry.table.contains_key( "type" ) { entry.string( "type" )? } else { "lambertian" };
+    let bsdf = match kind {
+        "lambertian" => {
+            entry.check_fields( &[ "type", "color", "emission" ] )?;
+            Bsdf::Lambertian { albedo: entry.color( "color" )? }
+        },
+        "metal" => {
+            entry.check_fields( &[ "type", "color", "roughness", "emission" ] )?;
+            Bsdf::Metal { albedo: entry.color( "color" )?, roughness: entry.fraction_or( "roughness", 0. )? }
+        },
+        "dielectric" => {
+            entry.check_fields( &[ "type", "color", "ior", "absorption", "emission" ] )?;
+            let ior = entry.float_or( "ior", 1.5 )?;
+            if ior <= 0. {
+                return Err( entry.error( "ior", "must be greater than 0" ) );
+            }
+            let absorption = entry.vec3_or( "absorption", Vec3::ZERO )?;
+            if absorption.min_element() < 0. {
+                return Err( entry.error( "absorption", "must not be negative" ) );
+            }
+            Bsdf::Dielectric { ior, tint: entry.color_or( "color", Vec3::ONE )?, absorption }
+        },
+        "emissive" => {
+            entry.check_fields( &[ "type", "emission" ] )?;
+            return Ok( Material::emissive( entry.vec3( "emission" )? ) );
+        },
+        "principled" => {
+            entry.check_fields( &[ "type", "color", "metallic", "roughness", "specular", "emission" ] )?;
+            Bsdf::Principled {
+                base_color: entry.color( "color" )?,
+                metallic: entry.fraction_or( "metallic", 0. )?,
+                roughness: entry.fraction_or( "roughness", 0.5 )?,
+                specular: entry.fraction_or( "specular", 0.5 )?
+            }
+        },
+        other => return Err( entry.error( "type", &format!( "unknown material type \"{}\"", other ) ) )
+    };
+    Ok( Material { bsdf, emission: entry.vec3_or( "emission", Vec3::ZERO )? } )
+}
+
+fn parse_shape( entry: &Entry, resources: &Resources ) -> Result<Box<dyn Hittable>, SceneError> {
+    // Any shape can be placed by a transform, which applies on top of its own position
+    let Some( value ) = entry.table.get( "transform" ) else { return parse_node( entry, resources ) };
+    let transform = parse_transform( &entry.child( "transform", value )? )?;
+    let mut table = entry.table.clone();
+    table.remove( "transform" );
+    let shape = parse_node( &Entry { name: entry.name.clone(), table: &table }, resources )?;
+    Ok( Box::new( Transformed::new( shape, transform ) ) )
+}
+
+fn parse_transform( entry: &Entry ) -> Result<Mat4, SceneError> {
+    entry.check_fields( &[ "translation", "rotation", "scale" ] )?;
+    let rotation = entry.vec3_or( "rotation", Vec3::ZERO )?;
+    let scale = match entry.table.get( "scale" ) {
+        Some( value ) => as_float( value ).map_or_else( || entry.vec3( "scale" ), | s | Ok( Vec3::splat( s ) ) )?,
+        None => Vec3::ONE
+    };
+    if scale.cmpeq( Vec3::ZERO ).any() {
+        return Err( entry.error( "scale", "must not be zero" ) );
+    }
+    Ok( Mat4::from_scale_rotation_translation(
+        scale,
+        Quat::from_euler( EulerRot::XYZ, rotation.x.to_radians(), rotation.y.to_radians(), rotation.z.to_radians() ),
+        entry.vec3_or( "translation", Vec3::ZERO )?
+    ) )
+}
+
+fn parse_node( entry: &Entry, resources: &Resources ) -> Result<Box<dyn Hittable>, SceneError> {
+    // Groups, combinations and domain operators take their materials from the shapes they are made of
+    match entry.string( "type" )? {
+        "group" => {
+            entry.check_fields( &[ "type", "shapes" ] )?;
+            let group = Group::new( parse_children( entry, resources )? ).ok_or_else( || entry.error( "shapes", "must not be empty" ) )?;
+            return Ok( Box::new( group ) );
+        },
+        "union" => return parse_csg( entry, Operation::Union, resources ),
+        "intersection" => return parse_csg( entry, Operation::Intersection, resources ),
+        "subtraction" => return parse_csg( entry, Operation::Subtraction, resources ),
+        kind @ ( "repeat" | "polar_repeat" | "mirror" | "twist" | "bend" | "elongate" | "round" | "onion" ) => {
+            return parse_domain( entry, kind, resources );
+        },
+        _ => ()
+    }
+
+    // Named materials are shared by every shape that uses them
+    let material = match entry.table.get( "material" ) {
+        Some( Value::String( name ) ) => resources.materials.get( name ).cloned()
+            .ok_or_else( || entry.error( "material", &format!( "unknown material \"{}\"", name ) ) )?,
+        Some( value ) => Arc::new( parse_material( &entry.child( "material", value )? )? ),
+        None => return Err( entry.error( "material", "missing field" ) )
+    };
+
+    match entry.string( "type" )? {
+        "sphere" => {
+            entry.check_fields( &[ "type", "material", "position", "radius" ] )?;
+            Ok( Box::new( Sphere {
+                position: entry.vec3( "position" )?,
+                radius: entry.positive( "radius" )?,
+                material
+            } ) )
+        },
+        "wall" => {
+            entry.check_fields( &[ "type", "material", "position", "size", "rotation" ] )?;
+            // The wall distance function works in local space, so store the inverse of the object rotation
+            let rotation = entry.vec3_or( "rotation", Vec3::ZERO )?;
+            let rotation = Mat4::from_euler( EulerRot::XYZ, rotation.x.to_radians(), rotation.y.to_radians(), rotation.z.to_radians() );
+            Ok( Box::new( Wall {
+                position: entry.vec3( "position" )?,
+                rotation: rotation.inverse(),
+                size: entry.positive_vec3( "size" )?,
+                material
+            } ) )
+        },
+        "torus" => {
+            entry.check_fields( &[ "type", "material", "position", "major_radius", "minor_radius" ] )?;
+            Ok( Box::new( Torus {
+                position: entry.vec3( "position" )?,
+                major_radius: entry.positive( "major_radius" )?,
+                minor_radius: entry.positive( "minor_radius" )?,
+                material
+            } ) )
+        },
+        "capsule" => {
+            entry.check_fields( &[ "type", "material", "a", "b", "radius" ] )?;
+            Ok( Box::new( Capsule { a: entry.vec3( "a" )?, b: entry.vec3( "b" )?, radius: entry.positive( "radius" )?, material } ) )
+        },
+        "cylinder" => {
+            entry.check_fields( &[ "type", "material", "position", "radius", "half_height" ] )?;
+            Ok( Box::new( Cylinder {
+                position: entry.vec3( "position" )?,
+                radius: entry.positive( "radius" )?,
+                half_height: entry.positive( "half_height" )?,
+                material
+            } ) )
+        },
+        "cone" => {
+            entry.check_fields( &[ "type", "material", "position", "half_height", "bottom_radius", "top_radius" ] )?;
+            let top_radius = entry.float_or( "top_radius", 0. )?;
+            if top_radius < 0. {
+                return Err( entry.error( "top_radius", "must not be negative" ) );
+            }
+            Ok( Box::new( Cone {
+                position: entry.vec3( "position" )?,
+                half_height: entry.positive( "half_height" )?,
+                bottom_radius: entry.positive( "bottom_radius" )?,
+                top_radius,
+                material
+            } ) )
+        },
+        "plane" => {
+            entry.check_fields( &[ "type", "material", "normal", "offset" ] )?;
+            Ok( Box::new( Plane { normal: entry.direction( "normal" )?, offset: entry.float_or( "offset", 0. )?, material } ) )
+        },
+        "rounded_box" => {
+            entry.check_fields( &[ "type", "material", "position", "size", "radius" ] )?;
+            let size = entry.positive_vec3( "size" )?;
+            let radius = entry.positive( "radius" )?;
+            if radius > size.min_element() {
+                return Err( entry.error( "radius", "must not be larger than the size" ) );
+            }
+            Ok( Box::new( RoundedBox { position: entry.vec3( "position" )?, size, radius, material } ) )
+        },
+        "ellipsoid" => {
+            entry.check_fields( &[ "type", "material", "position", "radii" ] )?;
+            let radii = entry.positive_vec3( "radii" )?;
+            Ok( Box::new( Ellipsoid { position: entry.vec3( "position" )?, radii, material } ) )
+        },
+        "octahedron" => {
+            entry.check_fields( &[ "type", "material", "position", "size" ] )?;
+            Ok( Box::new( Octahedron { position: entry.vec3( "position" )?, size: entry.positive( "size" )?, material } ) )
+        },
+        "hex_prism" => {
+            entry.check_fields( &[ "type", "material", "position", "radius", "half_length" ] )?;
+            Ok( Box::new( HexPrism {
+                position: entry.vec3( "position" )?,
+                radius: entry.positive( "radius" )?,
+                half_length: entry.positive( "half_length" )?,
+                material
+            } ) )
+        },
+        "triangle" => {
+            entry.check_fields( &[ "type", "material", "a", "b", "c" ] )?;
+            Ok( Box::new( Triangle { a: entry.vec3( "a" )?, b: entry.vec3( "b" )?, c: entry.vec3( "c" )?, material } ) )
+        },
+        "quad" => {
+            entry.check_fields( &[ "type", "material", "a", "b", "c", "d" ] )?;
+            Ok( Box::new( Quad { a: entry.vec3( "a" )?, b: entry.vec3( "b" )?, c: entry.vec3( "c" )?, d: entry.vec3( "d" )?, material } ) )
+        },
+        "mandelbulb" => {
+            entry.check_fields( &[ "type", "material", "position", "power", "iterations" ] )?;
+            Ok( Box::new( Mandelbulb {
+                position: entry.vec3( "position" )?,
+                power: entry.power_or( 8. )?,
+                iterations: entry.iterations_or( 8 )?,
+                material
+            } ) )
+        },
+        "mandelbox" => {
+            entry.check_fields( &[ "type", "material", "position", "scale", "iterations" ] )?;
+            let scale = entry.float_or( "scale", -1.5 )?;
+            if scale.abs() <= 1. {
+                return Err( entry.error( "scale", "must be below -1 or above 1" ) );
+            }
+            Ok( Box::new( Mandelbox { position: entry.vec3( "position" )?, scale, iterations: entry.iterations_or( 12 )?, material } ) )
+        },
+        "menger_sponge" => {
+            entry.check_fields( &[ "type", "material", "position", "size", "iterations" ] )?;
+            Ok( Box::new( MengerSponge {
+                position: entry.vec3( "position" )?,
+                size: entry.positive( "size" )?,
+                iterations: entry.iterations_or( 4 )?,
+                material
+            } ) )
+        },
+        "sierpinski" => {
+            entry.check_fields( &[ "type", "material", "position", "size", "iterations" ] )?;
+            Ok( Box::new( SierpinskiTetrahedron {
+                position: entry.vec3( "position" )?,
+                size: entry.positive( "size" )?,
+                iterations: entry.iterations_or( 8 )?,
+                material
+            } ) )
+        },
+        "julia" => {
+            entry.check_fields( &[ "type", "material", "position", "c", "iterations" ] )?;
+            Ok( Box::new( QuaternionJulia {
+                position: entry.vec3( "position" )?,
+                c: entry.vec4( "c" )?,
+                iterations: entry.iterations_or( 11 )?,
+                material
+            } ) )
+        },
+        "mesh" => {
+            entry.check_fields( &[ "type", "material", "file" ] )?;
+            let list = TriangleList::load( resources.directory.join( entry.string( "file" )? ) )
+                .map_err( | e | entry.error( "file", &e.to_string() ) )?;
+            if list.triangles.is_empty() {
+                return Err( entry.error( "file", "the mesh has no triangles" ) );
+            }
+            Ok( Box::new( Mesh::new( list, material ) ) )
+        },
+        "grid" => {
+            entry.check_fields( &[ "type", "material", "file", "interpolation" ] )?;
+            let interpolation = match if entry.table.contains_key( "interpolation" ) { entry.string( "interpolation" )? } else { "trilinear" } {
+                "trilinear" => Interpolation::Trilinear,
+                "tricubic" => Interpolation::Tricubic,
+                other => return Err( entry.error( "interpolation", &format!( "unknown interpolation \"{}\"", other ) ) )
+            };
+            let grid = DistanceGrid::load( resources.directory.join( entry.string( "file" )? ) )
+                .map_err( | e | entry.error( "file", &e.to_string() ) )?;
+            Ok( Box::new( GridShape { grid: Arc::new( grid ), interpolation, material } ) )
+        },
+        other => Err( entry.error( "type", &format!( "unknown shape type \"{}\"", other ) ) )
+    }
+}
+
+fn parse_csg( entry: &Entry, operation: Operation, resources: &Resources ) -> Result<Box<dyn Hittable>, SceneError> {
+    entry.check_fields( &[ "type", "shapes", "blend", "radius" ] )?;
+    let blend = match if entry.table.contains_key( "blend" ) { entry.string( "blend" )? } else { "sharp" } {
+        "sharp" => Blend::Sharp,
+        "smooth" => Blend::Smooth( entry.positive( "radius" )? ),
+        "chamfer" => Blend::Chamfer( entry.positive( "radius" )? ),
+        "round" => Blend::Round( entry.positive( "radius" )? ),
+        other => return Err( entry.error( "blend", &format!( "unknown blend \"{}\"", other ) ) )
+    };
+    if blend == Blend::Sharp && entry.table.contains_key( "radius" ) {
+        return Err( entry.error( "radius", "only used by smooth, chamfer and round blends" ) );
+    }
+
+    let mut shapes = parse_children( entry, resources )?.into_iter();
+    if shapes.len() < 2 {
+        return Err( entry.error( "shapes", "expected at least two shapes" ) );
+    }
+    // More than two shapes are combined from left to right, so a subtraction removes all others from the first
+    let mut combined = shapes.next().unwrap();
+    for shape in shapes {
+        combined = Box::new( Csg { a: combined, b: shape, operation, blend } );
+    }
+    Ok( combined )
+}
+
+fn parse_domain( entry: &Entry, kind: &str, resources: &Resources ) -> Result<Box<dyn Hittable>, SceneError> {
+    let shape = match entry.get( "shape" )? {
+        value @ Value::Table( _ ) => parse_shape( &entry.child( "shape", value )?, resources )?,
+        _ => return Err( entry.error( "shape", "expected a table" ) )
+    };
+    let unbounded = || entry.error( "shape", "must be bounded" );
+    match kind {
+        "repeat" => {
+            entry.check_fields( &[ "type", "shape", "spacing", "limit" ] )?;
+            let spacing = entry.vec3( "spacing" )?;
+            if spacing.min_element() < 0. {
+                return Err( entry.error( "spacing", "must not be negative" ) );
+            }
+            let repeat = if entry.table.contains_key( "limit" ) {
+                let limit = entry.vec3( "limit" )?;
+                if limit.min_element() < 0. || limit.fract() != Vec3::ZERO {
+                    return Err( entry.error( "limit", "must be whole numbers of at least 0" ) );
+                }
+                Repeat::limited( shape, spacing, limit )
+            } else {
+                Repeat::infinite( shape, spacing )
+            };
+            Ok( Box::new( repeat.ok_or_else( unbounded )? ) )
+        },
+        "polar_repeat" => {
+            entry.check_fields( &[ "type", "shape", "count" ] )?;
+            Ok( Box::new( PolarRepeat { shape, count: entry.count( "count" )? } ) )
+        },
+        "mirror" => {
+            entry.check_fields( &[ "type", "shape", "axes" ] )?;
+            let axes = entry.string( "axes" )?;
+            if axes.is_empty() || !axes.chars().all( | c | "xyz".contains( c ) ) {
+                return Err( entry.error( "axes", "expected a combination of x, y and z" ) );
+            }
+            Ok( Box::new( Mirror { shape, axes: BVec3::new( axes.contains( 'x' ), axes.contains( 'y' ), axes.contains( 'z' ) ) } ) )
+        },
+        "twist" => {
+            entry.check_fields( &[ "type", "shape", "angle" ] )?;
+            Ok( Box::new( Twist::new( shape, entry.float( "angle" )?.to_radians() ).ok_or_else( unbounded )? ) )
+        },
+        "bend" => {
+            entry.check_fields( &[ "type", "shape", "angle" ] )?;
+            Ok( Box::new( Bend::new( shape, entry.float( "angle" )?.to_radians() ).ok_or_else( unbounded )? ) )
+        },
+        "elongate" => {
+            entry.check_fields( &[ "type", "shape", "size" ] )?;
+            let size = entry.vec3( "size" )?;
+            if size.min_element() < 0. {
+                return Err( entry.error( "size", "must not be negative" ) );
+            }
+            Ok( Box::new( Elongate { shape, size } ) )
+        },
+        "round" => {
+            entry.check_fields( &[ "type", "shape", "radius" ] )?;
+            Ok( Box::new( Round { shape, radius: entry.positive( "radius" )? } ) )
+        },
+        _ => {
+            entry.check_fields( &[ "type", "shape", "thickness" ] )?;
+            Ok( Box::new( Onion { shape, thickness: entry.positive( "thickness" )? } ) )
+        }
+    }
+}
+
+// The `shapes` list of a group or combination
+fn parse_children( entry: &Entry, resources: &Resources ) -> Result<Vec<Box<dyn Hittable>>, SceneError> {
+    let shapes = entry.get( "shapes" )?.as_array().ok_or_else( || entry.error( "shapes", "expected an array of tables" ) )?;
+    shapes.iter().enumerate().map( | ( i, value ) | {
+        let name = format!( "{}.shapes[{}]", entry.name, i );
+        let table = value.as_table().ok_or_else( || SceneError::Invalid { entry: name.clone(), field: String::new(), message: "expected a table".to_string() } )?;
+        parse_shape( &Entry { name, table }, resources )
+    } ).collect()
+}
+
+fn parse_light( entry: &Entry ) -> Result<Light, SceneError> {
+    match entry.string( "type" )? {
+        "point" => {
+            entry.check_fields( &[ "type", "position", "intensity" ] )?;
+            Ok( Light::Point { position: entry.vec3( "position" )?, intensity: entry.vec3( "intensity" )? } )
+        },
+        "spot" => {
+            entry.check_fields( &[ "type", "position", "direction", "intensity", "angle", "inner_angle" ] )?;
+            let outer_angle = entry.positive( "angle" )?;
+            let inner_angle = entry.float_or( "inner_angle", outer_angle )?;
+            if inner_angle > outer_angle {
+                return Err( entry.error( "inner_angle", "must not be larger than angle" ) );
+            }
+            Ok( Light::Spot {
+                position: entry.vec3( "position" )?,
+                direction: entry.direction( "direction" )?,
+                intensity: entry.vec3( "intensity" )?,
+                inner_angle: inner_angle.to_radians(),
+                outer_angle: outer_angle.to_radians()
+            } )
+        },
+        "directional" => {
+            entry.check_fields( &[ "type", "direction", "irradiance" ] )?;
+            Ok( Light::Directional { direction: entry.direction( "direction" )?, irradiance: entry.vec3( "irradiance" )? } )
+        },
+        "area" => {
+            entry.check_fields( &[ "type", "corner", "edge_u", "edge_v", "radiance" ] )?;
+            let edge_u = entry.vec3( "edge_u" )?;
+            let edge_v = entry.vec3( "edge_v" )?;
+            if edge_u.cross( edge_v ).length_squared() == 0. {
+                return Err( entry.error( "edge_v", "must not be parallel to edge_u" ) );
+            }
+            Ok( Light::Area { corner: entry.vec3( "corner" )?, edge_u, edge_v, radiance: entry.vec3( "radiance" )? } )
+        },
+        other => Err( entry.error( "type", &format!( "unknown light type \"{}\"", other ) ) )
+    }
+}
+
+// A table in the scene file together with its path, used to produce helpful error messages.
+struct Entry<'a> {
+    name: String,
+    table: &'a Table
+}
+
+impl<'a> Entry<'a> {
+    fn error( &self, field: &str, message: &str ) -> SceneError {
+        SceneError::Invalid { entry: self.name.clone(), field: field.to_string(), message: message.to_string() }
+    }
+
+    fn child( &self, field: &str, value: &'a Value ) -> Result<Entry<'a>, SceneError> {
+        match value {
+            Value::Table( table ) if self.name.is_empty() => Ok( Entry { name: field.to_string(), table } ),
+            Value::Table( table ) => Ok( Entry { name: format!( "{}.{}", self.name, field ), table } ),
+            _ => Err( self.error( field, "expected a table" ) )
+        }
+    }
+
+    fn check_fields( &self, allowed: &[&str] ) -> Result<(), SceneError> {
+        match self.table.keys().find( | key | !allowed.contains( &key.as_str() ) ) {
+            Some( key ) => Err( self.error( key, "unknown field" ) ),
+            None => Ok( () )
+        }
+    }
+
+    fn get( &self, field: &str ) -> Result<&'a Value, SceneError> {
+        self.table.get( field ).ok_or_else( || self.error( field, "missing field" ) )
+    }
+
+    fn float( &self, field: &str ) -> Result<f32, SceneError> {
+        as_float( self.get( field )? ).ok_or_else( || self.error( field, "expected a number" ) )
+    }
+
+    fn float_or( &self, field: &str, default: f32 ) -> Result<f32, SceneError> {
+        if self.table.contains_key( field ) { self.float( field ) } else { Ok( default ) }
+    }
+
+    // A finite number above 0, written so that NaN fails the check as well
+    fn positive( &self, field: &str ) -> Result<f32, SceneError> {
+        let value = self.float( field )?;
+        if !( value > 0. && value.is_finite() ) {
+            return Err( self.error( field, "must be a finite number greater than 0" ) );
+        }
+        Ok( value )
+    }
+
+    fn positive_vec3( &self, field: &str ) -> Result<Vec3, SceneError> {
+        let value = self.vec3( field )?;
+        if !( value.cmpgt( Vec3::ZERO ).all() && value.is_finite() ) {
+            return Err( self.error( field, "must be finite numbers greater than 0" ) );
+        }
+        Ok( value )
+    }
+
+    // A number in [0, 1]
+    fn fraction_or( &self, field: &str, default: f32 ) -> Result<f32, SceneError> {
+        let value = self.float_or( field, default )?;
+        if !( 0. ..=1. ).contains( &value ) {
+            return Err( self.error( field, "must be between 0 and 1" ) );
+        }
+        Ok( value )
+    }
+
+    fn string( &self, field: &str ) -> Result<&'a str, SceneError> {
+        self.get( field )?.as_str().ok_or_else( || self.error( field, "expected a string" ) )
+    }
+
+    fn floats<const N: usize>( &self, field: &str ) -> Result<[f32; N], SceneError> {
+        let error = || self.error( field, &format!( "expected an array of {} numbers", N ) );
+        let array = self.get( field )?.as_array().ok_or_else( error )?;
+        if array.len() != N {
+            return Err( error() );
+        }
+        let mut floats = [ 0.; N ];
+        for ( float, value ) in floats.iter_mut().zip( array ) {
+            *float = as_float( value ).ok_or_else( error )?;
+        }
+        Ok( floats )
+    }
+
+    fn vec3( &self, field: &str ) -> Result<Vec3, SceneError> {
+        self.floats::<3>( field ).map( Vec3::from_array )
+    }
+
+    fn vec4( &self, field: &str ) -> Result<Vec4, SceneError> {
+        self.floats::<4>( field ).map( Vec4::from_array )
+    }
+
+    // A whole number of at least 1
+    fn count( &self, field: &str ) -> Result<u32, SceneError> {
+        match self.get( field )?.as_integer() {
+            Some( count ) if count >= 1 && count <= u32::MAX as i64 => Ok( count as u32 ),
+            _ => Err( self.error( field, "expected a whole number of at least 1" ) )
+        }
+    }
+
+    fn count_or( &self, field: &str, default: u32 ) -> Result<u32, SceneError> {
+        if self.table.contains_key( field ) { self.count( field ) } else { Ok( default ) }
+    }
+
+    // Fractal iterations, capped since each one costs time for every distance estimate
+    fn iterations_or( &self, default: u32 ) -> Result<u32, SceneError> {
+        let iterations = self.count_or( "iterations", default )?;
+        if iterations > MAX_ITERATIONS {
+            return Err( self.error( "iterations", &format!( "must be at most {}", MAX_ITERATIONS ) ) );
+        }
+        Ok( iterations )
+    }
+
+    // The Mandelbulb exponent, written so that NaN fails the check as well
+    fn power_or( &self, default: f32 ) -> Result<f32, SceneError> {
+        let power = self.float_or( "power", default )?;
+        if !( power >= 2. && power.is_finite() ) {
+            return Err( self.error( "power", "must be a finite number of at least 2" ) );
+        }
+        Ok( power )
+    }
+
+    fn direction( &self, field: &str ) -> Result<Vec3, SceneError> {
+        let direction = self.vec3( field )?;
+        if direction.length_squared() == 0. {
+            return Err( self.error( field, "must not be zero" ) );
+        }
+        Ok( direction.normalize() )
+    }
+
+    fn vec3_or( &self, field: &str, default: Vec3 ) -> Result<Vec3, SceneError> {
+        if self.table.contains_key( field ) { self.vec3( field ) } else { Ok( default ) }
+    }
+
+    fn color( &self, field: &str ) -> Result<Vec3, SceneError> {
+        match self.get( field )? {
+            Value::String( hex ) => parse_hex_color( hex ).ok_or_else( || self.error( field, "expected a color of the form \"#rrggbb\"" ) ),
+            _ => self.vec3( field )
+        }
+    }
+
+    fn color_or( &self, field: &str, default: Vec3 ) -> Result<Vec3, SceneError> {
+        if self.table.contains_key( field ) { self.color( field ) } else { Ok( default ) }
+    }
+}
+
+fn as_float( value: &Value ) -> Option<f32> {
+    match value {
+        Value::Float( f ) => Some( *f as f32 ),
+        Value::Integer( i ) => Some( *i as f32 ),
+        _ => None
+    }
+}
+
+fn parse_hex_color( hex: &str ) -> Option<Vec3> {
+    let hex = hex.strip_prefix( '#' )?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = | i: usize | u8::from_str_radix( &hex[ i..i + 2 ], 16 ).ok().map( | c | c as f32 / 255. );
+    Some( Vec3::new( channel( 0 )?, channel( 2 )?, channel( 4 )? ) )
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+    use super::{parse, SceneError};
+    use crate::bvh::Aabb;
+    use crate::grid::DistanceGrid;
+    use crate::material::{Bsdf, Material};
+
+    fn invalid( source: &str ) -> ( String, String ) {
+        match parse( source ) {
+            Err( SceneError::Invalid { entry, field, .. } ) => ( entry, field ),
+            Err( e ) => panic!( "unexpected error: {}", e ),
+            Ok( _ ) => panic!( "scene should not load" )
+        }
+    }
+
+    #[test]
+    fn parse_shapes() {
+        let scene = parse( r##"
+            [materials.mirror]
+            type = "metal"
+            color = "#ff0000"
+
+            [[shapes]]
+            type = "sphere"
+            position = [ 0, 1, 0 ]
+            radius = 2
+            material = "mirror"
+
+            [[shapes]]
+            type = "wall"
+            position = [ 0, -10, 0 ]
+            size = [ 100, 0.1, 100 ]
+            material = { color = [ 0.5, 0.5, 0.5 ] }
+        "## ).unwrap();
+
+        let content = scene.world.content();
+        assert_eq!( content.len(), 2 );
+        assert_eq!( content[ 0 ].distance( Vec3::new( 0., 4., 0. ) ), 1. );
+        assert_eq!( *content[ 0 ].material(), Material::mirror( Vec3::new( 1., 0., 0. ) ) );
+        assert!( !content[ 1 ].material().is_mirror() );
+    }
+
+    #[test]
+    fn parse_primitives() {
+        let scene = parse( r#"
+            [materials.white]
+            color = [ 1, 1, 1 ]
+
+            [[shapes]]
+            type = "torus"
+            position = [ 0, 0, 0 ]
+            major_radius = 2
+            minor_radius = 0.5
+            material = "white"
+
+            [[shapes]]
+            type = "capsule"
+            a = [ 0, 0, 0 ]
+            b = [ 0, 1, 0 ]
+            radius = 0.5
+            material = "white"
+
+            [[shapes]]
+            type = "cylinder"
+            position = [ 0, 0, 0 ]
+            radius = 1
+            half_height = 2
+            material = "white"
+
+            [[shapes]]
+            type = "cone"
+            position = [ 0, 0, 0 ]
+            half_height = 1
+            bottom_radius = 1
+            material = "white"
+
+            [[shapes]]
+            type = "plane"
+            normal = [ 0, 2, 0 ]
+            offset = -1
+            material = "white"
+
+            [[shapes]]
+            type = "rounded_box"
+            position = [ 0, 0, 0 ]
+            size = [ 1, 1, 1 ]
+            radius = 0.1
+            material = "white"
+
+            [[shapes]]
+            type = "ellipsoid"
+            position = [ 0, 0, 0 ]
+            radii = [ 1, 2, 3 ]
+            material = "white"
+
+            [[shapes]]
+            type = "octahedron"
+            position = [ 0, 0, 0 ]
+            size = 1
+            material = "white"
+
+            [[shapes]]
+            type = "hex_prism"
+            position = [ 0, 0, 0 ]
+            radius = 1
+            half_length = 1
+            material = "white"
+
+            [[shapes]]
+            type = "triangle"
+            a = [ 0, 0, 0 ]
+            b = [ 1, 0, 0 ]
+            c = [ 0, 1, 0 ]
+            material = "white"
+
+            [[shapes]]
+            type = "quad"
+            a = [ 0, 0, 0 ]
+            b = [ 1, 0, 0 ]
+            c = [ 1, 1, 0 ]
+            d = [ 0, 1, 0 ]
+            material = "white"
+        "# ).unwrap();
+
+        let content = scene.world.content();
+        assert_eq!( content.len(), 11 );
+        // The plane normal is normalized
+        assert_eq!( content[ 4 ].distance( Vec3::new( 0., 1., 0. ) ), 2. );
+
+        let ( entry, field ) = invalid( r#"
+            [[shapes]]
+            type = "rounded_box"
+            position = [ 0, 0, 0 ]
+            size = [ 1, 0.5, 1 ]
+            radius = 0.6
+            material = { color = [ 1, 1, 1 ] }
+        "# );
+        assert_eq!( ( entry.as_str(), field.as_str() ), ( "shapes[0]", "radius" ) );
+    }
+
+    #[test]
+    fn parse_materials() {
+        let scene = parse( r#"
+            [materials.glass]
+            type = "dielectric"
+            ior = 1.33
+            absorption = [ 0.1, 0.05, 0 ]
+
+            [materials.plastic]
+            type = "principled"
+            color = [ 0.8, 0.1, 0.1 ]
+            roughness = 0.3
+
+            [[shapes]]
+            type = "sphere"
+            position = [ 0, 0, 0 ]
+            radius = 1
+            material = "glass"
+
+            [[shapes]]
+            type = "sphere"
+            position = [ 3, 0, 0 ]
+            radius = 1
+            material = "glass"
+
+            [[shapes]]
+            type = "sphere"
+            position = [ 6, 0, 0 ]
+            radius = 1
+            material = "plastic"
+
+            [[shapes]]
+            type = "sphere"
+            position = [ 9, 0, 0 ]
+            radius = 1
+            material = { type = "emissive", emission = [ 2, 2, 2 ] }
+        "# ).unwrap();
+
+        let content = scene.world.content();
+        assert_eq!( content[ 0 ].material().bsdf, Bsdf::Dielectric { ior: 1.33, tint: Vec3::ONE, absorption: Vec3::new( 0.1, 0.05, 0. ) } );
+        // Shapes share named materials
+        assert!( std::ptr::eq( content[ 0 ].material(), content[ 1 ].material() ) );
+        assert_eq!( content[ 2 ].material().bsdf, Bsdf::Principled { base_color: Vec3::new( 0.8, 0.1, 0.1 ), metallic: 0., roughness: 0.3, specular: 0.5 } );
+        assert_eq!( content[ 3 ].material().emission, Vec3::splat( 2. ) );
+        assert_eq!( scene.world.lights.len(), 1 );
+
+        let ( entry, field ) = invalid( r#"
+            [materials.rough]
+            type = "metal"
+            color = [ 1, 1, 1 ]
+            roughness = 2
+        "# );
+        assert_eq!( ( entry.as_str(), field.as_str() ), ( "materials.rough", "roughness" ) );
+    }
+
+    #[test]
+    fn parse_csg() {
+        let scene = parse( r#"
+            [[shapes]]
+            type = "subtraction"
+            shapes = [
+                { type = "sphere", position = [ 0, 0, 0 ], radius = 2, material = { color = [ 1, 0, 0 ] } },
+                { type = "sphere", position = [ 2, 0, 0 ], radius = 1, material = { color = [ 0, 1, 0 ] } },
+                { type = "sphere", position = [ -2, 0, 0 ], radius = 1, material = { color = [ 0, 0, 1 ] } }
+            ]
+
+            [[shapes]]
+            type = "union"
+            blend = "smooth"
+            radius = 0.5
+            shapes = [
+                { type = "sphere", position = [ 0, 0, 0 ], radius = 1, material = { color = [ 1, 0, 0 ] } },
+                { type = "sphere", position = [ 2, 0, 0 ], radius = 1, material = { color = [ 0, 1, 0 ] } }
+            ]
+        "# ).unwrap();
+
+        let content = scene.world.content();
+        assert_eq!( content.len(), 2 );
+        // Both later spheres are cut away from the first
+        assert_eq!( content[ 0 ].distance( Vec3::new( 1.5, 0., 0. ) ), 0.5 );
+        assert_eq!( content[ 0 ].distance( Vec3::new( -1.5, 0., 0. ) ), 0.5 );
+        assert_eq!( content[ 0 ].distance( Vec3::ZERO ), -1. );
+        assert!( content[ 1 ].distance( Vec3::new( 1., 0., 0. ) ) < 0. );
+
+        let ( entry, field ) = invalid( r#"
+            [[shapes]]
+            type = "union"
+            shapes = [
+                { type = "sphere", position = [ 0, 0, 0 ], radius = 1, material = { color = [ 1, 0, 0 ] } },
+                { type = "sphere", position = [ 2, 0, 0 ], radius = 1 }
+            ]
+        "# );
+        assert_eq!( ( entry.as_str(), field.as_str() ), ( "shapes[0].shapes[1]", "material" ) );
+
+        let ( entry, field ) = invalid( r#"
+            [[shapes]]
+            type = "intersection"
+            blend = "round"
+            shapes = [
+                { type = "sphere", position = [ 0, 0, 0 ], radius = 1, material = { color = [ 1, 0, 0 ] } },
+                { type = "sphere", position = [ 1, 0, 0 ], radius = 1, material = { color = [ 1, 0, 0 ] } }
+            ]
+        "# );
+        assert_eq!( ( entry.as_str(), field.as_str() ), ( "shapes[0]", "radius" ) );
+    }
+
+    #[test]
+    fn parse_transforms() {
+        let scene = parse( r#"
+            [[shapes]]
+            type = "group"
+            transform = { translation = [ 0, 0, 10 ], rotation = [ 0, 0, 90 ] }
+            shapes = [
+                { type = "sphere", position = [ 2, 0, 0 ], radius = 1, material = { color = [ 1, 0, 0 ] } },
+                { type = "sphere", position = [ 0, 0, 0 ], radius = 1, material = { color = [ 0, 1, 0 ] }, transform = { scale = [ 1, 3, 1 ] } }
+            ]
+
+            [[shapes]]
+            type = "sphere"
+            position = [ 0, 0, 0 ]
+            radius = 1
+            material = { color = [ 1, 1, 1 ] }
+            transform = { translation = [ 5, 0, 0 ], scale = 2 }
+        "# ).unwrap();
+
+        let content = scene.world.content();
+        // The first sphere is turned onto the y axis, the stretched one lies along x
+        assert!( ( content[ 0 ].distance( Vec3::new( 0., 2., 10. ) ) + 1. ).abs() < 1e-5 );
+        assert!( content[ 0 ].distance( Vec3::new( -2.5, 0., 10. ) ) < 0. );
+        assert!( ( content[ 1 ].distance( Vec3::new( 8., 0., 0. ) ) - 1. ).abs() < 1e-5 );
+        assert_eq!( content[ 0 ].material_at( Vec3::new( 0., 2., 10. ) ).bsdf, Bsdf::Lambertian { albedo: Vec3::X } );
+
+        let ( entry, field ) = invalid( r#"
+            [[shapes]]
+            type = "sphere"
+            position = [ 0, 0, 0 ]
+            radius = 1
+            material = { color = [ 1, 1, 1 ] }
+            transform = { scale = [ 1, 0, 1 ] }
+        "# );
+        assert_eq!( ( entry.as_str(), field.as_str() ), ( "shapes[0].transform", "scale" ) );
+    }
+
+    #[test]
+    fn parse_domain_operators() {
+        let scene = parse( r#"
+            [[shapes]]
+            type = "mirror"
+            axes = "xz"
+            shape = { type = "sphere", position = [ 2, 0, 2 ], radius = 1, material = { color = [ 1, 1, 1 ] } }
+
+            [[shapes]]
+            type = "repeat"
+            spacing = [ 4, 0, 0 ]
+            limit = [ 2, 0, 0 ]
+            shape = { type = "onion", thickness = 0.1, shape = { type = "sphere", position = [ 0, 10, 0 ], radius = 1, material = { color = [ 1, 1, 1 ] } } }
+        "# ).unwrap();
+
+        let content = scene.world.content();
+        assert_eq!( content[ 0 ].distance( Vec3::new( -2., 0., -2. ) ), -1. );
+        assert_eq!( content[ 0 ].distance( Vec3::new( 2., 0., -2. ) ), -1. );
+        assert!( ( content[ 1 ].distance( Vec3::new( -8., 10., 0. ) ) - 0.9 ).abs() < 1e-6 );
+        assert!( content[ 1 ].distance( Vec3::new( -12., 10., 0. ) ) > 1. );
+
+        let ( entry, field ) = invalid( r#"
+            [[shapes]]
+            type = "twist"
+            angle = 20
+            shape = { type = "plane", normal = [ 0, 1, 0 ], material = { color = [ 1, 1, 1 ] } }
+        "# );
+        assert_eq!( ( entry.as_str(), field.as_str() ), ( "shapes[0]", "shape" ) );
+
+        let ( entry, field ) = invalid( r#"
+            [[shapes]]
+            type = "round"
+            radius = 0.1
+            shape = { type = "sphere", position = [ 0, 0, 0 ], radius = 1 }
+        "# );
+        assert_eq!( ( entry.as_str(), field.as_str() ), ( "shapes[0].shape", "material" ) );
+    }
+
+    #[test]
+    fn parse_mesh() {
+        let scene = parse( r#"
+            [[shapes]]
+            type = "mesh"
+            file = "scenes/meshes/icosahedron.obj"
+            material = { color = [ 1, 1, 1 ] }
+        "# ).unwrap();
+        let mesh = &scene.world.content()[ 0 ];
+        assert!( mesh.distance( Vec3::ZERO ) < -0.7 );
+        assert!( ( mesh.distance( Vec3::new( 0., 3., 0. ) ) - 2. ).abs() < 0.3 );
+
+        let ( entry, field ) = invalid( r#"
+            [[shapes]]
+            type = "mesh"
+            file = "scenes/meshes/missing.ply"
+            material = { color = [ 1, 1, 1 ] }
+        "# );
+        assert_eq!( ( entry.as_str(), field.as_str() ), ( "shapes[0]", "file" ) );
+    }
+
+    #[test]
+    fn parse_grid() {
+        let grid = DistanceGrid::bake( | p | p.length() - 1., Aabb::new( Vec3::splat( -1.5 ), Vec3::splat( 1.5 ) ), 0.25, 1 ).unwrap();
+        let path = std::env::temp_dir().join( format!( "rvk-scene-grid-{}.sdf", std::process::id() ) );
+        grid.save( &path ).unwrap();
+        let source = format!( r#"
+            [[shapes]]
+            type = "grid"
+            file = '{}'
+            interpolation = "tricubic"
+            material = {{ color = [ 1, 1, 1 ] }}
+        "#, path.display() );
+        let scene = parse( &source );
+        let _ = std::fs::remove_file( &path );
+        let scene = scene.unwrap();
+        let shape = &scene.world.content()[ 0 ];
+        assert!( ( shape.distance( Vec3::new( 0., 0.5, 0. ) ) + 0.5 ).abs() < 0.01 );
+        // Outside the grid the distance is a lower bound
+        let far = shape.distance( Vec3::new( 0., 3., 0. ) );
+        assert!( far > 1.5 && far <= 2., "{}", far );
+
+        let ( entry, field ) = invalid( &source.replace( "tricubic", "nearest" ) );
+        assert_eq!( ( entry.as_str(), field.as_str() ), ( "shapes[0]", "interpolation" ) );
+    }
+
+    #[test]
+    fn parse_fractals() {
+        let scene = parse( r#"
+            [materials.white]
+            color = [ 1, 1, 1 ]
+
+            [[shapes]]
+            type = "mandelbulb"
+            position = [ 0, 0, 0 ]
+            material = "white"
+
+            [[shapes]]
+            type = "mandelbox"
+            position = [ 10, 0, 0 ]
+            scale = 2
+            iterations = 6
+            material = "white"
+
+            [[shapes]]
+            type = "menger_sponge"
+            position = [ 20, 0, 0 ]
+            size = 1
+            material = "white"
+
+            [[shapes]]
+            type = "sierpinski"
+            position = [ 30, 0, 0 ]
+            size = 1
+            iterations = 5
+            material = "white"
+
+            [[shapes]]
+            type = "julia"
+            position = [ 40, 0, 0 ]
+            c = [ -0.2, 0.6, 0.2, 0.2 ]
+            material = "white"
+        "# ).unwrap();
+        let content = scene.world.content();
+        assert_eq!( content.len(), 5 );
+        assert!( content.iter().all( | shape | shape.orbit_trap( Vec3::ZERO ).is_some() ) );
+        assert_eq!( content[ 2 ].distance( Vec3::new( 20., 0., -2. ) ), 1. );
+
+        let ( entry, field ) = invalid( r#"
+            [[shapes]]
+            type = "mandelbulb"
+            position = [ 0, 0, 0 ]
+            iterations = 0
+            material = { color = [ 1, 1, 1 ] }
+        "# );
+        assert_eq!( ( entry.as_str(), field.as_str() ), ( "shapes[0]", "iterations" ) );
+        let ( _, field ) = invalid( r#"
+            [[shapes]]
+            type = "menger_sponge"
+            position = [ 0, 0, 0 ]
+            size = 1
+            iterations = 4294967295
+            material = { color = [ 1, 1, 1 ] }
+        "# );
+        assert_eq!( field, "iterations" );
+        for power in [ "1.5", "nan", "inf" ] {
+            let ( _, field ) = invalid( &format!( r#"
+                [[shapes]]
+                type = "mandelbulb"
+                position = [ 0, 0, 0 ]
+                power = {}
+                material = {{ color = [ 1, 1, 1 ] }}
+            "#, power ) );
+            assert_eq!( field, "power" );
+        }
+        let ( _, field ) = invalid( r#"
+            [[shapes]]
+            type = "julia"
+            position = [ 0, 0, 0 ]
+            c = [ 1, 2, 3 ]
+            material = { color = [ 1, 1, 1 ] }
+        "# );
+        assert_eq!( field, "c" );
+    }
+
+    #[test]
+    fn parse_lights() {
+        let scene = parse( r#"
+            [[shapes]]
+            type = "sphere"
+            position = [ 0, 1, 0 ]
+            radius = 2
+            material = { color = [ 0, 0, 0 ], emission = [ 4, 4, 4 ] }
+
+            [[lights]]
+            type = "point"
+            position = [ 0, 5, 0 ]
+            intensity = [ 10, 10, 10 ]
+
+            [[lights]]
+            type = "spot"
+            position = [ 0, 5, 0 ]
+            direction = [ 0, -1, 0 ]
+            intensity = [ 10, 10, 10 ]
+            angle = 30
+
+            [[lights]]
+            type = "directional"
+            direction = [ 0, -1, 0 ]
+            irradiance = [ 1, 1, 1 ]
+
+            [[lights]]
+            type = "area"
+            corner = [ -1, 4, -1 ]
+            edge_u = [ 0, 0, 2 ]
+            edge_v = [ 2, 0, 0 ]
+            radiance = [ 5, 5, 5 ]
+        "# ).unwrap();
+
+        assert_eq!( scene.world.lights.len(), 5 );
+    }
+
+    #[test]
+    fn report_wrong_light() {
+        let ( entry, field ) = invalid( r#"
+            [[lights]]
+            type = "spot"
+            position = [ 0, 5, 0 ]
+            direction = [ 0, 0, 0 ]
+            intensity = [ 10, 10, 10 ]
+            angle = 30
+        "# );
+        assert_eq!( entry, "lights[0]" );
+        assert_eq!( field, "direction" );
+    }
+
+    #[test]
+    fn report_wrong_field() {
+        let ( entry, field ) = invalid( r#"
+            [[shapes]]
+            type = "sphere"
+            position = [ 0, 0, 0 ]
+            radius = 1
+            material = { color = [ 1, 1, 1 ] }
+
+            [[shapes]]
+            type = "sphere"
+            position = [ 0, 0 ]
+            radius = 1
+            material = { color = [ 1, 1, 1 ] }
+        "# );
+        assert_eq!( entry, "shapes[1]" );
+        assert_eq!( field, "position" );
+    }
+
+    #[test]
+    fn report_nan_and_negative_sizes() {
+        let ( entry, field ) = invalid( r#"
+            [[shapes]]
+            type = "sphere"
+            position = [ 0, 0, 0 ]
+            radius = nan
+            material = { color = [ 1, 1, 1 ] }
+        "# );
+        assert_eq!( ( entry.as_str(), field.as_str() ), ( "shapes[0]", "radius" ) );
+
+        for size in [ "[ 1, -1, 1 ]", "[ 1, nan, 1 ]", "[ inf, 1, 1 ]" ] {
+            let ( entry, field ) = invalid( &format!( r#"
+                [[shapes]]
+                type = "wall"
+                position = [ 0, 0, 0 ]
+                size = {}
+                material = {{ color = [ 1, 1, 1 ] }}
+            "#, size ) );
+            assert_eq!( ( entry.as_str(), field.as_str() ), ( "shapes[0]", "size" ), "{}", size );
+        }
+    }
+
+    #[test]
+    fn report_unknown_material() {
+        let ( entry, field ) = invalid( r#"
+            [[shapes]]
+            type = "sphere"
+            position = [ 0, 0, 0 ]
+            radius = 1
+            material = "glass"
+        "# );
+        assert_eq!( entry, "shapes[0]" );
+        assert_eq!( field, "material" );
+    }
+
+    #[test]
+    fn report_unknown_field() {
+        let ( entry, field ) = invalid( r#"
+            [materials.white]
+            colour = [ 1, 1, 1 ]
+        "# );
+        assert_eq!( entry, "materials.white" );
+        assert_eq!( field, "colour" );
+    }
+}
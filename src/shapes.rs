@@ -0,0 +1,528 @@
+// Signed distance functions for more primitives, next to `rays::Sphere` and `rays::Wall`.
+// Unless noted otherwise the distances are exact, see https://iquilezles.org/articles/distfunctions/
+// Shapes are given in world space without rotation.
+
+use std::sync::Arc;
+use glam::{Vec2, Vec3};
+use crate::bvh::Aabb;
+use crate::material::Material;
+use crate::rays::{box_gradient, Hittable};
+
+fn dot2( v: Vec3 ) -> f32 {
+    v.dot( v )
+}
+
+// Ring around the y axis
+pub struct Torus {
+    pub position: Vec3,
+    // Radius of the ring
+    pub major_radius: f32,
+    // Radius of the tube
+    pub minor_radius: f32,
+    pub material: Arc<Material>
+}
+
+impl Hittable for Torus {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let p = pos - self.position;
+        let q = Vec2::new( Vec2::new( p.x, p.z ).length() - self.major_radius, p.y );
+        q.length() - self.minor_radius
+    }
+
+    fn gradient( &self, pos: Vec3 ) -> Option<Vec3> {
+        let p = pos - self.position;
+        let radial = Vec2::new( p.x, p.z ).normalize_or_zero();
+        let q = Vec2::new( Vec2::new( p.x, p.z ).length() - self.major_radius, p.y );
+        Some( Vec3::new( radial.x * q.x, q.y, radial.y * q.x ) )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let outer = self.major_radius + self.minor_radius;
+        let extent = Vec3::new( outer, self.minor_radius, outer );
+        Aabb::new( self.position - extent, self.position + extent )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// All points within radius of the line segment from a to b
+pub struct Capsule {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub radius: f32,
+    pub material: Arc<Material>
+}
+
+impl Hittable for Capsule {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let pa = pos - self.a;
+        let ba = self.b - self.a;
+        let h = if ba == Vec3::ZERO { 0. } else { ( pa.dot( ba ) / ba.dot( ba ) ).clamp( 0., 1. ) };
+        ( pa - ba * h ).length() - self.radius
+    }
+
+    fn gradient( &self, pos: Vec3 ) -> Option<Vec3> {
+        let pa = pos - self.a;
+        let ba = self.b - self.a;
+        let h = if ba == Vec3::ZERO { 0. } else { ( pa.dot( ba ) / ba.dot( ba ) ).clamp( 0., 1. ) };
+        Some( pa - ba * h )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        Aabb::new( self.a.min( self.b ) - self.radius, self.a.max( self.b ) + self.radius )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// Capped cylinder along the y axis
+pub struct Cylinder {
+    pub position: Vec3,
+    pub radius: f32,
+    pub half_height: f32,
+    pub material: Arc<Material>
+}
+
+impl Hittable for Cylinder {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let p = pos - self.position;
+        let d = Vec2::new( Vec2::new( p.x, p.z ).length(), p.y ).abs() - Vec2::new( self.radius, self.half_height );
+        d.max_element().min( 0. ) + d.max( Vec2::ZERO ).length()
+    }
+
+    fn gradient( &self, pos: Vec3 ) -> Option<Vec3> {
+        let p = pos - self.position;
+        let radial = Vec2::new( p.x, p.z ).normalize_or_zero();
+        let d = Vec2::new( Vec2::new( p.x, p.z ).length(), p.y ).abs() - Vec2::new( self.radius, self.half_height );
+        let g = if d.max_element() > 0. { d.max( Vec2::ZERO ) } else if d.x >= d.y { Vec2::X } else { Vec2::Y };
+        Some( Vec3::new( radial.x * g.x, g.y.copysign( p.y ), radial.y * g.x ) )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let extent = Vec3::new( self.radius, self.half_height, self.radius );
+        Aabb::new( self.position - extent, self.position + extent )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// Capped cone along the y axis, a top radius of 0 gives a pointed cone
+pub struct Cone {
+    pub position: Vec3,
+    pub half_height: f32,
+    pub bottom_radius: f32,
+    pub top_radius: f32,
+    pub material: Arc<Material>
+}
+
+impl Hittable for Cone {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let p = pos - self.position;
+        let ( h, r1, r2 ) = ( self.half_height, self.bottom_radius, self.top_radius );
+        let q = Vec2::new( Vec2::new( p.x, p.z ).length(), p.y );
+        let k1 = Vec2::new( r2, h );
+        let k2 = Vec2::new( r2 - r1, 2. * h );
+        // Closest point on the caps and on the slanted side
+        let ca = Vec2::new( q.x - q.x.min( if q.y < 0. { r1 } else { r2 } ), q.y.abs() - h );
+        let cb = q - k1 + k2 * ( ( k1 - q ).dot( k2 ) / k2.dot( k2 ) ).clamp( 0., 1. );
+        let sign = if cb.x < 0. && ca.y < 0. { -1. } else { 1. };
+        sign * f32::sqrt( f32::min( ca.dot( ca ), cb.dot( cb ) ) )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        let radius = self.bottom_radius.max( self.top_radius );
+        let extent = Vec3::new( radius, self.half_height, radius );
+        Aabb::new( self.position - extent, self.position + extent )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// Everything below the plane through normal * offset is inside
+pub struct Plane {
+    // Must be normalized
+    pub normal: Vec3,
+    pub offset: f32,
+    pub material: Arc<Material>
+}
+
+impl Hittable for Plane {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        pos.dot( self.normal ) - self.offset
+    }
+
+    fn gradient( &self, _pos: Vec3 ) -> Option<Vec3> {
+        Some( self.normal )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// Box with rounded edges, `size` holds the half extents including the rounding
+pub struct RoundedBox {
+    pub position: Vec3,
+    pub size: Vec3,
+    pub radius: f32,
+    pub material: Arc<Material>
+}
+
+impl Hittable for RoundedBox {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let q = ( pos - self.position ).abs() - self.size + self.radius;
+        q.max( Vec3::ZERO ).length() + q.max_element().min( 0. ) - self.radius
+    }
+
+    fn gradient( &self, pos: Vec3 ) -> Option<Vec3> {
+        let p = pos - self.position;
+        Some( box_gradient( p, p.abs() - self.size + self.radius ) )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        Aabb::new( self.position - self.size, self.position + self.size )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// Not exact, but never larger than the real distance and exact along the axes
+pub struct Ellipsoid {
+    pub position: Vec3,
+    pub radii: Vec3,
+    pub material: Arc<Material>
+}
+
+impl Hittable for Ellipsoid {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let p = pos - self.position;
+        let k0 = ( p / self.radii ).length();
+        let k1 = ( p / ( self.radii * self.radii ) ).length();
+        if k1 == 0. {
+            return -self.radii.min_element();
+        }
+        k0 * ( k0 - 1. ) / k1
+    }
+
+    fn bounds( &self ) -> Aabb {
+        Aabb::new( self.position - self.radii, self.position + self.radii )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// Regular octahedron with its corners at size along each axis
+pub struct Octahedron {
+    pub position: Vec3,
+    pub size: f32,
+    pub material: Arc<Material>
+}
+
+impl Hittable for Octahedron {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let p = ( pos - self.position ).abs();
+        let s = self.size;
+        let m = p.x + p.y + p.z - s;
+        // Rotate the closest corner region into place, or use the distance to the face
+        let q = if 3. * p.x < m {
+            p
+        } else if 3. * p.y < m {
+            Vec3::new( p.y, p.z, p.x )
+        } else if 3. * p.z < m {
+            Vec3::new( p.z, p.x, p.y )
+        } else {
+            return m * 0.577_350_26;
+        };
+        let k = ( 0.5 * ( q.z - q.y + s ) ).clamp( 0., s );
+        Vec3::new( q.x, q.y - s + k, q.z - k ).length()
+    }
+
+    fn bounds( &self ) -> Aabb {
+        Aabb::new( self.position - self.size, self.position + self.size )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// Hexagonal prism along the z axis with flat sides at `radius` above and below its center
+pub struct HexPrism {
+    pub position: Vec3,
+    pub radius: f32,
+    pub half_length: f32,
+    pub material: Arc<Material>
+}
+
+impl Hittable for HexPrism {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let k = Vec3::new( -0.866_025_4, 0.5, 0.577_350_26 );
+        let p = ( pos - self.position ).abs();
+        // Fold the point into the first sixth of the hexagon
+        let xy = Vec2::new( p.x, p.y ) - 2. * Vec2::new( k.x, k.y ).dot( Vec2::new( p.x, p.y ) ).min( 0. ) * Vec2::new( k.x, k.y );
+        let edge = Vec2::new( xy.x.clamp( -k.z * self.radius, k.z * self.radius ), self.radius );
+        let d = Vec2::new(
+            ( xy - edge ).length() * ( xy.y - self.radius ).signum(),
+            p.z - self.half_length
+        );
+        d.max_element().min( 0. ) + d.max( Vec2::ZERO ).length()
+    }
+
+    fn bounds( &self ) -> Aabb {
+        // The corners are further out than the flat sides
+        let corner = self.radius * 2. * 0.577_350_26;
+        let extent = Vec3::new( corner, corner, self.half_length );
+        Aabb::new( self.position - extent, self.position + extent )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// A single triangle without thickness, the distance is never negative
+pub struct Triangle {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub c: Vec3,
+    pub material: Arc<Material>
+}
+
+impl Hittable for Triangle {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let ( ba, pa ) = ( self.b - self.a, pos - self.a );
+        let ( cb, pb ) = ( self.c - self.b, pos - self.b );
+        let ( ac, pc ) = ( self.a - self.c, pos - self.c );
+        let nor = ba.cross( ac );
+
+        // Outside of one of the edges the closest point is on an edge, otherwise on the face
+        let inside = ba.cross( nor ).dot( pa ).signum() + cb.cross( nor ).dot( pb ).signum() + ac.cross( nor ).dot( pc ).signum() >= 2.;
+        f32::sqrt( if inside {
+            nor.dot( pa ) * nor.dot( pa ) / dot2( nor )
+        } else {
+            edge_distance2( ba, pa ).min( edge_distance2( cb, pb ) ).min( edge_distance2( ac, pc ) )
+        } )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        Aabb::from_points( &[ self.a, self.b, self.c ] )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// A planar quad without thickness, the corners must be given in order around the edge
+pub struct Quad {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub c: Vec3,
+    pub d: Vec3,
+    pub material: Arc<Material>
+}
+
+impl Hittable for Quad {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        let ( ba, pa ) = ( self.b - self.a, pos - self.a );
+        let ( cb, pb ) = ( self.c - self.b, pos - self.b );
+        let ( dc, pc ) = ( self.d - self.c, pos - self.c );
+        let ( ad, pd ) = ( self.a - self.d, pos - self.d );
+        let nor = ba.cross( ad );
+
+        let inside = ba.cross( nor ).dot( pa ).signum() + cb.cross( nor ).dot( pb ).signum()
+            + dc.cross( nor ).dot( pc ).signum() + ad.cross( nor ).dot( pd ).signum() >= 3.;
+        f32::sqrt( if inside {
+            nor.dot( pa ) * nor.dot( pa ) / dot2( nor )
+        } else {
+            edge_distance2( ba, pa ).min( edge_distance2( cb, pb ) ).min( edge_distance2( dc, pc ) ).min( edge_distance2( ad, pd ) )
+        } )
+    }
+
+    fn bounds( &self ) -> Aabb {
+        Aabb::from_points( &[ self.a, self.b, self.c, self.d ] )
+    }
+
+    fn material( &self ) -> & Material {
+        & self.material
+    }
+}
+
+// Squared distance from the start of an edge offset by p to the edge
+fn edge_distance2( edge: Vec3, p: Vec3 ) -> f32 {
+    dot2( edge * ( edge.dot( p ) / dot2( edge ) ).clamp( 0., 1. ) - p )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use glam::Vec3;
+    use super::{Capsule, Cone, Cylinder, Ellipsoid, HexPrism, Octahedron, Plane, Quad, RoundedBox, Torus, Triangle};
+    use crate::material::Material;
+    use crate::rays::{numeric_normal, Hittable};
+
+    fn material() -> Arc<Material> {
+        Arc::new( Material::lambertian( Vec3::ONE ) )
+    }
+
+    fn assert_distances( shape: &dyn Hittable, expected: &[( Vec3, f32 )] ) {
+        for &( pos, distance ) in expected {
+            let found = shape.distance( pos );
+            assert!( ( found - distance ).abs() < 1e-5, "distance at {} is {}, expected {}", pos, found, distance );
+            // The distance may never be smaller than the distance to the bounds
+            assert!( shape.bounds().distance( pos ) <= found.max( 0. ) + 1e-5 );
+        }
+    }
+
+    // Analytic normals match numeric ones, at points away from edges and the middle of the shape
+    fn assert_gradients( shape: &dyn Hittable, points: &[Vec3] ) {
+        for &pos in points {
+            let ( analytic, numeric ) = ( shape.calc_normal( pos ), numeric_normal( shape, pos ) );
+            assert!( ( analytic - numeric ).length() < 1e-2, "{} against {} at {}", analytic, numeric, pos );
+        }
+    }
+
+    #[test]
+    fn analytic_normals() {
+        let points = [ Vec3::new( 2.3, 1.4, 0.5 ), Vec3::new( -0.7, 0.2, 1.9 ), Vec3::new( 0.3, -1.6, -0.8 ), Vec3::new( 1.1, 0.9, -2.4 ) ];
+        assert_gradients( &Torus { position: Vec3::new( 0., 0.5, 0. ), major_radius: 1.5, minor_radius: 0.4, material: material() }, &points );
+        assert_gradients( &Capsule { a: Vec3::new( -1., 0., 0. ), b: Vec3::new( 1., 1., 0. ), radius: 0.5, material: material() }, &points );
+        assert_gradients( &Cylinder { position: Vec3::ZERO, radius: 1., half_height: 1.2, material: material() }, &points );
+        assert_gradients( &Plane { normal: Vec3::new( 1., 2., 0. ).normalize(), offset: 0.3, material: material() }, &points );
+        assert_gradients( &RoundedBox { position: Vec3::new( 0., 0.2, 0. ), size: Vec3::new( 1., 0.8, 1.5 ), radius: 0.2, material: material() }, &points );
+    }
+
+    #[test]
+    fn torus() {
+        let torus = Torus { position: Vec3::new( 0., 1., 0. ), major_radius: 2., minor_radius: 0.5, material: material() };
+        assert_distances( &torus, &[
+            ( Vec3::new( 2., 1., 0. ), -0.5 ),
+            ( Vec3::new( 0., 1., 0. ), 1.5 ),
+            ( Vec3::new( 0., 2., -2. ), 0.5 ),
+            ( Vec3::new( 4., 1., 0. ), 1.5 )
+        ] );
+    }
+
+    #[test]
+    fn capsule() {
+        let capsule = Capsule { a: Vec3::ZERO, b: Vec3::new( 0., 2., 0. ), radius: 0.5, material: material() };
+        assert_distances( &capsule, &[
+            ( Vec3::new( 1., 1., 0. ), 0.5 ),
+            ( Vec3::new( 0., 4., 0. ), 1.5 ),
+            ( Vec3::new( 0., -1., 0. ), 0.5 ),
+            ( Vec3::new( 0., 1., 0. ), -0.5 )
+        ] );
+    }
+
+    #[test]
+    fn cylinder() {
+        let cylinder = Cylinder { position: Vec3::ZERO, radius: 1., half_height: 2., material: material() };
+        assert_distances( &cylinder, &[
+            ( Vec3::ZERO, -1. ),
+            ( Vec3::new( 3., 0., 0. ), 2. ),
+            ( Vec3::new( 0., 5., 0. ), 3. ),
+            ( Vec3::new( 0., 0., 2. ), 1. ),
+            ( Vec3::new( 2., 3., 0. ), f32::sqrt( 2. ) )
+        ] );
+    }
+
+    #[test]
+    fn cone() {
+        let cone = Cone { position: Vec3::ZERO, half_height: 1., bottom_radius: 1., top_radius: 0., material: material() };
+        assert_distances( &cone, &[
+            ( Vec3::new( 0., 2., 0. ), 1. ),
+            ( Vec3::new( 0., -2., 0. ), 1. ),
+            ( Vec3::new( 3., -1., 0. ), 2. ),
+            // Perpendicular to the side, which passes through ( 0.5, 0 ) with a slope of 2
+            ( Vec3::new( 0.5 + 2. / f32::sqrt( 5. ), 1. / f32::sqrt( 5. ), 0. ), 1. ),
+            ( Vec3::new( 0., -0.9, 0. ), -0.1 )
+        ] );
+    }
+
+    #[test]
+    fn plane() {
+        let plane = Plane { normal: Vec3::Y, offset: -1., material: material() };
+        assert_distances( &plane, &[
+            ( Vec3::new( 5., 0., 3. ), 1. ),
+            ( Vec3::new( 0., -3., 0. ), -2. )
+        ] );
+    }
+
+    #[test]
+    fn rounded_box() {
+        let rounded = RoundedBox { position: Vec3::ZERO, size: Vec3::ONE, radius: 0.2, material: material() };
+        assert_distances( &rounded, &[
+            ( Vec3::new( 2., 0., 0. ), 1. ),
+            ( Vec3::ZERO, -1. ),
+            ( Vec3::splat( 2. ), 1.2 * f32::sqrt( 3. ) - 0.2 )
+        ] );
+    }
+
+    #[test]
+    fn ellipsoid() {
+        let ellipsoid = Ellipsoid { position: Vec3::ZERO, radii: Vec3::new( 1., 2., 3. ), material: material() };
+        assert_distances( &ellipsoid, &[
+            ( Vec3::new( 2., 0., 0. ), 1. ),
+            ( Vec3::new( 0., 4., 0. ), 2. ),
+            ( Vec3::new( 0., 0., 6. ), 3. ),
+            ( Vec3::ZERO, -1. )
+        ] );
+    }
+
+    #[test]
+    fn octahedron() {
+        let octahedron = Octahedron { position: Vec3::ZERO, size: 1., material: material() };
+        assert_distances( &octahedron, &[
+            ( Vec3::new( 2., 0., 0. ), 1. ),
+            ( Vec3::ZERO, -1. / f32::sqrt( 3. ) ),
+            ( Vec3::ONE, 2. / f32::sqrt( 3. ) )
+        ] );
+    }
+
+    #[test]
+    fn hex_prism() {
+        let prism = HexPrism { position: Vec3::ZERO, radius: 1., half_length: 1., material: material() };
+        assert_distances( &prism, &[
+            ( Vec3::new( 0., 2., 0. ), 1. ),
+            ( Vec3::new( 0., 0., 3. ), 2. ),
+            ( Vec3::new( 0., 0., 0.5 ), -0.5 ),
+            // Through a corner, which is 2 / sqrt( 3 ) from the center
+            ( Vec3::new( 3., 0., 0. ), 3. - 2. / f32::sqrt( 3. ) )
+        ] );
+    }
+
+    #[test]
+    fn triangle() {
+        let triangle = Triangle { a: Vec3::ZERO, b: Vec3::X, c: Vec3::Y, material: material() };
+        assert_distances( &triangle, &[
+            ( Vec3::new( 0.2, 0.2, 1. ), 1. ),
+            ( Vec3::new( 0.2, 0.2, -0.5 ), 0.5 ),
+            ( Vec3::new( 2., 0., 0. ), 1. ),
+            ( Vec3::new( -1., -1., 0. ), f32::sqrt( 2. ) ),
+            ( Vec3::new( 1., 1., 0. ), f32::sqrt( 0.5 ) )
+        ] );
+    }
+
+    #[test]
+    fn quad() {
+        let quad = Quad { a: Vec3::ZERO, b: Vec3::X, c: Vec3::new( 1., 1., 0. ), d: Vec3::Y, material: material() };
+        assert_distances( &quad, &[
+            ( Vec3::new( 0.5, 0.5, -2. ), 2. ),
+            ( Vec3::new( 0.9, 0.9, 0.3 ), 0.3 ),
+            ( Vec3::new( 3., 0.5, 0. ), 2. ),
+            ( Vec3::new( 2., 2., 1. ), f32::sqrt( 3. ) )
+        ] );
+    }
+}
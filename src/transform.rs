@@ -0,0 +1,219 @@
+// Placing shapes in a hierarchy: `Transformed` moves a shape with an affine transform and `Group` joins several
+// shapes into one, so groups of transformed shapes can be transformed again like the nodes of a scene graph.
+
+use std::borrow::Cow;
+use std::f32::consts::TAU;
+use glam::{EulerRot, Mat3, Mat4, Quat, Vec3};
+use crate::bvh::{Aabb, Bvh};
+use crate::material::Material;
+use crate::rays::Hittable;
+
+// A shape defined in its own local space and placed in the world by `to_world`
+pub struct Transformed<H: Hittable> {
+    pub shape: H,
+    to_world: Mat4,
+    to_local: Mat4,
+    // Distances in local space are multiplied by this to get a bound on the world space distance
+    scale: f32
+}
+
+impl<H: Hittable> Transformed<H> {
+    pub fn new( shape: H, to_world: Mat4 ) -> Transformed<H> {
+        let to_local = to_world.inverse();
+        Transformed { shape, to_world, to_local, scale: min_scale( Mat3::from_mat4( to_world ) ) }
+    }
+
+    // Scale first, then rotate by euler angles in radians applied in the order x, y, z, then translate
+    pub fn from_trs( shape: H, translation: Vec3, rotation: Vec3, scale: Vec3 ) -> Transformed<H> {
+        let rotation = Quat::from_euler( EulerRot::XYZ, rotation.x, rotation.y, rotation.z );
+        Transformed::new( shape, Mat4::from_scale_rotation_translation( scale, rotation, translation ) )
+    }
+
+    pub fn to_world( &self ) -> Mat4 {
+        self.to_world
+    }
+
+    // World space box around a box in local space
+    fn world_bounds( &self, bounds: Aabb ) -> Aabb {
+        if !bounds.is_finite() {
+            return Aabb::INFINITE;
+        }
+        Aabb::from_points( &bounds.corners().map( | c | self.to_world.transform_point3( c ) ) )
+    }
+}
+
+impl<H: Hittable> Hittable for Transformed<H> {
+    // The local distance is exact for rotations and translations. A scale stretches distances by up to its
+    // largest and at least by its smallest factor, so scaling by the smallest one never oversteps.
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        self.shape.distance( self.to_local.transform_point3( pos ) ) * self.scale
+    }
+
+    fn bounds( &self ) -> Aabb {
+        self.world_bounds( self.shape.bounds() )
+    }
+
+    fn material( &self ) -> & Material {
+        self.shape.material()
+    }
+
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        self.shape.material_at( self.to_local.transform_point3( pos ) )
+    }
+
+    fn gradient( &self, pos: Vec3 ) -> Option<Vec3> {
+        let gradient = self.shape.gradient( self.to_local.transform_point3( pos ) )?;
+        Some( self.to_local.transpose().transform_vector3( gradient ) * self.scale )
+    }
+
+    // Normals transform with the inverse transpose, so they stay perpendicular to scaled surfaces
+    fn calc_normal( &self, pos: Vec3 ) -> Vec3 {
+        let normal = self.shape.calc_normal( self.to_local.transform_point3( pos ) );
+        self.to_local.transpose().transform_vector3( normal ).normalize()
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        self.shape.orbit_trap( self.to_local.transform_point3( pos ) )
+    }
+
+    fn emitters( &self ) -> Vec<Aabb> {
+        self.shape.emitters().into_iter().map( | bounds | self.world_bounds( bounds ) ).collect()
+    }
+}
+
+// Several shapes acting as one, the surface of whichever is nearest
+pub struct Group {
+    children: Vec<Box<dyn Hittable>>,
+    bvh: Bvh,
+    bounds: Aabb
+}
+
+impl Group {
+    // Returns None for an empty list, a group needs at least one shape to take its material from
+    pub fn new( children: Vec<Box<dyn Hittable>> ) -> Option<Group> {
+        if children.is_empty() {
+            return None;
+        }
+        let bounds: Vec<Aabb> = children.iter().map( | child | child.bounds() ).collect();
+        let total = bounds.iter().fold( Aabb::EMPTY, | total, b | total.union( b ) );
+        Some( Group { bvh: Bvh::new( &bounds ), children, bounds: total } )
+    }
+
+    pub fn children( &self ) -> &[Box<dyn Hittable>] {
+        &self.children
+    }
+
+    fn nearest( &self, pos: Vec3 ) -> &dyn Hittable {
+        let ( i, _ ) = self.bvh.nearest( pos, | i | self.children[ i ].distance( pos ) ).unwrap();
+        self.children[ i ].as_ref()
+    }
+}
+
+impl Hittable for Group {
+    fn distance( &self, pos: Vec3 ) -> f32 {
+        self.bvh.nearest( pos, | i | self.children[ i ].distance( pos ) ).unwrap().1
+    }
+
+    fn bounds( &self ) -> Aabb {
+        self.bounds
+    }
+
+    // The material of the first shape, use `material_at` for the one at a point
+    fn material( &self ) -> & Material {
+        self.children[ 0 ].material()
+    }
+
+    fn material_at( &self, pos: Vec3 ) -> Cow<'_, Material> {
+        self.nearest( pos ).material_at( pos )
+    }
+
+    fn gradient( &self, pos: Vec3 ) -> Option<Vec3> {
+        self.nearest( pos ).gradient( pos )
+    }
+
+    fn calc_normal( &self, pos: Vec3 ) -> Vec3 {
+        self.nearest( pos ).calc_normal( pos )
+    }
+
+    fn orbit_trap( &self, pos: Vec3 ) -> Option<f32> {
+        self.nearest( pos ).orbit_trap( pos )
+    }
+
+    fn emitters( &self ) -> Vec<Aabb> {
+        self.children.iter().flat_map( | child | child.emitters() ).collect()
+    }
+}
+
+// The smallest factor by which the linear map m stretches any vector, its smallest singular value. It is the
+// square root of the smallest eigenvalue of m^T m, found in closed form for symmetric matrices:
+// https://en.wikipedia.org/wiki/Eigenvalue_algorithm#3%C3%973_matrices
+fn min_scale( m: Mat3 ) -> f32 {
+    let s = m.transpose() * m;
+    let off_diagonal = s.y_axis.x * s.y_axis.x + s.z_axis.x * s.z_axis.x + s.z_axis.y * s.z_axis.y;
+    let diagonal = Vec3::new( s.x_axis.x, s.y_axis.y, s.z_axis.z );
+    let q = ( diagonal.x + diagonal.y + diagonal.z ) / 3.;
+    let p = f32::sqrt( ( ( diagonal - q ).length_squared() + 2. * off_diagonal ) / 6. );
+    let smallest = if p <= f32::EPSILON * q {
+        diagonal.min_element()
+    } else {
+        let b = ( s - Mat3::from_diagonal( Vec3::splat( q ) ) ) * ( 1. / p );
+        let phi = ( b.determinant() * 0.5 ).clamp( -1., 1. ).acos() / 3.;
+        q + 2. * p * ( phi + TAU / 3. ).cos()
+    };
+    smallest.max( 0. ).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use glam::{Mat3, Mat4, Vec3};
+    use super::{min_scale, Group, Transformed};
+    use crate::material::Material;
+    use crate::rays::{Hittable, Sphere};
+
+    fn unit_sphere() -> Sphere {
+        Sphere { position: Vec3::ZERO, radius: 1., material: Arc::new( Material::lambertian( Vec3::ONE ) ) }
+    }
+
+    #[test]
+    fn smallest_scale() {
+        assert!( ( min_scale( Mat3::IDENTITY ) - 1. ).abs() < 1e-6 );
+        assert!( ( min_scale( Mat3::from_diagonal( Vec3::new( 3., 0.5, 2. ) ) ) - 0.5 ).abs() < 1e-6 );
+        let rotated = Mat3::from_rotation_y( 0.7 ) * Mat3::from_diagonal( Vec3::new( 1., 4., 2. ) ) * Mat3::from_rotation_x( 1.1 );
+        assert!( ( min_scale( rotated ) - 1. ).abs() < 1e-4, "{}", min_scale( rotated ) );
+    }
+
+    #[test]
+    fn scaled_sphere() {
+        // An ellipsoid with radii 2, 1, 1 at x = 5
+        let shape = Transformed::from_trs( unit_sphere(), Vec3::new( 5., 0., 0. ), Vec3::ZERO, Vec3::new( 2., 1., 1. ) );
+        assert!( shape.distance( Vec3::new( 5., 0., 0. ) ) < 0. );
+        assert!( shape.distance( Vec3::new( 6.9, 0., 0. ) ) < 0. );
+        assert!( shape.distance( Vec3::new( 5., 1.1, 0. ) ) > 0. );
+        // The corrected distance never oversteps the surface
+        for pos in [ Vec3::new( 10., 0., 0. ), Vec3::new( 5., 3., 0. ), Vec3::new( 8., 2., 1. ) ] {
+            let d = shape.distance( pos );
+            assert!( d > 0. && shape.distance( pos + ( Vec3::new( 5., 0., 0. ) - pos ).normalize() * d ) >= -1e-5 );
+        }
+        let bounds = shape.bounds();
+        assert!( ( bounds.min - Vec3::new( 3., -1., -1. ) ).length() < 1e-5 );
+        assert!( ( bounds.max - Vec3::new( 7., 1., 1. ) ).length() < 1e-5 );
+        // The normal of the stretched side points along the stretch
+        let normal = shape.calc_normal( Vec3::new( 5. + 2. * 0.6, 0.8, 0. ) );
+        assert!( ( normal - Vec3::new( 0.3, 0.8, 0. ).normalize() ).length() < 1e-3, "{}", normal );
+    }
+
+    #[test]
+    fn nested_groups() {
+        let pair = Group::new( vec![
+            Box::new( Transformed::new( unit_sphere(), Mat4::from_translation( Vec3::new( -2., 0., 0. ) ) ) ),
+            Box::new( Transformed::new( unit_sphere(), Mat4::from_translation( Vec3::new( 2., 0., 0. ) ) ) )
+        ] ).unwrap();
+        // Rotating the group a quarter turn around z moves the spheres onto the y axis
+        let rotated = Transformed::from_trs( pair, Vec3::new( 0., 0., 1. ), Vec3::new( 0., 0., std::f32::consts::FRAC_PI_2 ), Vec3::ONE );
+        assert!( ( rotated.distance( Vec3::new( 0., 2., 1. ) ) + 1. ).abs() < 1e-5 );
+        assert!( ( rotated.distance( Vec3::new( 0., -5., 1. ) ) - 2. ).abs() < 1e-5 );
+        assert!( rotated.distance( Vec3::new( 2., 0., 1. ) ) > 0.5 );
+        assert!( Group::new( vec![] ).is_none() );
+    }
+}